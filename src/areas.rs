@@ -0,0 +1,475 @@
+/*
+Pbfextractor creates graph files for the cycle-routing projects from pbf and srtm data
+Copyright (C) 2018  Florian Barth
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Areas around the roads, like parks, forests and industry, for metrics on
+//! the surroundings of edges.
+//!
+//! Areas are closed ways and multipolygon relations. Major roads are noisy
+//! within a corridor of a few hundred meters.
+use super::pbf::{Latitude, Longitude, Node, OsmNodeId, OsmWayId};
+use super::region::{Region, Ring};
+
+use log::info;
+use osmpbfreader::{OsmId, OsmObj, OsmPbfReader, Tags};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+
+/// Meters per degree of latitude.
+const METERS_PER_DEGREE: f64 = 111_195.0;
+/// Side length of the cells of the index in degrees.
+const CELL_SIZE: f64 = 0.01;
+/// Edges are sampled at least every 10 m.
+const SAMPLE_DISTANCE: f64 = 10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AreaKind {
+    Green,
+    Noisy,
+}
+
+/// The kind of an area by its `landuse`, `leisure` and `natural` tags.
+pub fn area_kind(tags: &Tags) -> Option<AreaKind> {
+    let tag = |key| tags.get(key).map(|v| v.as_str());
+    match (tag("landuse"), tag("leisure"), tag("natural")) {
+        (Some("industrial"), _, _)
+        | (Some("railway"), _, _)
+        | (Some("quarry"), _, _)
+        | (Some("landfill"), _, _) => Some(AreaKind::Noisy),
+        (Some("forest"), _, _)
+        | (Some("meadow"), _, _)
+        | (Some("grass"), _, _)
+        | (Some("orchard"), _, _)
+        | (Some("vineyard"), _, _)
+        | (Some("allotments"), _, _)
+        | (Some("cemetery"), _, _)
+        | (Some("recreation_ground"), _, _)
+        | (Some("village_green"), _, _)
+        | (_, Some("park"), _)
+        | (_, Some("garden"), _)
+        | (_, Some("nature_reserve"), _)
+        | (_, _, Some("wood"))
+        | (_, _, Some("scrub"))
+        | (_, _, Some("heath"))
+        | (_, _, Some("grassland"))
+        | (_, _, Some("wetland")) => Some(AreaKind::Green),
+        _ => None,
+    }
+}
+
+/// Distance in meters up to which a major road is noisy.
+fn noise_distance(tags: &Tags) -> Option<f64> {
+    match tags.get("highway").map(|v| v.as_str()) {
+        Some("motorway") | Some("motorway_link") => Some(200.0),
+        Some("trunk") | Some("trunk_link") => Some(150.0),
+        Some("primary") | Some("primary_link") => Some(100.0),
+        _ => None,
+    }
+}
+
+/// Distance between two points in meters, in a plane around the first.
+fn distance(from: (Longitude, Latitude), to: (Longitude, Latitude)) -> f64 {
+    distance_to_segment(to, from, from)
+}
+
+/// Distance of a point to a segment in meters, in a plane around the point.
+fn distance_to_segment(
+    (lng, lat): (Longitude, Latitude),
+    from: (Longitude, Latitude),
+    to: (Longitude, Latitude),
+) -> f64 {
+    let scale = lat.to_radians().cos();
+    let project = |(x, y): (f64, f64)| ((x - lng) * scale, y - lat);
+    let (x1, y1) = project(from);
+    let (x2, y2) = project(to);
+    let (dx, dy) = (x2 - x1, y2 - y1);
+    let length = dx * dx + dy * dy;
+    let t = if length > 0.0 {
+        (-(x1 * dx + y1 * dy) / length).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    (x1 + t * dx).hypot(y1 + t * dy) * METERS_PER_DEGREE
+}
+
+/// Joins the member ways of a multipolygon at their ends into closed rings.
+/// Ways that don't form a ring are left out.
+fn join_rings(mut ways: Vec<Vec<OsmNodeId>>) -> Vec<Vec<OsmNodeId>> {
+    let mut rings = Vec::new();
+    while let Some(mut ring) = ways.pop() {
+        while ring.len() > 1 && ring.first() != ring.last() {
+            let end = ring[ring.len() - 1];
+            let next = ways
+                .iter()
+                .position(|w| w.first() == Some(&end) || w.last() == Some(&end));
+            match next {
+                Some(i) => {
+                    let mut way = ways.swap_remove(i);
+                    if way.first() != Some(&end) {
+                        way.reverse();
+                    }
+                    ring.extend(way.into_iter().skip(1));
+                }
+                None => break,
+            }
+        }
+        if ring.len() > 3 && ring.first() == ring.last() {
+            rings.push(ring);
+        }
+    }
+    rings
+}
+
+struct Area {
+    kind: AreaKind,
+    min: (Longitude, Latitude),
+    max: (Longitude, Latitude),
+    region: Region,
+}
+
+impl Area {
+    /// Whether the point is inside, testing the bounding box before the
+    /// polygon.
+    fn contains(&self, lat: Latitude, lng: Longitude) -> bool {
+        self.min.0 <= lng
+            && lng <= self.max.0
+            && self.min.1 <= lat
+            && lat <= self.max.1
+            && self.region.contains(lat, lng)
+    }
+}
+
+struct Corridor {
+    from: (Longitude, Latitude),
+    to: (Longitude, Latitude),
+    distance: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Shape {
+    Area(usize),
+    Corridor(usize),
+}
+
+/// Areas and noisy corridors, indexed by cells of `CELL_SIZE` degrees.
+#[derive(Default)]
+pub struct AreaIndex {
+    areas: Vec<Area>,
+    corridors: Vec<Corridor>,
+    cells: HashMap<(i64, i64), Vec<Shape>>,
+}
+
+impl AreaIndex {
+    fn cell(lng: Longitude, lat: Latitude) -> (i64, i64) {
+        (
+            (lng / CELL_SIZE).floor() as i64,
+            (lat / CELL_SIZE).floor() as i64,
+        )
+    }
+
+    /// Adds the shape to all cells overlapping the box.
+    fn insert(&mut self, shape: Shape, min: (Longitude, Latitude), max: (Longitude, Latitude)) {
+        let (x_min, y_min) = AreaIndex::cell(min.0, min.1);
+        let (x_max, y_max) = AreaIndex::cell(max.0, max.1);
+        for x in x_min..=x_max {
+            for y in y_min..=y_max {
+                self.cells.entry((x, y)).or_default().push(shape);
+            }
+        }
+    }
+
+    pub fn add_area(&mut self, kind: AreaKind, outer: Vec<Ring>, holes: Vec<Ring>) {
+        if outer.is_empty() {
+            return;
+        }
+        let points = outer.iter().flatten();
+        let min = points.clone().fold((f64::INFINITY, f64::INFINITY), |m, p| {
+            (m.0.min(p.0), m.1.min(p.1))
+        });
+        let max = points.fold((f64::NEG_INFINITY, f64::NEG_INFINITY), |m, p| {
+            (m.0.max(p.0), m.1.max(p.1))
+        });
+        self.insert(Shape::Area(self.areas.len()), min, max);
+        self.areas.push(Area {
+            kind,
+            min,
+            max,
+            region: Region::Polygon { outer, holes },
+        });
+    }
+
+    /// Adds a segment of a road that is noisy up to `distance` meters.
+    pub fn add_corridor(
+        &mut self,
+        from: (Longitude, Latitude),
+        to: (Longitude, Latitude),
+        distance: f64,
+    ) {
+        let lat = from.1.abs().max(to.1.abs()).min(89.0);
+        let d_lat = distance / METERS_PER_DEGREE;
+        let d_lng = d_lat / lat.to_radians().cos();
+        let min = (from.0.min(to.0) - d_lng, from.1.min(to.1) - d_lat);
+        let max = (from.0.max(to.0) + d_lng, from.1.max(to.1) + d_lat);
+        self.insert(Shape::Corridor(self.corridors.len()), min, max);
+        self.corridors.push(Corridor { from, to, distance });
+    }
+
+    /// Whether the point is in an area of the kind. Noisy points also include
+    /// those close to major roads.
+    pub fn contains(&self, kind: AreaKind, lat: Latitude, lng: Longitude) -> bool {
+        let shapes = match self.cells.get(&AreaIndex::cell(lng, lat)) {
+            Some(shapes) => shapes,
+            None => return false,
+        };
+        shapes.iter().any(|s| match *s {
+            Shape::Area(i) => {
+                let area = &self.areas[i];
+                area.kind == kind && area.contains(lat, lng)
+            }
+            Shape::Corridor(i) => {
+                let c = &self.corridors[i];
+                kind == AreaKind::Noisy
+                    && distance_to_segment((lng, lat), c.from, c.to) <= c.distance
+            }
+        })
+    }
+
+    /// The share of the edge between the nodes inside areas of the kind,
+    /// sampled every `SAMPLE_DISTANCE` meters.
+    pub fn share(&self, kind: AreaKind, source: &Node, target: &Node) -> f64 {
+        let length = distance((source.long, source.lat), (target.long, target.lat));
+        let samples = (length / SAMPLE_DISTANCE).ceil().max(1.0) as usize;
+        let inside = (0..samples)
+            .filter(|i| {
+                let t = (*i as f64 + 0.5) / samples as f64;
+                let lat = source.lat + t * (target.lat - source.lat);
+                let lng = source.long + t * (target.long - source.long);
+                self.contains(kind, lat, lng)
+            })
+            .count();
+        inside as f64 / samples as f64
+    }
+
+    /// Reads the areas and major roads of the pbf files.
+    pub fn read(paths: &[&str]) -> io::Result<AreaIndex> {
+        let mut readers = paths
+            .iter()
+            .map(|path| File::open(path).map(OsmPbfReader::new))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // relations follow the ways in pbf files, so the members of the
+        // multipolygons are collected first
+        let mut multipolygons = Vec::new();
+        let mut members: HashMap<OsmWayId, Vec<OsmNodeId>> = HashMap::new();
+        for reader in &mut readers {
+            for obj in reader.par_iter() {
+                let r = match obj {
+                    Ok(OsmObj::Relation(r)) if r.tags.contains("type", "multipolygon") => r,
+                    _ => continue,
+                };
+                if let Some(kind) = area_kind(&r.tags) {
+                    let mut outer = Vec::new();
+                    let mut inner = Vec::new();
+                    for m in &r.refs {
+                        if let OsmId::Way(w) = m.member {
+                            let id = w.0 as OsmWayId;
+                            members.insert(id, Vec::new());
+                            if m.role == "inner" {
+                                inner.push(id);
+                            } else {
+                                outer.push(id);
+                            }
+                        }
+                    }
+                    multipolygons.push((kind, outer, inner));
+                }
+            }
+            reader.rewind().expect("Can't rewind pbf file!");
+        }
+
+        // the coordinates of the nodes of the areas and roads, filled in by
+        // the pass over the nodes
+        let mut closed_ways = Vec::new();
+        let mut roads = Vec::new();
+        let mut coords: HashMap<OsmNodeId, Option<(Longitude, Latitude)>> = HashMap::new();
+        for reader in &mut readers {
+            for obj in reader.par_iter() {
+                let w = match obj {
+                    Ok(OsmObj::Way(w)) => w,
+                    _ => continue,
+                };
+                let nodes: Vec<OsmNodeId> = w.nodes.iter().map(|n| n.0 as OsmNodeId).collect();
+                let mut used = false;
+                if let Some(member) = members.get_mut(&(w.id.0 as OsmWayId)) {
+                    *member = nodes.clone();
+                    used = true;
+                }
+                if nodes.len() > 3 && nodes.first() == nodes.last() {
+                    if let Some(kind) = area_kind(&w.tags) {
+                        closed_ways.push((kind, nodes.clone()));
+                        used = true;
+                    }
+                }
+                if let Some(distance) = noise_distance(&w.tags) {
+                    roads.push((distance, nodes.clone()));
+                    used = true;
+                }
+                if used {
+                    coords.extend(nodes.into_iter().map(|n| (n, None)));
+                }
+            }
+            reader.rewind().expect("Can't rewind pbf file!");
+        }
+
+        for reader in &mut readers {
+            for obj in reader.par_iter() {
+                if let Ok(OsmObj::Node(n)) = obj {
+                    if let Some(coord) = coords.get_mut(&(n.id.0 as OsmNodeId)) {
+                        let lat = f64::from(n.decimicro_lat) / 10_000_000.0;
+                        let lng = f64::from(n.decimicro_lon) / 10_000_000.0;
+                        *coord = Some((lng, lat));
+                    }
+                }
+            }
+        }
+        let coord = |n: &OsmNodeId| coords.get(n).copied().flatten();
+
+        // rings with missing nodes, e.g. cut off by the extract, are left out
+        let to_ring = |nodes: &[OsmNodeId]| -> Option<Ring> { nodes.iter().map(coord).collect() };
+        let mut index = AreaIndex::default();
+        for (kind, nodes) in closed_ways {
+            if let Some(ring) = to_ring(&nodes) {
+                index.add_area(kind, vec![ring], Vec::new());
+            }
+        }
+        for (kind, outer, inner) in multipolygons {
+            let rings = |ways: Vec<OsmWayId>| -> Vec<Ring> {
+                let ways = ways.iter().map(|w| members[w].clone()).collect();
+                join_rings(ways).iter().filter_map(|r| to_ring(r)).collect()
+            };
+            index.add_area(kind, rings(outer), rings(inner));
+        }
+        for (distance, nodes) in roads {
+            for segment in nodes.windows(2) {
+                if let (Some(from), Some(to)) = (coord(&segment[0]), coord(&segment[1])) {
+                    index.add_corridor(from, to, distance);
+                }
+            }
+        }
+        info!(
+            "Collected {} areas and {} segments of major roads",
+            index.areas.len(),
+            index.corridors.len()
+        );
+        Ok(index)
+    }
+}
+
+#[test]
+fn join_multipolygon_rings() {
+    let rings = join_rings(vec![
+        vec![1, 2, 3],
+        vec![10, 11, 12, 10],
+        vec![1, 4, 3],
+        vec![20, 21],
+    ]);
+    assert_eq!(2, rings.len());
+    assert!(rings.contains(&vec![10, 11, 12, 10]));
+    assert!(rings.contains(&vec![1, 4, 3, 2, 1]) || rings.contains(&vec![1, 2, 3, 4, 1]));
+}
+
+#[test]
+fn shares_of_edges() {
+    let square = |lng: f64, lat: f64, side: f64| -> Ring {
+        vec![
+            (lng, lat),
+            (lng + side, lat),
+            (lng + side, lat + side),
+            (lng, lat + side),
+            (lng, lat),
+        ]
+    };
+    let mut index = AreaIndex::default();
+    // a park from 10.0 to 10.02 with a hole from 10.005 to 10.015
+    index.add_area(
+        AreaKind::Green,
+        vec![square(10.0, 49.99, 0.02)],
+        vec![square(10.005, 49.995, 0.01)],
+    );
+    index.add_corridor((10.05, 49.9), (10.05, 50.1), 100.0);
+
+    assert!(index.contains(AreaKind::Green, 50.0, 10.001));
+    assert!(!index.contains(AreaKind::Green, 50.0, 10.006));
+    assert!(!index.contains(AreaKind::Noisy, 50.0, 10.001));
+    assert!(index.contains(AreaKind::Noisy, 50.0, 10.0509));
+    assert!(!index.contains(AreaKind::Noisy, 50.0, 10.0516));
+
+    let node = |lng| Node::new(0, 50.0, lng, 0.0);
+    let share = |kind, from, to| index.share(kind, &node(from), &node(to));
+    // half of the 0.02 degrees are in the park without the hole
+    assert!((0.5 - share(AreaKind::Green, 10.0, 10.02)).abs() < 0.01);
+    assert_eq!(0.0, share(AreaKind::Green, 10.03, 10.04));
+    // 2 * 100 m of the 0.04 degrees (about 2860 m) are close to the road
+    let noisy = share(AreaKind::Noisy, 10.03, 10.07);
+    assert!((200.0 / 2860.0 - noisy).abs() < 0.01);
+    // edges shorter than the sample distance are sampled in the middle
+    assert_eq!(1.0, share(AreaKind::Green, 10.0001, 10.0002));
+}
+
+#[test]
+fn read_areas() {
+    use super::test_pbf::{temp_path, PbfWriter};
+    use osmpbfreader::WayId;
+
+    let path = temp_path("areas.pbf");
+    PbfWriter::default()
+        .node(1, 50.0, 10.0, &[])
+        .node(2, 50.0, 10.01, &[])
+        .node(3, 50.01, 10.01, &[])
+        .node(4, 50.01, 10.0, &[])
+        .node(5, 50.0, 10.02, &[])
+        .node(6, 50.01, 10.02, &[])
+        .node(7, 50.02, 10.0, &[])
+        .node(8, 50.02, 10.02, &[])
+        .way(10, &[1, 2, 3, 4, 1], &[("leisure", "park")])
+        .way(11, &[2, 5, 6], &[])
+        .way(12, &[6, 3, 2], &[])
+        .way(13, &[7, 8], &[("highway", "motorway")])
+        .way(14, &[1, 7], &[("highway", "residential")])
+        .relation(
+            20,
+            &[
+                (OsmId::Way(WayId(11)), "outer"),
+                (OsmId::Way(WayId(12)), "outer"),
+            ],
+            &[("type", "multipolygon"), ("landuse", "industrial")],
+        )
+        .write(&path);
+
+    let index = AreaIndex::read(&[path.to_str().unwrap()]).unwrap();
+    assert_eq!(2, index.areas.len());
+    assert_eq!(1, index.corridors.len());
+    assert!(index.contains(AreaKind::Green, 50.005, 10.005));
+    assert!(!index.contains(AreaKind::Noisy, 50.005, 10.005));
+    assert!(index.contains(AreaKind::Noisy, 50.005, 10.015));
+    assert!(index.contains(AreaKind::Noisy, 50.0195, 10.01));
+    assert!(!index.contains(AreaKind::Green, 50.015, 10.01));
+    assert!(AreaIndex::read(&[temp_path("missing.pbf").to_str().unwrap()]).is_err());
+
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,121 @@
+/*
+Pbfextractor creates graph files for the cycle-routing projects from pbf and srtm data
+Copyright (C) 2018  Florian Barth
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Parsing of the lengths and weights in `width`, `maxheight`, `maxweight`
+//! and similar tags.
+use super::units::*;
+
+/// Parses a length in meters. Numbers without unit are meters, `cm`, `mm`,
+/// `km`, `mi`, `ft` and feet and inches like `12'6"` are converted. Lists
+/// separated by `;` give the smallest value, values like `default` or
+/// `none` give `None`.
+pub fn parse_length(value: &str) -> Option<Meters> {
+    min_of(value, parse_single_length).map(Meters)
+}
+
+/// Parses a weight in kilograms. Numbers without unit are tonnes, `kg`,
+/// short tons (`st`) and `lbs` are converted.
+pub fn parse_weight(value: &str) -> Option<Kilograms> {
+    min_of(value, parse_single_weight).map(Kilograms)
+}
+
+/// The smallest positive value of a `;` separated list.
+fn min_of(value: &str, parse: fn(&str) -> Option<f64>) -> Option<f64> {
+    value
+        .split(';')
+        .filter_map(|v| parse(v.trim()))
+        .filter(|v| *v > 0.0)
+        .fold(None, |lowest, v| Some(lowest.map_or(v, |l: f64| l.min(v))))
+}
+
+/// Splits a value into its number and the rest. Decimal commas are
+/// accepted.
+fn split_number(value: &str) -> Option<(f64, &str)> {
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == ','))
+        .unwrap_or(value.len());
+    let number = value[..end].replace(',', ".").parse().ok()?;
+    Some((number, value[end..].trim()))
+}
+
+fn parse_single_length(value: &str) -> Option<f64> {
+    let (number, unit) = split_number(value)?;
+    let meters = match unit {
+        "" | "m" => Meters(number),
+        "cm" => Meters(number / 100.0),
+        "mm" => Meters(number / 1000.0),
+        "km" => Meters::from(Kilometers(number)),
+        "mi" => Meters(number * 1609.344),
+        "ft" => Meters::from(Feet(number)),
+        _ => match unit.strip_prefix('\'') {
+            Some(inches) => {
+                let inches = inches.trim().trim_end_matches('"');
+                let inches = if inches.is_empty() {
+                    0.0
+                } else {
+                    split_number(inches).filter(|(_, rest)| rest.is_empty())?.0
+                };
+                Meters(Meters::from(Feet(number)).0 + Meters::from(Inches(inches)).0)
+            }
+            None => return None,
+        },
+    };
+    Some(meters.0)
+}
+
+fn parse_single_weight(value: &str) -> Option<f64> {
+    let (number, unit) = split_number(value)?;
+    let kilograms = match unit {
+        "" | "t" => Kilograms::from(Tonnes(number)),
+        "kg" => Kilograms(number),
+        "st" => Kilograms::from(ShortTons(number)),
+        "lbs" => Kilograms::from(Pounds(number)),
+        _ => return None,
+    };
+    Some(kilograms.0)
+}
+
+#[test]
+fn parse_lengths() {
+    let meters = |v| parse_length(v).map(|m| (m.0 * 1000.0).round() / 1000.0);
+    assert_eq!(Some(3.5), meters("3.5"));
+    assert_eq!(Some(3.5), meters("3,5 m"));
+    assert_eq!(Some(3.5), meters("350 cm"));
+    assert_eq!(Some(3.81), meters("12'6\""));
+    assert_eq!(Some(3.658), meters("12'"));
+    assert_eq!(Some(3.658), meters("12 ft"));
+    assert_eq!(Some(2.2), meters("2.5;2.2"));
+    assert_eq!(Some(4.0), meters("default;4"));
+    assert_eq!(None, meters("default"));
+    assert_eq!(None, meters("below_default"));
+    assert_eq!(None, meters("none"));
+    assert_eq!(None, meters("0"));
+    assert_eq!(None, meters("3 furlongs"));
+}
+
+#[test]
+fn parse_weights() {
+    let kg = |v| parse_weight(v).map(|w| w.0.round());
+    assert_eq!(Some(7500.0), kg("7.5"));
+    assert_eq!(Some(7500.0), kg("7.5 t"));
+    assert_eq!(Some(3500.0), kg("3500 kg"));
+    assert_eq!(Some(9072.0), kg("10 st"));
+    assert_eq!(Some(4536.0), kg("10000 lbs"));
+    assert_eq!(Some(3500.0), kg("7.5;3.5"));
+    assert_eq!(None, kg("none"));
+    assert_eq!(None, kg("5 horses"));
+}
@@ -0,0 +1,253 @@
+/*
+Pbfextractor creates graph files for the cycle-routing projects from pbf and srtm data
+Copyright (C) 2018  Florian Barth
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use super::edge_store::{Edge, EdgeStore};
+use super::metrics::TurnMetric;
+use super::pbf::{EdgeId, Graph, MetricIndices, Node};
+use super::restrictions::{RestrictionKind, TurnRestriction};
+
+use log::warn;
+use std::collections::{HashMap, HashSet};
+
+/// Converts a node based graph into its line graph. Every edge of `g`
+/// becomes a node with the same id and every allowed turn becomes an edge.
+/// A turn carries the costs of the edge it leaves plus the value of
+/// `turn_metric`, which is added to the returned metric indices.
+///
+/// `g.edges` have to be sorted by source, as done by `Loader::build_graph`.
+pub fn line_graph(
+    g: &Graph,
+    indices: &MetricIndices,
+    turn_metric: &dyn TurnMetric<f64>,
+) -> (Graph, MetricIndices) {
+    let mut line_indices = indices.clone();
+    let turn_index = g.edges.cost_count();
+    line_indices.insert(turn_metric.name(), turn_index);
+
+    let mut first_out = vec![0; g.nodes.len() + 1];
+    for e in g.edges.topology() {
+        first_out[e.source + 1] += 1;
+    }
+    for i in 1..first_out.len() {
+        first_out[i] += first_out[i - 1];
+    }
+
+    let (forbidden, mandatory) = turn_rules(&g.restrictions);
+
+    let nodes = g
+        .edges
+        .topology()
+        .iter()
+        .map(|e| {
+            let s = &g.nodes[e.source];
+            let d = &g.nodes[e.dest];
+            Node::new(
+                e.osm_way_id,
+                (s.lat + d.lat) / 2.0,
+                (s.long + d.long) / 2.0,
+                (s.height + d.height) / 2.0,
+            )
+        })
+        .collect();
+
+    let mut edges = EdgeStore::new(g.edges.cost_count() + 1, g.edges.precision());
+    for from in g.edges.iter() {
+        let from_id = from.id;
+        let outgoing = first_out[from.dest]..first_out[from.dest + 1];
+        for to_id in outgoing {
+            if forbidden.contains(&(from_id, to_id))
+                || mandatory
+                    .get(&from_id)
+                    .is_some_and(|allowed| !allowed.contains(&to_id))
+            {
+                continue;
+            }
+            let to = g.edges.get(to_id);
+            let turn = turn_metric
+                .calc(
+                    &g.nodes[from.source],
+                    &g.nodes[from.dest],
+                    &g.nodes[to.dest],
+                )
+                .unwrap();
+            let mut costs = from.internal_costs();
+            costs.push(turn);
+            edges.push(Edge::new(from_id, to_id, from.osm_way_id), &costs);
+        }
+    }
+
+    let graph = Graph {
+        nodes,
+        edges,
+        restrictions: Vec::new(),
+    };
+    (graph, line_indices)
+}
+
+/// The turns allowed after edges with only_* restrictions.
+type AllowedTurns = HashMap<EdgeId, HashSet<EdgeId>>;
+
+/// Splits via node restrictions into forbidden turns and the turns allowed
+/// after an edge with only_* restrictions. Several only_* restrictions on
+/// the same edge allow each of their turns.
+/// Via way restrictions span more than one turn and can't be expressed in
+/// the line graph without splitting nodes, so they are dropped with a
+/// warning. They are still written by `--restrictions`.
+fn turn_rules(restrictions: &[TurnRestriction]) -> (HashSet<(EdgeId, EdgeId)>, AllowedTurns) {
+    let mut forbidden = HashSet::new();
+    let mut mandatory: AllowedTurns = HashMap::new();
+    let mut skipped = 0;
+    for r in restrictions {
+        if !r.via.is_empty() {
+            skipped += 1;
+            continue;
+        }
+        match r.kind {
+            RestrictionKind::OnlyLeftTurn
+            | RestrictionKind::OnlyRightTurn
+            | RestrictionKind::OnlyStraightOn
+            | RestrictionKind::OnlyUTurn => {
+                mandatory.entry(r.from).or_default().insert(r.to);
+            }
+            _ => {
+                forbidden.insert((r.from, r.to));
+            }
+        }
+    }
+    if skipped > 0 {
+        warn!(
+            "Dropped {} via way restrictions for the edge based graph",
+            skipped
+        );
+    }
+    (forbidden, mandatory)
+}
+
+#[cfg(test)]
+fn test_graph(restrictions: Vec<TurnRestriction>) -> Graph {
+    use super::edge_store::Precision;
+
+    //     2
+    //     |
+    // 0 - 1 - 3
+    let nodes = vec![
+        Node::new(10, 50.0, 9.99, 0.0),
+        Node::new(11, 50.0, 10.0, 0.0),
+        Node::new(12, 50.01, 10.0, 0.0),
+        Node::new(13, 50.0, 10.01, 0.0),
+    ];
+    let mut pairs = Vec::new();
+    for (s, d) in &[(0, 1), (1, 2), (1, 3)] {
+        pairs.push((*s, *d));
+        pairs.push((*d, *s));
+    }
+    pairs.sort();
+    let mut edges = EdgeStore::new(1, Precision::Double);
+    for (s, d) in pairs {
+        edges.push(Edge::new(s, d, 1), &[1.0]);
+    }
+    Graph {
+        nodes,
+        edges,
+        restrictions,
+    }
+}
+
+#[test]
+fn line_graph_turns() {
+    use super::metrics::TurnCost;
+
+    let g = test_graph(Vec::new());
+    let mut indices = MetricIndices::new();
+    indices.insert("EdgeCount".to_owned(), 0);
+
+    let (lg, lg_indices) = line_graph(&g, &indices, &TurnCost::default());
+
+    assert_eq!(g.edges.len(), lg.nodes.len());
+    assert_eq!(Some(&1), lg_indices.get("TurnCost"));
+    // every edge into 1 can continue on all three edges out of 1, the
+    // edges into the leaves only turn back
+    assert_eq!(3 * 3 + 3, lg.edges.len());
+
+    let edge = |s, d| g.edges.iter().position(|e| e.source == s && e.dest == d);
+    let turn = |from: (usize, usize), to: (usize, usize)| {
+        lg.edges
+            .iter()
+            .find(|e| Some(e.source) == edge(from.0, from.1) && Some(e.dest) == edge(to.0, to.1))
+            .map(|e| e.internal_costs())
+    };
+    let straight = turn((0, 1), (1, 3)).unwrap();
+    let left = turn((0, 1), (1, 2)).unwrap();
+    let u_turn = turn((0, 1), (1, 0)).unwrap();
+    assert_eq!(1.0, straight[0]);
+    assert!(straight[1] < left[1]);
+    assert!(left[1] < u_turn[1]);
+}
+
+#[test]
+fn line_graph_restrictions() {
+    use super::metrics::TurnCost;
+
+    let g = test_graph(Vec::new());
+    let edge = |s, d| {
+        g.edges
+            .iter()
+            .position(|e| e.source == s && e.dest == d)
+            .unwrap()
+    };
+    let restrictions = vec![
+        TurnRestriction {
+            kind: RestrictionKind::NoLeftTurn,
+            from: edge(0, 1),
+            via: Vec::new(),
+            to: edge(1, 2),
+        },
+        TurnRestriction {
+            kind: RestrictionKind::OnlyStraightOn,
+            from: edge(3, 1),
+            via: Vec::new(),
+            to: edge(1, 0),
+        },
+        TurnRestriction {
+            kind: RestrictionKind::OnlyLeftTurn,
+            from: edge(2, 1),
+            via: Vec::new(),
+            to: edge(1, 3),
+        },
+        TurnRestriction {
+            kind: RestrictionKind::OnlyRightTurn,
+            from: edge(2, 1),
+            via: Vec::new(),
+            to: edge(1, 0),
+        },
+    ];
+    let g = test_graph(restrictions);
+
+    let (lg, lg_indices) = line_graph(&g, &MetricIndices::new(), &TurnCost::default());
+    assert_eq!(Some(&1), lg_indices.get("TurnCost"));
+    let has_turn = |from, to| lg.edges.iter().any(|e| e.source == from && e.dest == to);
+
+    assert!(!has_turn(edge(0, 1), edge(1, 2)));
+    assert!(has_turn(edge(0, 1), edge(1, 3)));
+    assert!(has_turn(edge(3, 1), edge(1, 0)));
+    assert!(!has_turn(edge(3, 1), edge(1, 2)));
+    assert!(!has_turn(edge(3, 1), edge(1, 3)));
+    // both only_* restrictions of the same edge are allowed
+    assert!(has_turn(edge(2, 1), edge(1, 3)));
+    assert!(has_turn(edge(2, 1), edge(1, 0)));
+    assert!(!has_turn(edge(2, 1), edge(1, 2)));
+}
@@ -0,0 +1,398 @@
+/*
+Pbfextractor creates graph files for the cycle-routing projects from pbf and srtm data
+Copyright (C) 2018  Florian Barth
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use super::pbf::{EdgeId, InternalMetrics, MetricIndices, NodeId, OsmWayId};
+
+use rayon::prelude::*;
+use std::ops::Deref;
+
+/// Floating point type the costs are stored with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    Single,
+    Double,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub source: NodeId,
+    pub dest: NodeId,
+    pub osm_way_id: OsmWayId,
+}
+
+impl Edge {
+    pub fn new(source: NodeId, dest: NodeId, osm_way_id: OsmWayId) -> Edge {
+        Edge {
+            source,
+            dest,
+            osm_way_id,
+        }
+    }
+}
+
+enum CostMatrix {
+    Single(Vec<f32>),
+    Double(Vec<f64>),
+}
+
+impl CostMatrix {
+    fn new(precision: Precision) -> CostMatrix {
+        match precision {
+            Precision::Single => CostMatrix::Single(Vec::new()),
+            Precision::Double => CostMatrix::Double(Vec::new()),
+        }
+    }
+
+    fn get(&self, i: usize) -> f64 {
+        match self {
+            CostMatrix::Single(c) => f64::from(c[i]),
+            CostMatrix::Double(c) => c[i],
+        }
+    }
+
+    fn set(&mut self, i: usize, value: f64) {
+        match self {
+            CostMatrix::Single(c) => c[i] = value as f32,
+            CostMatrix::Double(c) => c[i] = value,
+        }
+    }
+
+    fn extend(&mut self, values: &[f64]) {
+        match self {
+            CostMatrix::Single(c) => c.extend(values.iter().map(|v| *v as f32)),
+            CostMatrix::Double(c) => c.extend_from_slice(values),
+        }
+    }
+
+    fn copy_within(&mut self, src: std::ops::Range<usize>, dest: usize) {
+        match self {
+            CostMatrix::Single(c) => c.copy_within(src, dest),
+            CostMatrix::Double(c) => c.copy_within(src, dest),
+        }
+    }
+
+    fn truncate(&mut self, len: usize) {
+        match self {
+            CostMatrix::Single(c) => c.truncate(len),
+            CostMatrix::Double(c) => c.truncate(len),
+        }
+    }
+
+    /// The rows in the given order.
+    fn gather(&self, rows: &[EdgeId], width: usize) -> CostMatrix {
+        fn gather<T: Copy>(c: &[T], rows: &[EdgeId], width: usize) -> Vec<T> {
+            let mut result = Vec::with_capacity(rows.len() * width);
+            for r in rows {
+                result.extend_from_slice(&c[r * width..(r + 1) * width]);
+            }
+            result
+        }
+        match self {
+            CostMatrix::Single(c) => CostMatrix::Single(gather(c, rows, width)),
+            CostMatrix::Double(c) => CostMatrix::Double(gather(c, rows, width)),
+        }
+    }
+}
+
+/// Edges with their costs in one contiguous matrix. Row `i` of the matrix
+/// holds the costs of edge `i` ordered by metric index, so all costs of all
+/// edges need a single allocation.
+pub struct EdgeStore {
+    edges: Vec<Edge>,
+    costs: CostMatrix,
+    cost_count: usize,
+}
+
+impl EdgeStore {
+    pub fn new(cost_count: usize, precision: Precision) -> EdgeStore {
+        EdgeStore {
+            edges: Vec::new(),
+            costs: CostMatrix::new(precision),
+            cost_count,
+        }
+    }
+
+    pub fn precision(&self) -> Precision {
+        match self.costs {
+            CostMatrix::Single(_) => Precision::Single,
+            CostMatrix::Double(_) => Precision::Double,
+        }
+    }
+
+    pub fn cost_count(&self) -> usize {
+        self.cost_count
+    }
+
+    pub fn len(&self) -> usize {
+        self.edges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edges.is_empty()
+    }
+
+    pub fn push(&mut self, e: Edge, costs: &[f64]) -> EdgeId {
+        assert_eq!(self.cost_count, costs.len());
+        self.edges.push(e);
+        self.costs.extend(costs);
+        self.edges.len() - 1
+    }
+
+    /// Moves all edges of `other` to the end of this store.
+    pub fn append(&mut self, other: &mut EdgeStore) {
+        assert_eq!(self.cost_count, other.cost_count);
+        match (&mut self.costs, &mut other.costs) {
+            (CostMatrix::Single(c), CostMatrix::Single(o)) => c.append(o),
+            (CostMatrix::Double(c), CostMatrix::Double(o)) => c.append(o),
+            (c, o) => {
+                for i in 0..other.edges.len() * other.cost_count {
+                    c.extend(&[o.get(i)]);
+                }
+                o.truncate(0);
+            }
+        }
+        self.edges.append(&mut other.edges);
+    }
+
+    pub fn get(&self, id: EdgeId) -> EdgeRef<'_> {
+        assert!(id < self.edges.len());
+        EdgeRef { id, store: self }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = EdgeRef<'_>> {
+        (0..self.edges.len()).map(move |id| EdgeRef { id, store: self })
+    }
+
+    /// The edges without their costs.
+    pub fn topology(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    pub fn edge_mut(&mut self, id: EdgeId) -> &mut Edge {
+        &mut self.edges[id]
+    }
+
+    pub fn cost(&self, id: EdgeId, index: usize) -> f64 {
+        debug_assert!(index < self.cost_count);
+        self.costs.get(id * self.cost_count + index)
+    }
+
+    pub fn set_cost(&mut self, id: EdgeId, index: usize, value: f64) {
+        debug_assert!(index < self.cost_count);
+        self.costs.set(id * self.cost_count + index, value);
+    }
+
+    /// Copies the costs of the edge into `row`.
+    pub fn read_costs(&self, id: EdgeId, row: &mut Vec<f64>) {
+        row.clear();
+        row.extend((0..self.cost_count).map(|i| self.cost(id, i)));
+    }
+
+    /// Calls `update` for every edge and its costs. The edges are split
+    /// between all cores, every call only sees its own edge, so the result
+    /// doesn't depend on the number of threads.
+    pub fn par_for_each_mut(&mut self, update: impl Fn(&mut Edge, &mut CostsMut) + Sync) {
+        let width = self.cost_count;
+        let edges = self.edges.par_iter_mut();
+        match &mut self.costs {
+            _ if width == 0 => {
+                edges.for_each(|e| update(e, &mut CostsMut::Double(&mut [])));
+            }
+            CostMatrix::Single(c) => edges
+                .zip(c.par_chunks_mut(width))
+                .for_each_init(Vec::new, |converted, (e, row)| {
+                    update(e, &mut CostsMut::Single(row, converted))
+                }),
+            CostMatrix::Double(c) => edges
+                .zip(c.par_chunks_mut(width))
+                .for_each(|(e, row)| update(e, &mut CostsMut::Double(row))),
+        }
+    }
+
+    /// Keeps the edges `keep` returns true for, without changing their order.
+    pub fn retain(&mut self, mut keep: impl FnMut(EdgeRef) -> bool) {
+        let kept: Vec<bool> = self.iter().map(&mut keep).collect();
+        let width = self.cost_count;
+        let mut len = 0;
+        for (id, kept) in kept.into_iter().enumerate() {
+            if !kept {
+                continue;
+            }
+            if id != len {
+                self.edges[len] = self.edges[id];
+                self.costs
+                    .copy_within(id * width..(id + 1) * width, len * width);
+            }
+            len += 1;
+        }
+        self.edges.truncate(len);
+        self.costs.truncate(len * width);
+    }
+
+    /// Reorders the edges, edge `i` becomes `order[i]`.
+    pub fn permute(&mut self, order: &[EdgeId]) {
+        assert_eq!(self.edges.len(), order.len());
+        self.edges = order.iter().map(|id| self.edges[*id]).collect();
+        self.costs = self.costs.gather(order, self.cost_count);
+    }
+}
+
+/// The costs of one edge, ordered by metric index. Single precision costs
+/// come with a buffer for their conversion to `f64`, which is reused for
+/// all edges of a thread.
+pub enum CostsMut<'a> {
+    Single(&'a mut [f32], &'a mut Vec<f64>),
+    Double(&'a mut [f64]),
+}
+
+impl<'a> CostsMut<'a> {
+    pub fn get(&self, index: usize) -> f64 {
+        match self {
+            CostsMut::Single(c, _) => f64::from(c[index]),
+            CostsMut::Double(c) => c[index],
+        }
+    }
+
+    pub fn set(&mut self, index: usize, value: f64) {
+        match self {
+            CostsMut::Single(c, _) => c[index] = value as f32,
+            CostsMut::Double(c) => c[index] = value,
+        }
+    }
+
+    /// Calls `f` with the costs as `f64`, single precision costs are
+    /// converted first.
+    pub fn with_f64<R>(&mut self, f: impl FnOnce(&[f64]) -> R) -> R {
+        match self {
+            CostsMut::Single(c, converted) => {
+                converted.clear();
+                converted.extend(c.iter().map(|v| f64::from(*v)));
+                f(converted)
+            }
+            CostsMut::Double(c) => f(c),
+        }
+    }
+}
+
+/// Handle of an edge in an `EdgeStore`, dereferences to the edge itself.
+#[derive(Clone, Copy)]
+pub struct EdgeRef<'a> {
+    pub id: EdgeId,
+    store: &'a EdgeStore,
+}
+
+impl<'a> Deref for EdgeRef<'a> {
+    type Target = Edge;
+
+    fn deref(&self) -> &Edge {
+        &self.store.edges[self.id]
+    }
+}
+
+impl<'a> EdgeRef<'a> {
+    pub fn cost(&self, index: usize) -> f64 {
+        self.store.cost(self.id, index)
+    }
+
+    /// All costs including internal metrics, ordered by metric index.
+    pub fn internal_costs(&self) -> Vec<f64> {
+        let mut costs = Vec::with_capacity(self.store.cost_count);
+        self.store.read_costs(self.id, &mut costs);
+        costs
+    }
+
+    pub fn costs(&self, indices: &MetricIndices, internal_only: &InternalMetrics) -> Vec<f64> {
+        let mut costs = Vec::new();
+        for (metric, index) in indices.iter() {
+            if internal_only.contains(metric) {
+                continue;
+            }
+            costs.push(self.cost(*index));
+        }
+
+        costs
+    }
+}
+
+#[test]
+fn store_operations() {
+    for precision in &[Precision::Single, Precision::Double] {
+        let mut store = EdgeStore::new(2, *precision);
+        for i in 0..5 {
+            store.push(Edge::new(i, 4 - i, 10 + i), &[i as f64, 0.5]);
+        }
+        store.set_cost(1, 1, 1.5);
+        assert_eq!(1.5, store.get(1).cost(1));
+
+        store.retain(|e| e.source != 2);
+        assert_eq!(4, store.len());
+        assert_eq!(vec![3.0, 0.5], store.get(2).internal_costs());
+
+        store.permute(&[3, 2, 1, 0]);
+        let sources: Vec<_> = store.iter().map(|e| e.source).collect();
+        assert_eq!(vec![4, 3, 1, 0], sources);
+        assert_eq!(vec![1.0, 1.5], store.get(2).internal_costs());
+        assert_eq!(11, store.get(2).osm_way_id);
+
+        let mut other = EdgeStore::new(2, Precision::Double);
+        other.push(Edge::new(7, 8, 9), &[0.25, 0.75]);
+        store.append(&mut other);
+        assert!(other.is_empty());
+        assert_eq!(vec![0.25, 0.75], store.get(4).internal_costs());
+    }
+}
+
+#[test]
+fn parallel_update() {
+    for precision in &[Precision::Single, Precision::Double] {
+        let mut store = EdgeStore::new(2, *precision);
+        for i in 0..1000 {
+            store.push(Edge::new(i, i + 1, 0), &[i as f64, 0.0]);
+        }
+        store.par_for_each_mut(|e, costs| {
+            e.dest = e.source;
+            let sum = costs.with_f64(|c| c.iter().sum::<f64>());
+            assert_eq!(sum, costs.get(0));
+            costs.set(1, sum + 0.5);
+        });
+        for e in store.iter() {
+            assert_eq!(e.source, e.dest);
+            assert_eq!(
+                vec![e.source as f64, e.source as f64 + 0.5],
+                e.internal_costs()
+            );
+        }
+    }
+
+    let mut empty = EdgeStore::new(0, Precision::Single);
+    empty.push(Edge::new(1, 2, 0), &[]);
+    empty.par_for_each_mut(|e, _| e.source = 3);
+    assert_eq!(3, empty.get(0).source);
+}
+
+#[test]
+fn filtered_costs() {
+    let mut indices = MetricIndices::new();
+    indices.insert("A".to_owned(), 1);
+    indices.insert("B".to_owned(), 0);
+    indices.insert("C".to_owned(), 2);
+    let internal: InternalMetrics = vec!["B".to_owned()].into_iter().collect();
+
+    let mut store = EdgeStore::new(3, Precision::Double);
+    store.push(Edge::new(0, 1, 0), &[1.0, 2.0, 3.0]);
+    assert_eq!(vec![2.0, 3.0], store.get(0).costs(&indices, &internal));
+}
@@ -0,0 +1,412 @@
+/*
+Pbfextractor creates graph files for the cycle-routing projects from pbf and srtm data
+Copyright (C) 2018  Florian Barth
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Edges and ways spilled to disk for extracts that don't fit into memory.
+use super::edge_store::{Edge, EdgeStore, Precision};
+use super::pbf::{NodeId, OsmNodeId, OsmWayId};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+/// Collects edges in sorted runs of at most `memory_limit` bytes in
+/// temporary files. The runs are merged on every call of `groups`.
+pub struct EdgeRuns {
+    runs: Vec<(File, usize)>,
+    buffer: EdgeStore,
+    buffer_edges: usize,
+    len: usize,
+}
+
+impl EdgeRuns {
+    pub fn new(memory_limit: usize, cost_count: usize, precision: Precision) -> EdgeRuns {
+        EdgeRuns {
+            runs: Vec::new(),
+            buffer: EdgeStore::new(cost_count, precision),
+            buffer_edges: (memory_limit / edge_size(cost_count, precision)).max(1),
+            len: 0,
+        }
+    }
+
+    /// Moves the edges of `edges` into the runs.
+    pub fn append(&mut self, edges: &mut EdgeStore) -> io::Result<()> {
+        self.len += edges.len();
+        self.buffer.append(edges);
+        if self.buffer.len() >= self.buffer_edges {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn run_count(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        let buffer = &mut self.buffer;
+        let mut order: Vec<usize> = (0..buffer.len()).collect();
+        order.sort_unstable_by_key(|id| {
+            let e = buffer.get(*id);
+            (e.source, e.dest)
+        });
+        buffer.permute(&order);
+
+        let file = tempfile::tempfile()?;
+        let mut out = BufWriter::new(&file);
+        write_edges(&mut out, buffer)?;
+        out.flush()?;
+        drop(out);
+        self.runs.push((file, buffer.len()));
+        *buffer = EdgeStore::new(buffer.cost_count(), buffer.precision());
+        Ok(())
+    }
+
+    /// All edges ordered by source and target.
+    fn sorted(&mut self) -> io::Result<SortedEdges> {
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        let mut runs = Vec::with_capacity(self.runs.len());
+        for (file, len) in &self.runs {
+            runs.push((reopen(file)?, *len));
+        }
+        let mut sorted = SortedEdges {
+            runs,
+            heads: EdgeStore::new(self.buffer.cost_count(), self.buffer.precision()),
+            heap: BinaryHeap::new(),
+            costs: vec![0.0; self.buffer.cost_count()],
+        };
+        for run in 0..sorted.runs.len() {
+            sorted.read_head(run)?;
+        }
+        Ok(sorted)
+    }
+
+    /// All edges grouped by source and target, each group is a store of the
+    /// parallel edges between two nodes. The groups end after the first
+    /// error.
+    pub fn groups(&mut self) -> io::Result<EdgeGroups> {
+        Ok(EdgeGroups {
+            edges: self.sorted()?,
+            failed: false,
+        })
+    }
+
+    /// All edges without their costs ordered by source and target.
+    pub fn edges(&mut self) -> io::Result<impl Iterator<Item = io::Result<Edge>>> {
+        Ok(self.groups()?.flat_map(|g| match g {
+            Ok(g) => g.topology().iter().map(|e| Ok(*e)).collect(),
+            Err(e) => vec![Err(e)],
+        }))
+    }
+}
+
+/// The finished edges of a graph in a temporary file, read in the order they
+/// were added in chunks of at most `memory_limit` bytes.
+pub struct EdgeFile {
+    file: File,
+    out: BufWriter<File>,
+    len: usize,
+    chunk_edges: usize,
+    cost_count: usize,
+    precision: Precision,
+}
+
+impl EdgeFile {
+    pub fn new(
+        memory_limit: usize,
+        cost_count: usize,
+        precision: Precision,
+    ) -> io::Result<EdgeFile> {
+        let file = tempfile::tempfile()?;
+        Ok(EdgeFile {
+            out: BufWriter::new(file.try_clone()?),
+            file,
+            len: 0,
+            chunk_edges: (memory_limit / edge_size(cost_count, precision)).max(1),
+            cost_count,
+            precision,
+        })
+    }
+
+    pub fn append(&mut self, edges: &EdgeStore) -> io::Result<()> {
+        self.len += edges.len();
+        write_edges(&mut self.out, edges)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn cost_count(&self) -> usize {
+        self.cost_count
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    /// The edges in stores that fit into the memory limit. Can be called
+    /// several times, the chunks end after the first error.
+    pub fn chunks(&mut self) -> io::Result<impl Iterator<Item = io::Result<EdgeStore>>> {
+        self.out.flush()?;
+        let mut input = reopen(&self.file)?;
+        let (cost_count, precision) = (self.cost_count, self.precision);
+        let chunk_edges = self.chunk_edges;
+        let mut left = self.len;
+        let mut costs = vec![0.0; cost_count];
+        Ok(std::iter::from_fn(move || {
+            if left == 0 {
+                return None;
+            }
+            let mut chunk = EdgeStore::new(cost_count, precision);
+            for _ in 0..left.min(chunk_edges) {
+                match read_edge(&mut input, &mut costs) {
+                    Ok(e) => {
+                        chunk.push(e, &costs);
+                    }
+                    Err(e) => {
+                        left = 0;
+                        return Some(Err(e));
+                    }
+                }
+            }
+            left -= chunk.len();
+            Some(Ok(chunk))
+        }))
+    }
+}
+
+/// Bytes of an edge with its costs in memory.
+fn edge_size(cost_count: usize, precision: Precision) -> usize {
+    let cost_size = match precision {
+        Precision::Single => 4,
+        Precision::Double => 8,
+    };
+    std::mem::size_of::<Edge>() + cost_count * cost_size
+}
+
+/// Reads a temporary file from its start, independent of other readers.
+fn reopen(file: &File) -> io::Result<BufReader<File>> {
+    let mut file = file.try_clone()?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(BufReader::new(file))
+}
+
+fn write_edges<W: Write>(out: &mut W, edges: &EdgeStore) -> io::Result<()> {
+    for e in edges.iter() {
+        out.write_u64::<LittleEndian>(e.source as u64)?;
+        out.write_u64::<LittleEndian>(e.dest as u64)?;
+        out.write_u64::<LittleEndian>(e.osm_way_id as u64)?;
+        for c in 0..edges.cost_count() {
+            out.write_f64::<LittleEndian>(e.cost(c))?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads an edge written by `write_edges`, its costs go to `costs`.
+fn read_edge<R: Read>(input: &mut R, costs: &mut [f64]) -> io::Result<Edge> {
+    let mut read = || input.read_u64::<LittleEndian>().map(|v| v as usize);
+    let e = Edge::new(read()?, read()?, read()?);
+    for c in costs.iter_mut() {
+        *c = input.read_f64::<LittleEndian>()?;
+    }
+    Ok(e)
+}
+
+/// The node ids of ways in a temporary file, read in the order they were
+/// added.
+pub struct WayNodeFile {
+    out: BufWriter<File>,
+    len: usize,
+}
+
+impl WayNodeFile {
+    pub fn new() -> io::Result<WayNodeFile> {
+        Ok(WayNodeFile {
+            out: BufWriter::new(tempfile::tempfile()?),
+            len: 0,
+        })
+    }
+
+    pub fn push(&mut self, way: OsmWayId, nodes: &[OsmNodeId]) -> io::Result<()> {
+        self.out.write_u64::<LittleEndian>(way as u64)?;
+        self.out.write_u64::<LittleEndian>(nodes.len() as u64)?;
+        for n in nodes {
+            self.out.write_u64::<LittleEndian>(*n as u64)?;
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The ways with their node ids.
+    pub fn read(self) -> io::Result<impl Iterator<Item = io::Result<(OsmWayId, Vec<OsmNodeId>)>>> {
+        let mut file = self.out.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut input = BufReader::new(file);
+        Ok((0..self.len).map(move |_| {
+            let mut read = || input.read_u64::<LittleEndian>().map(|v| v as usize);
+            let way = read()?;
+            let len = read()?;
+            let nodes = (0..len).map(|_| read()).collect::<io::Result<_>>()?;
+            Ok((way, nodes))
+        }))
+    }
+}
+
+/// K-way merge of the runs. Row `i` of `heads` is the next edge of run `i`.
+struct SortedEdges {
+    runs: Vec<(BufReader<File>, usize)>,
+    heads: EdgeStore,
+    heap: BinaryHeap<Reverse<(NodeId, NodeId, usize)>>,
+    costs: Vec<f64>,
+}
+
+impl SortedEdges {
+    fn read_head(&mut self, run: usize) -> io::Result<()> {
+        let (input, left) = &mut self.runs[run];
+        if *left == 0 {
+            return Ok(());
+        }
+        *left -= 1;
+        let e = read_edge(input, &mut self.costs)?;
+        if run == self.heads.len() {
+            self.heads.push(e, &self.costs);
+        } else {
+            *self.heads.edge_mut(run) = e;
+            for (i, c) in self.costs.iter().enumerate() {
+                self.heads.set_cost(run, i, *c);
+            }
+        }
+        self.heap.push(Reverse((e.source, e.dest, run)));
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<(NodeId, NodeId)> {
+        self.heap.peek().map(|Reverse((s, d, _))| (*s, *d))
+    }
+
+    /// Moves the smallest edge into `edges`.
+    fn pop_into(&mut self, edges: &mut EdgeStore) -> io::Result<()> {
+        let Reverse((_, _, run)) = self.heap.pop().expect("no edges left");
+        self.heads.read_costs(run, &mut self.costs);
+        edges.push(*self.heads.get(run), &self.costs);
+        self.read_head(run)
+    }
+}
+
+pub struct EdgeGroups {
+    edges: SortedEdges,
+    failed: bool,
+}
+
+impl Iterator for EdgeGroups {
+    type Item = io::Result<EdgeStore>;
+
+    fn next(&mut self) -> Option<io::Result<EdgeStore>> {
+        if self.failed {
+            return None;
+        }
+        let key = self.edges.peek()?;
+        let heads = &self.edges.heads;
+        let mut group = EdgeStore::new(heads.cost_count(), heads.precision());
+        while self.edges.peek() == Some(key) {
+            if let Err(e) = self.edges.pop_into(&mut group) {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(group))
+    }
+}
+
+#[test]
+fn read_way_nodes() {
+    let mut file = WayNodeFile::new().unwrap();
+    file.push(10, &[1, 2, 3]).unwrap();
+    file.push(11, &[]).unwrap();
+    assert_eq!(2, file.len());
+    let ways: Vec<_> = file.read().unwrap().map(Result::unwrap).collect();
+    assert_eq!(vec![(10, vec![1, 2, 3]), (11, vec![])], ways);
+}
+
+#[test]
+fn merge_runs() {
+    let edge_size = std::mem::size_of::<Edge>() + 8;
+    // three edges per run
+    let mut runs = EdgeRuns::new(3 * edge_size, 1, Precision::Double);
+    let input = [(5, 1), (2, 3), (2, 1), (9, 9), (2, 1), (0, 4), (5, 0)];
+    for (i, (s, d)) in input.iter().enumerate() {
+        let mut edge = EdgeStore::new(1, Precision::Double);
+        edge.push(Edge::new(*s, *d, i), &[i as f64]);
+        runs.append(&mut edge).unwrap();
+    }
+    assert_eq!(7, runs.len());
+    assert_eq!(2, runs.run_count());
+
+    let sorted: Vec<_> = runs
+        .edges()
+        .unwrap()
+        .map(|e| e.map(|e| (e.source, e.dest)).unwrap())
+        .collect();
+    assert_eq!(
+        vec![(0, 4), (2, 1), (2, 1), (2, 3), (5, 0), (5, 1), (9, 9)],
+        sorted
+    );
+    assert_eq!(3, runs.run_count());
+
+    // runs can be merged again and keep their costs
+    let groups: Vec<EdgeStore> = runs.groups().unwrap().map(Result::unwrap).collect();
+    assert_eq!(6, groups.len());
+    let mut parallel: Vec<f64> = groups[1].iter().map(|e| e.cost(0)).collect();
+    parallel.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(vec![2.0, 4.0], parallel);
+    assert_eq!(3, groups[5].get(0).osm_way_id);
+}
+
+#[test]
+fn read_edge_file_in_chunks() {
+    // two edges per chunk
+    let edge_size = edge_size(1, Precision::Single);
+    let mut file = EdgeFile::new(2 * edge_size, 1, Precision::Single).unwrap();
+    let mut edges = EdgeStore::new(1, Precision::Single);
+    for i in 0..5 {
+        edges.push(Edge::new(i, i + 1, 10), &[i as f64]);
+    }
+    file.append(&edges).unwrap();
+    assert_eq!(5, file.len());
+    for _ in 0..2 {
+        let chunks: Vec<EdgeStore> = file.chunks().unwrap().map(Result::unwrap).collect();
+        assert_eq!(
+            vec![2, 2, 1],
+            chunks.iter().map(|c| c.len()).collect::<Vec<_>>()
+        );
+        assert_eq!(4.0, chunks[2].cost(0, 0));
+        assert_eq!(Precision::Single, chunks[0].precision());
+    }
+}
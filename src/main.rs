@@ -19,17 +19,39 @@
 extern crate byteorder;
 extern crate osmpbfreader;
 
+mod areas;
+mod dimensions;
+mod edge_based;
+mod edge_store;
+mod external;
+mod maxspeed;
 mod metrics;
+mod node_ids;
 mod pbf;
+mod region;
+mod report;
+mod restrictions;
+mod routes;
+#[cfg(test)]
+mod test_pbf;
 mod units;
+mod update;
 
+use self::areas::AreaIndex;
+use self::edge_store::{EdgeStore, Precision};
 use self::metrics::*;
 use self::pbf::*;
+use self::region::*;
+use self::restrictions::TurnRestriction;
+use self::routes::CycleRouteFactors;
 
-use clap::App;
+use clap::{App, Arg};
+use log::{error, info};
+use std::borrow::Borrow;
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::rc::Rc;
+use std::io::{self, BufWriter, Write};
+use std::sync::Arc;
 use std::time::SystemTime;
 
 fn main() {
@@ -38,97 +60,563 @@ fn main() {
         .about("Extracts Graphs with multidimensional costs from PBF files")
         .args_from_usage(
             "-z          'saves graph gzipped'
+             -v, --verbose... 'logs more details, can be given twice'
+             -q, --quiet  'only logs warnings and errors'
+             --report     'writes a JSON report of the run to GRAPH.report.json'
+             --area-metrics 'adds the share of edges in green and in noisy areas'
+             --curvature  'adds the Curvature of the ways in degrees per kilometer'
+             --edge-based 'writes the edge based graph with turn costs'
+             --bbox=[BBOX] 'only extracts the area minlon,minlat,maxlon,maxlat'
+             --poly=[POLY] 'only extracts the area of an osmosis polygon file'
+             --complete-ways 'keeps ways crossing the clipping border completely'
+             -r, --restrictions=[FILE] 'File to write turn restrictions to, edges are line graph nodes with --edge-based'
+             --sidecar=[FILE] 'File to store the extract in for later updates'
+             --update=[SIDECAR] 'applies PBF-FILE as osc change file to the stored extract'
+             --memory-limit=[MB] 'spills edges to temporary files above this memory usage'
+             --single-precision 'stores edge costs as f32 to save memory'
+             --random-weights=[DIST] 'adds RandomWeights, uniform:MIN,MAX normal:MEAN,SD exponential:MEAN or distance:MIN,MAX'
+             --seed=[SEED] 'seed of the random weights, 0 by default'
+             --grid-cells=[N] 'number of cells along each axis of the grid metrics, 20 by default'
+             --profile=[PROFILE] 'car (default), truck, bicycle, foot or wheelchair'
+             --truck-size=[SIZE] 'height and width in m and weight in t of the truck, 4,2.55,40 by default'
+             --rider=[RIDER] 'power in W, mass in kg and rolling resistance of the cyclist, 100,90,0.004 by default'
+             --energy     'adds the EnergyConsumption in Wh of an electric car, truck or e-bike, depending on the profile'
+             --vehicle=[PARAMS] 'parameters of the vehicle of --energy, e.g. mass=2100,auxiliary_power=500'
+             --cycle-routes=[FACTORS] 'factors of the bicycle unsuitability on cycle routes, e.g. icn:0.5,lcn:0.7'
              <PBF-FILE>   'PBF File to extract from'
              <SRTM>       'Directory with srtm files'
              <GRAPH>      'File to write graph to'",
         )
+        .arg(
+            Arg::from_usage("-m, --merge=[PBF]... 'additional PBF file to merge into the graph'")
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::from_usage("--flag=[FLAG]... 'adds the meters on toll, motorway, unpaved, tunnel, bridge or ferry ways'")
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::from_usage("--grid-metric=[SPEC]... 'adds a synthetic metric on the grid, see README'")
+                .number_of_values(1)
+                .use_delimiter(false),
+        )
         .get_matches();
 
+    report::init_logging(if matches.is_present("quiet") {
+        -1
+    } else {
+        matches.occurrences_of("verbose") as i64
+    });
     let zip = matches.is_present("z");
 
-    let pbf_input = matches
+    let mut pbf_input = vec![matches
         .value_of("PBF-FILE")
-        .expect("No PBF File to extract from");
+        .expect("No PBF File to extract from")];
+    if let Some(merge) = matches.values_of("merge") {
+        pbf_input.extend(merge);
+    }
     let srtm_input = matches.value_of("SRTM").expect("No srtm input file given");
     let output = matches.value_of("GRAPH").expect("No output file given");
-    let grid = Grid::new_ptr();
+    let grid_cells: u32 = match matches.value_of("grid-cells") {
+        Some(cells) => match cells.parse() {
+            Ok(cells) if cells > 0 => cells,
+            _ => {
+                error!("invalid number of grid cells {}", cells);
+                std::process::exit(1);
+            }
+        },
+        None => 20,
+    };
+
+    let dist = Arc::new(Distance);
+    let car = Arc::new(CarSpeed);
+    let fast_car = Arc::new(FastCarSpeed);
+    let truck = Arc::new(TruckSpeed);
 
-    let dist = Rc::new(Distance);
-    let car = Rc::new(CarSpeed);
-    let fast_car = Rc::new(FastCarSpeed);
-    let truck = Rc::new(TruckSpeed);
+    let grid_metrics: GridMetrics = matches
+        .values_of("grid-metric")
+        .into_iter()
+        .flatten()
+        .map(|spec| {
+            parse_grid_metric(spec).unwrap_or_else(|e| {
+                error!("{}", e);
+                std::process::exit(1);
+            })
+        })
+        .collect();
 
-    let _grid_x = Rc::new(GridX(grid.clone()));
-    let _grid_y = Rc::new(GridY(grid.clone()));
-    let _chess = Rc::new(ChessBoard(grid.clone()));
+    let _car_time = Arc::new(TravelTime::new(dist.clone(), car.clone()));
+    let _fast_car_time = Arc::new(TravelTime::new(dist.clone(), fast_car));
 
-    let _car_time = Rc::new(TravelTime::new(dist.clone(), car));
-    let _fast_car_time = Rc::new(TravelTime::new(dist.clone(), fast_car));
-    let _truck_time = Rc::new(TravelTime::new(dist.clone(), truck));
+    let seed: u64 = matches.value_of("seed").map_or(0, |seed| {
+        seed.parse().unwrap_or_else(|_| {
+            error!("invalid seed {}", seed);
+            std::process::exit(1);
+        })
+    });
+    let random = matches.value_of("random-weights").map(|d| {
+        let distribution: RandomDistribution = d.parse().unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1);
+        });
+        Arc::new(RandomWeights::new(seed, distribution))
+    });
 
-    let _random = Rc::new(RandomWeights);
+    let route_factors: CycleRouteFactors =
+        matches
+            .value_of("cycle-routes")
+            .map_or_else(Default::default, |f| {
+                f.parse().unwrap_or_else(|e| {
+                    error!("{}", e);
+                    std::process::exit(1);
+                })
+            });
 
-    let internal_only_metrics: InternalMetrics = vec![].into_iter().collect();
+    let truck_filter: TruckEdgeFilter =
+        matches
+            .value_of("truck-size")
+            .map_or_else(Default::default, |size| {
+                size.parse().unwrap_or_else(|e| {
+                    error!("{}", e);
+                    std::process::exit(1);
+                })
+            });
 
-    let tag_metrics: TagMetrics = vec![];
-    let node_metrics: NodeMetrics = vec![dist];
-    let cost_metrics: CostMetrics = vec![];
+    let rider: Rider = matches
+        .value_of("rider")
+        .map_or_else(Default::default, |rider| {
+            rider.parse().unwrap_or_else(|e| {
+                error!("{}", e);
+                std::process::exit(1);
+            })
+        });
+
+    let profile = matches.value_of("profile").unwrap_or("car");
+    let (
+        edge_filter,
+        mut tag_metrics,
+        mut node_metrics,
+        node_tag_metrics,
+        mut cost_metrics,
+        mut internal_only_metrics,
+    ): (
+        Box<dyn EdgeFilter>,
+        TagMetrics,
+        NodeMetrics,
+        NodeTagMetrics,
+        CostMetrics,
+        Vec<String>,
+    ) = match profile {
+        "car" => (
+            Box::new(CarEdgeFilter),
+            vec![],
+            vec![dist],
+            vec![Arc::new(TrafficSignalDelay), Arc::new(BarrierDelay)],
+            vec![],
+            vec![],
+        ),
+        "truck" => (
+            Box::new(truck_filter),
+            vec![truck.clone(), Arc::new(Lanes), Arc::new(Width)],
+            vec![dist.clone()],
+            vec![],
+            vec![Arc::new(TravelTime::new(dist, truck))],
+            vec![TruckSpeed.name()],
+        ),
+        "bicycle" => {
+            let unsuitability = Arc::new(BicycleUnsuitability { route_factors });
+            (
+                Box::new(BicycleEdgeFilter),
+                vec![unsuitability.clone(), Arc::new(SurfaceRoughness)],
+                vec![
+                    dist.clone(),
+                    Arc::new(HeightAscent),
+                    Arc::new(HeightDescent),
+                ],
+                vec![Arc::new(TrafficSignalDelay), Arc::new(BarrierDelay)],
+                // the unsuitability is a factor around 1, it is written
+                // weighted by the distance so rounding doesn't erase it
+                vec![
+                    Arc::new(UnsuitDistMetric::new(dist.clone(), unsuitability)),
+                    Arc::new(BicycleTravelTime::new(dist, rider)),
+                ],
+                vec![
+                    BicycleUnsuitability::default().name(),
+                    SurfaceRoughness.name(),
+                    HeightDescent.name(),
+                ],
+            )
+        }
+        "foot" => {
+            let speed = Arc::new(WalkingSpeed);
+            (
+                Box::new(FootEdgeFilter),
+                vec![speed.clone(), Arc::new(HikingDifficulty)],
+                vec![
+                    dist.clone(),
+                    Arc::new(HeightAscent),
+                    Arc::new(HeightDescent),
+                ],
+                vec![],
+                vec![Arc::new(WalkingTime::new(dist, speed))],
+                vec![WalkingSpeed.name(), HeightDescent.name()],
+            )
+        }
+        "wheelchair" => (
+            Box::new(WheelchairEdgeFilter),
+            vec![Arc::new(WheelchairUnsuitability)],
+            vec![dist, Arc::new(HeightAscent)],
+            vec![],
+            vec![],
+            vec![],
+        ),
+        _ => {
+            error!("unknown profile {}", profile);
+            std::process::exit(1);
+        }
+    };
+    // every profile may use ferries, travel times on them need the flag and
+    // the duration, the flag is only written with --flag ferry
+    tag_metrics.push(Arc::new(FerryFlag));
+    tag_metrics.push(Arc::new(FerryDuration));
+    internal_only_metrics.push(FerryDuration.name());
+    let mut ferry_flag = false;
+    for name in matches.values_of("flag").into_iter().flatten() {
+        let flag = parse_flag(name).unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1);
+        });
+        if flag.name() == FerryFlag.name() {
+            ferry_flag = true;
+        } else {
+            tag_metrics.push(flag.clone());
+            internal_only_metrics.push(flag.name());
+        }
+        cost_metrics.push(Arc::new(FlagMetric::new(Arc::new(Distance), flag)));
+    }
+    if !ferry_flag {
+        internal_only_metrics.push(FerryFlag.name());
+    }
+    if matches.is_present("energy") {
+        let vehicle = |default: Vehicle| match matches.value_of("vehicle") {
+            Some(params) => default.with_parameters(params).unwrap_or_else(|e| {
+                error!("{}", e);
+                std::process::exit(1);
+            }),
+            None => default,
+        };
+        let energy: Arc<dyn CostMetric<f64>> = match profile {
+            "car" => {
+                tag_metrics.push(car.clone());
+                internal_only_metrics.push(CarSpeed.name());
+                Arc::new(EnergyConsumption::new(
+                    Arc::new(Distance),
+                    car.clone(),
+                    vehicle(Vehicle::electric_car()),
+                ))
+            }
+            "truck" => Arc::new(EnergyConsumption::new(
+                Arc::new(Distance),
+                Arc::new(TruckSpeed),
+                vehicle(Vehicle::electric_truck()),
+            )),
+            "bicycle" => {
+                tag_metrics.push(Arc::new(BicycleSpeed));
+                internal_only_metrics.push(BicycleSpeed.name());
+                Arc::new(EnergyConsumption::new(
+                    Arc::new(Distance),
+                    Arc::new(BicycleSpeed),
+                    vehicle(Vehicle::e_bike()),
+                ))
+            }
+            _ => {
+                error!("--energy needs the car, truck or bicycle profile");
+                std::process::exit(1);
+            }
+        };
+        let heights: [Arc<dyn NodeMetric<f64>>; 2] =
+            [Arc::new(HeightAscent), Arc::new(HeightDescent)];
+        for height in heights.iter() {
+            if node_metrics.iter().all(|m| m.name() != height.name()) {
+                node_metrics.push(height.clone());
+                internal_only_metrics.push(height.name());
+            }
+        }
+        cost_metrics.push(energy);
+    } else if matches.is_present("vehicle") {
+        error!("--vehicle needs --energy");
+        std::process::exit(1);
+    }
+    let internal_only_metrics: InternalMetrics = internal_only_metrics.into_iter().collect();
 
-    let l = pbf::Loader::new(
-        pbf_input,
+    if let Some(random) = random {
+        node_metrics.push(random);
+    }
+    if matches.is_present("area-metrics") {
+        if matches.is_present("update") {
+            error!("--area-metrics needs the pbf files and can't be combined with --update");
+            std::process::exit(1);
+        }
+        let areas = AreaIndex::read(&pbf_input).unwrap_or_else(|e| {
+            error!("Can't read the areas: {}", e);
+            std::process::exit(1);
+        });
+        let areas = Arc::new(areas);
+        node_metrics.push(Arc::new(GreenShare(areas.clone())));
+        node_metrics.push(Arc::new(NoiseShare(areas)));
+    }
+    let mut names = HashSet::new();
+    let metric_names = node_metrics.iter().map(|m| m.name());
+    let metric_names = metric_names.chain(node_tag_metrics.iter().map(|m| m.name()));
+    let metric_names = metric_names.chain(cost_metrics.iter().map(|m| m.name()));
+    for name in metric_names.chain(grid_metrics.iter().map(|m| m.name())) {
+        if !names.insert(name.clone()) {
+            error!("metric {} can only be used once", name);
+            std::process::exit(1);
+        }
+    }
+    let mut l = pbf::Loader::new(
+        pbf_input.clone(),
         srtm_input,
-        CarEdgeFilter,
+        edge_filter,
         tag_metrics,
         node_metrics,
+        node_tag_metrics,
         cost_metrics,
         internal_only_metrics,
-        grid,
     );
+    if !grid_metrics.is_empty() {
+        l.set_grid_metrics(grid_metrics, grid_cells);
+    }
+    if matches.is_present("curvature") {
+        l.set_way_metrics(vec![Arc::new(Curvature)]);
+    }
 
-    let output_file = File::create(&output).unwrap();
-    let graph = BufWriter::new(output_file);
-    if zip {
-        let graph = flate2::write::GzEncoder::new(graph, flate2::Compression::best());
-        write_graph(&l, graph);
-    } else {
-        write_graph(&l, graph);
+    let region = match (matches.value_of("bbox"), matches.value_of("poly")) {
+        (Some(_), Some(_)) => {
+            error!("Only one of --bbox and --poly can be given");
+            std::process::exit(1);
+        }
+        (Some(bbox), None) => Some(Region::parse_bbox(bbox)),
+        (None, Some(poly)) => Some(Region::from_poly_file(poly)),
+        (None, None) => None,
+    };
+    if let Some(region) = region {
+        let region = region.unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1);
+        });
+        let mode = if matches.is_present("complete-ways") {
+            ClipMode::CompleteWays
+        } else {
+            ClipMode::Strict
+        };
+        l.set_clip(Clip { region, mode });
+    }
+
+    if let Some(limit) = matches.value_of("memory-limit") {
+        if matches.is_present("sidecar") || matches.is_present("update") {
+            error!("--memory-limit can't be combined with --sidecar or --update");
+            std::process::exit(1);
+        }
+        let limit: usize = limit.parse().unwrap_or_else(|_| {
+            error!("invalid memory limit {}", limit);
+            std::process::exit(1);
+        });
+        l.set_memory_limit(limit * 1024 * 1024);
+    }
+    if matches.is_present("single-precision") {
+        l.set_precision(Precision::Single);
+    }
+    if matches.is_present("sidecar") {
+        l.set_updatable();
+    }
+    let extract = match matches.value_of("update") {
+        Some(sidecar) => {
+            if matches.is_present("merge") {
+                error!("--merge can't be combined with --update");
+                std::process::exit(1);
+            }
+            let changes = update::read_change_file(pbf_input[0]).unwrap_or_else(|e| {
+                error!("{}", e);
+                std::process::exit(1);
+            });
+            let mut extract = File::open(sidecar)
+                .map_err(update::UpdateError::from)
+                .and_then(|f| l.read_sidecar(f))
+                .unwrap_or_else(|e| {
+                    error!("{}", e);
+                    std::process::exit(1);
+                });
+            l.apply_changes(&mut extract, changes);
+            extract
+        }
+        None => l.read_extract().unwrap_or_else(|e| {
+            error!("Can't read the pbf file {}", e);
+            std::process::exit(1);
+        }),
+    };
+    if let Some(sidecar) = matches.value_of("sidecar") {
+        let sidecar_file = File::create(sidecar).unwrap();
+        l.write_sidecar(&extract, sidecar_file).unwrap();
+    }
+
+    let create_output = || -> Box<dyn Write> {
+        let graph = BufWriter::new(File::create(output).unwrap());
+        if zip {
+            Box::new(flate2::write::GzEncoder::new(
+                graph,
+                flate2::Compression::best(),
+            ))
+        } else {
+            Box::new(graph)
+        }
+    };
+    // with a memory limit the edges are written from a temporary file, only
+    // the line graph needs all edges in memory
+    let (indices, restrictions, written) =
+        if extract.edge_runs.is_some() && !matches.is_present("edge-based") {
+            let mut g = l.build_spilled_graph(extract);
+            let writing = l.report().phase("writing graph", "edges");
+            let edge_count = g.edges.len();
+            let written = g.edges.chunks().and_then(|edges| {
+                write_graph(
+                    &l.metrics_indices,
+                    &l.internal_metrics,
+                    &g.nodes,
+                    edge_count,
+                    edges,
+                    create_output(),
+                    &writing,
+                )
+            });
+            drop(writing);
+            (l.metrics_indices.clone(), g.restrictions, written)
+        } else {
+            let g = l.build_graph(extract);
+            let (line_graph, indices) = if matches.is_present("edge-based") {
+                let turns = l.report().phase("building line graph", "turns");
+                let (line_graph, indices) =
+                    edge_based::line_graph(&g, &l.metrics_indices, &TurnCost::default());
+                turns.add(line_graph.edges.len());
+                (Some(line_graph), indices)
+            } else {
+                (None, l.metrics_indices.clone())
+            };
+            let graph = line_graph.as_ref().unwrap_or(&g);
+
+            let writing = l.report().phase("writing graph", "edges");
+            let edges = std::iter::once(Ok(&graph.edges));
+            let written = write_graph(
+                &indices,
+                &l.internal_metrics,
+                &graph.nodes,
+                graph.edges.len(),
+                edges,
+                create_output(),
+                &writing,
+            );
+            drop(writing);
+            (indices, g.restrictions, written)
+        };
+    if let Err(e) = written {
+        error!("Can't write the graph: {}", e);
+        std::process::exit(1);
     }
-}
 
-fn write_graph<T: EdgeFilter, W: Write>(l: &Loader<T>, mut graph: W) {
-    let (nodes, edges) = l.load_graph();
+    if let Some(path) = matches.value_of("restrictions") {
+        let restrictions_file = File::create(path).unwrap();
+        write_restrictions(&restrictions, BufWriter::new(restrictions_file));
+    }
+
+    if matches.is_present("report") {
+        let path = format!("{}.report.json", output);
+        let metrics: Vec<String> = indices
+            .keys()
+            .filter(|m| !l.internal_metrics.contains(*m))
+            .cloned()
+            .collect();
+        let written = File::create(&path).and_then(|f| {
+            l.report()
+                .write_json(BufWriter::new(f), &pbf_input, output, &metrics)
+        });
+        match written {
+            Ok(()) => info!("Wrote run report to {}", path),
+            Err(e) => error!("could not write run report {}: {}", path, e),
+        }
+    }
+}
 
-    writeln!(&mut graph, "# Build by: pbfextractor").unwrap();
-    writeln!(&mut graph, "# Build on: {:?}", SystemTime::now()).unwrap();
-    write!(&mut graph, "# metrics: ").unwrap();
+fn write_graph<W: Write, S: Borrow<EdgeStore>>(
+    indices: &MetricIndices,
+    internal_metrics: &InternalMetrics,
+    nodes: &[Node],
+    edge_count: usize,
+    edges: impl Iterator<Item = io::Result<S>>,
+    mut graph: W,
+    progress: &report::Phase,
+) -> io::Result<()> {
+    writeln!(&mut graph, "# Build by: pbfextractor")?;
+    writeln!(&mut graph, "# Build on: {:?}", SystemTime::now())?;
+    write!(&mut graph, "# metrics: ")?;
 
-    for metric in l.metrics_indices.keys() {
-        if l.internal_metrics.contains(metric) {
+    for metric in indices.keys() {
+        if internal_metrics.contains(metric) {
             continue;
         }
-        write!(&mut graph, "{}, ", metric).unwrap();
+        write!(&mut graph, "{}, ", metric)?;
     }
 
-    write!(&mut graph, "\n\n").unwrap();
+    write!(&mut graph, "\n\n")?;
 
-    writeln!(&mut graph, "{}", l.metric_count()).unwrap();
-    writeln!(&mut graph, "{}", nodes.len()).unwrap();
-    writeln!(&mut graph, "{}", edges.len()).unwrap();
+    writeln!(&mut graph, "{}", indices.len() - internal_metrics.len())?;
+    writeln!(&mut graph, "{}", nodes.len())?;
+    writeln!(&mut graph, "{}", edge_count)?;
 
     for (i, node) in nodes.iter().enumerate() {
         writeln!(
             &mut graph,
             "{} {} {} {} {} 0",
             i, node.osm_id, node.lat, node.long, node.height,
+        )?;
+    }
+    for edges in edges {
+        let edges = edges?;
+        for edge in edges.borrow().iter() {
+            write!(&mut graph, "{} {} ", edge.source, edge.dest)?;
+            for cost in &edge.costs(indices, internal_metrics) {
+                write!(&mut graph, "{} ", cost.round())?;
+            }
+            writeln!(&mut graph, "-1 -1")?;
+            progress.inc();
+        }
+    }
+    graph.flush()
+}
+
+/// Writes one restriction per line as
+/// `<kind> <from edge> <to edge> <via edge count> <via edges...>`.
+/// Edge ids are the line indices of the edges in the graph file.
+fn write_restrictions<W: Write>(restrictions: &[TurnRestriction], mut out: W) {
+    writeln!(&mut out, "# Build by: pbfextractor").unwrap();
+    writeln!(&mut out, "# Build on: {:?}", SystemTime::now()).unwrap();
+    write!(&mut out, "\n\n").unwrap();
+
+    writeln!(&mut out, "{}", restrictions.len()).unwrap();
+    for r in restrictions {
+        write!(
+            &mut out,
+            "{} {} {} {}",
+            r.kind.as_str(),
+            r.from,
+            r.to,
+            r.via.len()
         )
         .unwrap();
-    }
-    for edge in &edges {
-        write!(&mut graph, "{} {} ", edge.source, edge.dest).unwrap();
-        for cost in &edge.costs(&l.metrics_indices, &l.internal_metrics) {
-            write!(&mut graph, "{} ", cost.round()).unwrap();
+        for v in &r.via {
+            write!(&mut out, " {}", v).unwrap();
         }
-        writeln!(&mut graph, "-1 -1").unwrap();
+        writeln!(&mut out).unwrap();
     }
-    graph.flush().unwrap();
+    out.flush().unwrap();
 }
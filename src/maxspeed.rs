@@ -0,0 +1,339 @@
+/*
+Pbfextractor creates graph files for the cycle-routing projects from pbf and srtm data
+Copyright (C) 2018  Florian Barth
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Parsing of the `maxspeed` tags of ways.
+use super::units::*;
+
+use osmpbfreader::Tags;
+
+/// Speed of `walk` and `living_street`.
+const WALKING_SPEED: f64 = 10.0;
+
+/// Implicit limits of urban and rural roads, trunks and motorways per
+/// country code, `None` where there is no general limit. GB and US values are
+/// in mph, all others in km/h.
+const IMPLICIT_SPEEDS: &[(&str, [Option<f64>; 4])] = &[
+    ("AT", [Some(50.0), Some(100.0), Some(100.0), Some(130.0)]),
+    ("BE", [Some(50.0), Some(90.0), Some(120.0), Some(120.0)]),
+    ("CH", [Some(50.0), Some(80.0), Some(100.0), Some(120.0)]),
+    ("CZ", [Some(50.0), Some(90.0), Some(110.0), Some(130.0)]),
+    ("DE", [Some(50.0), Some(100.0), None, None]),
+    ("DK", [Some(50.0), Some(80.0), Some(80.0), Some(130.0)]),
+    ("ES", [Some(50.0), Some(90.0), Some(100.0), Some(120.0)]),
+    ("FI", [Some(50.0), Some(80.0), Some(100.0), Some(120.0)]),
+    ("FR", [Some(50.0), Some(80.0), Some(110.0), Some(130.0)]),
+    ("GB", [Some(30.0), Some(60.0), Some(70.0), Some(70.0)]),
+    ("HU", [Some(50.0), Some(90.0), Some(110.0), Some(130.0)]),
+    ("IT", [Some(50.0), Some(90.0), Some(110.0), Some(130.0)]),
+    ("NL", [Some(50.0), Some(80.0), Some(100.0), Some(130.0)]),
+    ("NO", [Some(50.0), Some(80.0), Some(80.0), Some(90.0)]),
+    ("PL", [Some(50.0), Some(90.0), Some(100.0), Some(140.0)]),
+    ("PT", [Some(50.0), Some(90.0), Some(100.0), Some(120.0)]),
+    ("RO", [Some(50.0), Some(90.0), Some(100.0), Some(130.0)]),
+    ("RU", [Some(60.0), Some(90.0), Some(90.0), Some(110.0)]),
+    ("SE", [Some(50.0), Some(70.0), Some(90.0), Some(110.0)]),
+    ("SK", [Some(50.0), Some(90.0), Some(90.0), Some(130.0)]),
+    ("UA", [Some(50.0), Some(90.0), Some(110.0), Some(130.0)]),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpeedLimit {
+    Limit(KilometersPerHour),
+    Unlimited,
+}
+
+impl SpeedLimit {
+    /// The lower of both limits.
+    pub fn min(self, other: SpeedLimit) -> SpeedLimit {
+        match (self, other) {
+            (SpeedLimit::Limit(a), SpeedLimit::Limit(b)) => {
+                SpeedLimit::Limit(if a.0 <= b.0 { a } else { b })
+            }
+            (SpeedLimit::Unlimited, l) | (l, SpeedLimit::Unlimited) => l,
+        }
+    }
+}
+
+/// Parses a `maxspeed` value: a number with an optional unit (`km/h`,
+/// `mph` or `knots`), `none`, `walk`, an implicit limit like `RO:urban` or
+/// `DE:zone30`, or several of these separated by `;`, of which the lowest is
+/// used. `None` for values like `signals` and unknown ones.
+pub fn parse_maxspeed(value: &str) -> Option<SpeedLimit> {
+    value
+        .split(';')
+        .filter_map(|v| parse_single(v.trim()))
+        .fold(None, |lowest, l| {
+            Some(lowest.map_or(l, |m: SpeedLimit| m.min(l)))
+        })
+}
+
+fn parse_single(value: &str) -> Option<SpeedLimit> {
+    match value {
+        "none" => Some(SpeedLimit::Unlimited),
+        "walk" | "living_street" => Some(SpeedLimit::Limit(KilometersPerHour(WALKING_SPEED))),
+        _ => match value.split_once(':') {
+            Some((country, kind)) => implicit_speed(country, kind),
+            None => parse_with_unit(value, false),
+        },
+    }
+}
+
+/// Parses a number followed by an optional unit. Numbers without unit are
+/// km/h, or mph if `mph` is set.
+fn parse_with_unit(value: &str, mph: bool) -> Option<SpeedLimit> {
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let number: f64 = value[..end].parse().ok()?;
+    let speed = match value[end..].trim() {
+        "" if mph => KilometersPerHour::from(MilesPerHour(number)),
+        "" | "km/h" | "kmh" | "kph" => KilometersPerHour(number),
+        "mph" => KilometersPerHour::from(MilesPerHour(number)),
+        "knots" => KilometersPerHour::from(Knots(number)),
+        _ => return None,
+    };
+    if speed.0 > 0.0 {
+        Some(SpeedLimit::Limit(speed))
+    } else {
+        None
+    }
+}
+
+/// The limit of a road kind like `urban` in a country, also handles
+/// `zone30`, `zone:30`, `living_street` and `bicycle_road`.
+fn implicit_speed(country: &str, kind: &str) -> Option<SpeedLimit> {
+    let mph = country == "GB" || country == "US";
+    if let Some(zone) = kind.strip_prefix("zone") {
+        return parse_with_unit(zone.trim_start_matches(':'), mph);
+    }
+    let column = match kind {
+        "walk" | "living_street" => return parse_single(kind),
+        "bicycle_road" => return Some(SpeedLimit::Limit(KilometersPerHour(30.0))),
+        "urban" | "nsl_restricted" => 0,
+        "rural" | "nsl_single" => 1,
+        "trunk" | "nsl_dual" => 2,
+        "motorway" => 3,
+        _ => return None,
+    };
+    let (_, speeds) = IMPLICIT_SPEEDS.iter().find(|(c, _)| *c == country)?;
+    Some(match speeds[column] {
+        Some(speed) if mph => SpeedLimit::Limit(KilometersPerHour::from(MilesPerHour(speed))),
+        Some(speed) => SpeedLimit::Limit(KilometersPerHour(speed)),
+        None => SpeedLimit::Unlimited,
+    })
+}
+
+/// Parses `maxspeed:conditional` values like `80 @ (weight>7.5); 30 @ wet`
+/// into limits and their conditions.
+pub fn parse_conditional(value: &str) -> Vec<(SpeedLimit, &str)> {
+    let mut result = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    // `;` also separates times inside of a condition
+    for (i, c) in value.char_indices().chain(Some((value.len(), ';'))) {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ';' if depth == 0 => {
+                if let Some((limit, condition)) = value[start..i].split_once('@') {
+                    let condition = condition.trim();
+                    let condition = condition
+                        .strip_prefix('(')
+                        .and_then(|c| c.strip_suffix(')'))
+                        .unwrap_or(condition)
+                        .trim();
+                    if let Some(limit) = parse_maxspeed(limit.trim()) {
+                        result.push((limit, condition));
+                    }
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Whether a condition of `maxspeed:conditional` holds for all heavy goods
+/// vehicles. Times and weather are unknown and never hold.
+pub fn applies_to_heavy_vehicles(condition: &str) -> bool {
+    let condition: String = condition.chars().filter(|c| !c.is_whitespace()).collect();
+    matches!(condition.as_str(), "hgv" | "goods" | "trailer") || condition.starts_with("weight>")
+}
+
+/// The limit of the way, for `heavy` vehicles including weight and hgv
+/// conditions. Without `maxspeed`, the implicit limit of `maxspeed:type` or
+/// `source:maxspeed` is used, also if `maxspeed` can't be parsed, e.g.
+/// `signals`. `None` if the way has no usable limit.
+pub fn way_speed_limit(tags: &Tags, heavy: bool) -> Option<SpeedLimit> {
+    let mut limit = ["maxspeed", "maxspeed:type", "source:maxspeed"]
+        .iter()
+        .find_map(|key| tags.get(*key).and_then(|v| parse_maxspeed(v)));
+    if heavy {
+        let conditional = tags.get("maxspeed:conditional").map_or(Vec::new(), |v| {
+            parse_conditional(v)
+                .into_iter()
+                .filter(|(_, condition)| applies_to_heavy_vehicles(condition))
+                .map(|(l, _)| l)
+                .collect()
+        });
+        for l in conditional {
+            limit = Some(limit.map_or(l, |m| m.min(l)));
+        }
+    }
+    limit
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// The tags of a way as seen travelling in `direction` along it, with
+/// `maxspeed:forward` or `maxspeed:backward` replacing `maxspeed`. `None`
+/// if the way has no limits for that direction.
+pub fn directional_tags(tags: &Tags, direction: Direction) -> Option<Tags> {
+    let suffix = match direction {
+        Direction::Forward => "forward",
+        Direction::Backward => "backward",
+    };
+    let replaced = [
+        ("maxspeed", format!("maxspeed:{}", suffix)),
+        (
+            "maxspeed:conditional",
+            format!("maxspeed:{}:conditional", suffix),
+        ),
+    ];
+    if !replaced
+        .iter()
+        .any(|(_, key)| tags.contains_key(key.as_str()))
+    {
+        return None;
+    }
+    let mut result = tags.clone();
+    for (key, directional) in &replaced {
+        if let Some(value) = tags.get(directional.as_str()) {
+            result.insert((*key).into(), value.clone());
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+fn kmh(value: &str) -> Option<f64> {
+    match parse_maxspeed(value) {
+        Some(SpeedLimit::Limit(s)) => Some((s.0 * 100.0).round() / 100.0),
+        Some(SpeedLimit::Unlimited) => Some(f64::INFINITY),
+        None => None,
+    }
+}
+
+#[test]
+fn parse_maxspeed_values() {
+    assert_eq!(Some(50.0), kmh("50"));
+    assert_eq!(Some(50.0), kmh(" 50 km/h"));
+    assert_eq!(Some(80.47), kmh("50 mph"));
+    assert_eq!(Some(80.47), kmh("50mph"));
+    assert_eq!(Some(18.52), kmh("10 knots"));
+    assert_eq!(Some(30.0), kmh("30;50"));
+    assert_eq!(Some(30.0), kmh("signals;30"));
+    assert_eq!(Some(f64::INFINITY), kmh("none"));
+    assert_eq!(Some(10.0), kmh("walk"));
+    assert_eq!(Some(10.0), kmh("DE:living_street"));
+    assert_eq!(None, kmh("signals"));
+    assert_eq!(None, kmh("50 furlongs"));
+    assert_eq!(None, kmh("0"));
+    assert_eq!(None, kmh(""));
+}
+
+#[test]
+fn parse_implicit_speeds() {
+    assert_eq!(Some(50.0), kmh("RO:urban"));
+    assert_eq!(Some(80.0), kmh("FR:rural"));
+    assert_eq!(Some(100.0), kmh("DE:rural"));
+    assert_eq!(Some(f64::INFINITY), kmh("DE:motorway"));
+    assert_eq!(Some(30.0), kmh("DE:zone30"));
+    assert_eq!(Some(20.0), kmh("DE:zone:20"));
+    assert_eq!(Some(30.0), kmh("DE:bicycle_road"));
+    assert_eq!(Some(96.56), kmh("GB:nsl_single"));
+    assert_eq!(Some(32.19), kmh("GB:zone20"));
+    assert_eq!(None, kmh("XX:urban"));
+    assert_eq!(None, kmh("DE:village"));
+}
+
+#[test]
+fn conditional_speeds() {
+    let conditional =
+        parse_conditional("80 @ (weight>7.5); 30 @ (Mo-Fr 07:00-09:00; Sa 10:00-12:00); 60 @ wet");
+    assert_eq!(
+        vec![
+            (SpeedLimit::Limit(KilometersPerHour(80.0)), "weight>7.5"),
+            (
+                SpeedLimit::Limit(KilometersPerHour(30.0)),
+                "Mo-Fr 07:00-09:00; Sa 10:00-12:00"
+            ),
+            (SpeedLimit::Limit(KilometersPerHour(60.0)), "wet"),
+        ],
+        conditional
+    );
+    assert!(applies_to_heavy_vehicles("weight > 3.5"));
+    assert!(applies_to_heavy_vehicles("hgv"));
+    assert!(!applies_to_heavy_vehicles("wet"));
+
+    let tags: Tags = vec![
+        ("maxspeed".into(), "100".into()),
+        (
+            "maxspeed:conditional".into(),
+            "60 @ (hgv); 30 @ (22:00-06:00)".into(),
+        ),
+    ]
+    .into_iter()
+    .collect();
+    let limit = |heavy| match way_speed_limit(&tags, heavy) {
+        Some(SpeedLimit::Limit(s)) => s.0,
+        _ => panic!("no limit"),
+    };
+    assert_eq!(100.0, limit(false));
+    assert_eq!(60.0, limit(true));
+
+    let tags: Tags = vec![
+        ("maxspeed".into(), "signals".into()),
+        ("maxspeed:type".into(), "DE:urban".into()),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(
+        Some(SpeedLimit::Limit(KilometersPerHour(50.0))),
+        way_speed_limit(&tags, false)
+    );
+}
+
+#[test]
+fn directional_limits() {
+    let tags: Tags = vec![
+        ("highway".into(), "primary".into()),
+        ("maxspeed".into(), "70".into()),
+        ("maxspeed:backward".into(), "50".into()),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(None, directional_tags(&tags, Direction::Forward));
+    let backward = directional_tags(&tags, Direction::Backward).unwrap();
+    assert_eq!("50", backward.get("maxspeed").unwrap().as_str());
+    assert_eq!("primary", backward.get("highway").unwrap().as_str());
+}
@@ -15,15 +15,17 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
+use super::areas::{AreaIndex, AreaKind};
+use super::dimensions::{parse_length, parse_weight};
+use super::maxspeed::{way_speed_limit, SpeedLimit};
 use super::pbf::{MetricIndices, Node};
+use super::routes::CycleRouteFactors;
 use super::units::*;
 
 use osmpbfreader::Tags;
-use rand::prelude::random;
 use smartstring::{LazyCompact, SmartString};
 
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub enum MetricError {
@@ -31,9 +33,23 @@ pub enum MetricError {
     NonFiniteTime(f64, f64),
 }
 
+impl std::fmt::Display for MetricError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MetricError::UnknownMetric => write!(f, "unknown metric"),
+            MetricError::NonFiniteTime(dist, speed) => write!(
+                f,
+                "non finite travel time for distance {} and speed {}",
+                dist, speed
+            ),
+        }
+    }
+}
+
 pub type MetricResult<T> = Result<T, MetricError>;
 
-pub trait Metric {
+/// Metrics are shared between the threads calculating them.
+pub trait Metric: Send + Sync {
     fn name(&self) -> String;
 }
 
@@ -49,17 +65,47 @@ macro_rules! metric {
 
 pub trait TagMetric<T>: Metric {
     fn calc(&self, tags: &Tags) -> MetricResult<T>;
+
+    /// Whether the value is a total of the whole way, which the loader
+    /// splits over the edges of the way by their length.
+    fn is_way_total(&self) -> bool {
+        false
+    }
+
+    /// Whether the metric uses the cycle routes a way belongs to, which the
+    /// loader then adds to the tags of the way.
+    fn uses_cycle_routes(&self) -> bool {
+        false
+    }
 }
 
 pub trait NodeMetric<T>: Metric {
     fn calc(&self, source: &Node, target: &Node) -> MetricResult<T>;
 }
 
+/// Metric on the tags of a node, added to every edge leaving that node.
+pub trait NodeTagMetric<T>: Metric {
+    fn calc(&self, tags: &Tags) -> MetricResult<T>;
+}
+
 pub trait CostMetric<T>: Metric {
     fn calc(&self, costs: &[f64], map: &MetricIndices) -> MetricResult<T>;
 }
 
-fn bounded_speed(tags: &Tags, driver_max: f64) -> MetricResult<KilometersPerHour> {
+pub trait TurnMetric<T>: Metric {
+    fn calc(&self, from: &Node, via: &Node, to: &Node) -> MetricResult<T>;
+}
+
+/// Metric on an edge and the geometry of its way, given as the bends of the
+/// way at the source and the target of the edge in degrees.
+pub trait WayMetric: Metric {
+    fn calc(&self, source: &Node, target: &Node, bends: (f64, f64)) -> MetricResult<f64>;
+}
+
+fn bounded_speed(tags: &Tags, driver_max: f64, heavy: bool) -> MetricResult<KilometersPerHour> {
+    if is_ferry_route(tags) {
+        return Ok(KilometersPerHour(FERRY_SPEED.min(driver_max)));
+    }
     let street_type = tags.get("highway").map(smartstring::alias::String::as_ref);
     let tag_speed = match street_type {
         Some("motorway") | Some("trunk") => driver_max,
@@ -75,18 +121,10 @@ fn bounded_speed(tags: &Tags, driver_max: f64) -> MetricResult<KilometersPerHour
         _ => 50.0,
     };
 
-    let max_speed_tag = tags.get("maxspeed");
-    let max_speed = match max_speed_tag.map(smartstring::alias::String::as_ref) {
-        Some("none") => Some(driver_max),
-        Some("walk") | Some("DE:walk") => Some(10.0),
-        Some("living_street") | Some("DE:living_street") => Some(10.0),
-        Some(s) => s.parse().ok(),
-        None => None,
-    };
-
-    let speed = match max_speed {
-        Some(s) if s > 0.0 && s <= driver_max => s,
-        _ => tag_speed.min(driver_max),
+    let speed = match way_speed_limit(tags, heavy) {
+        Some(SpeedLimit::Limit(s)) => s.0.min(driver_max),
+        Some(SpeedLimit::Unlimited) => driver_max,
+        None => tag_speed.min(driver_max),
     };
     Ok(KilometersPerHour(speed))
 }
@@ -96,7 +134,7 @@ pub struct CarSpeed;
 metric!(CarSpeed);
 impl TagMetric<KilometersPerHour> for CarSpeed {
     fn calc(&self, tags: &Tags) -> MetricResult<KilometersPerHour> {
-        bounded_speed(&tags, 120.0)
+        bounded_speed(tags, 120.0, false)
     }
 }
 
@@ -105,7 +143,7 @@ pub struct TruckSpeed;
 metric!(TruckSpeed);
 impl TagMetric<KilometersPerHour> for TruckSpeed {
     fn calc(&self, tags: &Tags) -> MetricResult<KilometersPerHour> {
-        bounded_speed(&tags, 80.0)
+        bounded_speed(tags, 80.0, true)
     }
 }
 
@@ -114,7 +152,7 @@ pub struct FastCarSpeed;
 metric!(FastCarSpeed);
 impl TagMetric<KilometersPerHour> for FastCarSpeed {
     fn calc(&self, tags: &Tags) -> MetricResult<KilometersPerHour> {
-        bounded_speed(&tags, 180.0)
+        bounded_speed(tags, 180.0, false)
     }
 }
 
@@ -136,10 +174,161 @@ impl NodeMetric<Meters> for Distance {
     }
 }
 
+/// Share of the edge inside parks, forests and other green areas in percent.
+#[allow(dead_code)]
+pub struct GreenShare(pub Arc<AreaIndex>);
+metric!(GreenShare);
+
+impl NodeMetric<f64> for GreenShare {
+    fn calc(&self, source: &Node, target: &Node) -> MetricResult<f64> {
+        Ok(self.0.share(AreaKind::Green, source, target) * 100.0)
+    }
+}
+
+/// Share of the edge inside industrial areas or close to major roads in
+/// percent.
+#[allow(dead_code)]
+pub struct NoiseShare(pub Arc<AreaIndex>);
+metric!(NoiseShare);
+
+impl NodeMetric<f64> for NoiseShare {
+    fn calc(&self, source: &Node, target: &Node) -> MetricResult<f64> {
+        Ok(self.0.share(AreaKind::Noisy, source, target) * 100.0)
+    }
+}
+
+/// How curvy a road is, the change of heading in degrees per kilometer. Each
+/// bend of a way is split evenly between the edges on both sides, so the
+/// curvature times the length of the edges sums up to the total change of
+/// heading along the way. The ends of ways don't bend.
+#[allow(dead_code)]
+pub struct Curvature;
+metric!(Curvature);
+
+impl WayMetric for Curvature {
+    fn calc(&self, source: &Node, target: &Node, bends: (f64, f64)) -> MetricResult<f64> {
+        let length: f64 = NodeMetric::calc(&Distance, source, target)?;
+        if length > 0.0 {
+            Ok((bends.0 + bends.1) / 2.0 / length * 1000.0)
+        } else {
+            Ok(0.0)
+        }
+    }
+}
+
+/// Speed of ferries and shuttle trains without `duration`.
+const FERRY_SPEED: f64 = 20.0;
+
+/// Whether the way is a ferry or a shuttle train carrying vehicles.
+fn is_ferry_route(tags: &Tags) -> bool {
+    tags.contains("route", "ferry") || tags.contains("route", "shuttle_train")
+}
+
+/// Whether a profile may use a ferry, `None` if the way is no ferry. Access
+/// tags are looked up in the order of `keys`, without them `default` is used.
+fn ferry_access(tags: &Tags, keys: &[&str], default: bool) -> Option<bool> {
+    if !is_ferry_route(tags) {
+        return None;
+    }
+    Some(access_granted(tags, keys).unwrap_or(default))
+}
+
+/// Parses a `duration` given as `mm`, `hh:mm`, `hh:mm:ss` or ISO 8601
+/// like `PT1H30M`.
+fn parse_duration(value: &str) -> Option<Seconds> {
+    let value = value.trim();
+    if let Some(iso) = value.strip_prefix("PT") {
+        let mut seconds = 0.0;
+        let mut number = String::new();
+        for c in iso.chars() {
+            let unit = match c {
+                'H' => 3600.0,
+                'M' => 60.0,
+                'S' => 1.0,
+                _ => {
+                    number.push(c);
+                    continue;
+                }
+            };
+            seconds += number.parse::<f64>().ok()? * unit;
+            number.clear();
+        }
+        return if number.is_empty() && seconds > 0.0 {
+            Some(Seconds(seconds))
+        } else {
+            None
+        };
+    }
+    let parts = value
+        .split(':')
+        .map(|p| p.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let seconds = match parts[..] {
+        [minutes] => minutes * 60.0,
+        [hours, minutes] => hours * 3600.0 + minutes * 60.0,
+        [hours, minutes, seconds] => hours * 3600.0 + minutes * 60.0 + seconds,
+        _ => return None,
+    };
+    if seconds > 0.0 {
+        Some(Seconds(seconds))
+    } else {
+        None
+    }
+}
+
+/// 1 on ferries and shuttle trains, so routers can avoid them.
+#[allow(dead_code)]
+pub struct FerryFlag;
+metric!(FerryFlag);
+
+impl TagMetric<f64> for FerryFlag {
+    fn calc(&self, tags: &Tags) -> MetricResult<f64> {
+        Ok(flag(is_ferry_route(tags)))
+    }
+}
+
+/// The `duration` of a ferry in seconds, split over its edges by length.
+/// 0 for ferries without duration and all other ways.
+#[allow(dead_code)]
+pub struct FerryDuration;
+metric!(FerryDuration);
+
+impl TagMetric<f64> for FerryDuration {
+    fn calc(&self, tags: &Tags) -> MetricResult<f64> {
+        if !is_ferry_route(tags) {
+            return Ok(0.0);
+        }
+        Ok(tags
+            .get("duration")
+            .and_then(|d| parse_duration(d))
+            .map_or(0.0, |d| d.0))
+    }
+
+    fn is_way_total(&self) -> bool {
+        true
+    }
+}
+
+/// Time on a ferry edge, from `FerryDuration` if known and from the default
+/// ferry speed otherwise. `None` for other edges and if `FerryFlag` is not
+/// calculated.
+fn ferry_time(costs: &[f64], map: &MetricIndices, dist: f64) -> Option<Seconds> {
+    let flag = *map.get(&FerryFlag.name())?;
+    if costs[flag] == 0.0 {
+        return None;
+    }
+    let duration = map.get(&FerryDuration.name()).map_or(0.0, |i| costs[*i]);
+    if duration > 0.0 {
+        Some(Seconds(duration))
+    } else {
+        Some(Meters(dist) / MetersPerSecond::from(KilometersPerHour(FERRY_SPEED)))
+    }
+}
+
 #[allow(dead_code)]
 pub struct TravelTime<D: Metric, S: Metric> {
-    distance: Rc<D>,
-    speed: Rc<S>,
+    distance: Arc<D>,
+    speed: Arc<S>,
 }
 
 impl<D, S> Metric for TravelTime<D, S>
@@ -161,7 +350,7 @@ where
     D: Metric,
     S: Metric,
 {
-    pub fn new(distance: Rc<D>, speed: Rc<S>) -> TravelTime<D, S> {
+    pub fn new(distance: Arc<D>, speed: Arc<S>) -> TravelTime<D, S> {
         TravelTime { distance, speed }
     }
 }
@@ -180,6 +369,9 @@ where
             .ok_or(MetricError::UnknownMetric)?;
 
         let dist = Meters(costs[dist_index]);
+        if let Some(time) = ferry_time(costs, map, dist.0) {
+            return Ok(time);
+        }
         let speed = KilometersPerHour(costs[speed_index]);
         let time = dist / MetersPerSecond::from(speed);
 
@@ -218,6 +410,119 @@ where
     }
 }
 
+/// Initial bearing in degrees from `source` towards `target`.
+fn bearing(source: &Node, target: &Node) -> f64 {
+    let theta1 = source.lat.to_radians();
+    let theta2 = target.lat.to_radians();
+    let delta_lambda = (target.long - source.long).to_radians();
+    let y = delta_lambda.sin() * theta2.cos();
+    let x = theta1.cos() * theta2.sin() - theta1.sin() * theta2.cos() * delta_lambda.cos();
+    y.atan2(x).to_degrees()
+}
+
+/// Change of direction in degrees between 0 (straight on) and 180 (u-turn).
+pub fn turn_angle(from: &Node, via: &Node, to: &Node) -> f64 {
+    let diff = (bearing(via, to) - bearing(from, via)).abs() % 360.0;
+    if diff > 180.0 {
+        360.0 - diff
+    } else {
+        diff
+    }
+}
+
+/// Bend of a way at `via` in degrees, 0 if a neighbour is at the same
+/// position, which gives no direction.
+pub fn bend(from: &Node, via: &Node, to: &Node) -> f64 {
+    let same = |a: &Node, b: &Node| a.lat == b.lat && a.long == b.long;
+    if same(from, via) || same(via, to) {
+        0.0
+    } else {
+        turn_angle(from, via, to)
+    }
+}
+
+/// Time penalty for turning, growing linearly with the turn angle up to
+/// `max_turn` for a full reversal. U-turns additionally cost `u_turn`.
+pub struct TurnCost {
+    pub max_turn: Seconds,
+    pub u_turn: Seconds,
+}
+
+metric!(TurnCost);
+
+impl Default for TurnCost {
+    fn default() -> Self {
+        TurnCost {
+            max_turn: Seconds(15.0),
+            u_turn: Seconds(20.0),
+        }
+    }
+}
+
+impl TurnMetric<Seconds> for TurnCost {
+    fn calc(&self, from: &Node, via: &Node, to: &Node) -> MetricResult<Seconds> {
+        if from.osm_id == to.osm_id {
+            return Ok(Seconds(self.max_turn.0 + self.u_turn.0));
+        }
+        let angle = turn_angle(from, via, to);
+        Ok(Seconds(self.max_turn.0 * angle / 180.0))
+    }
+}
+
+impl<T> TurnMetric<f64> for T
+where
+    T: TurnMetric<Seconds>,
+{
+    fn calc(&self, from: &Node, via: &Node, to: &Node) -> MetricResult<f64> {
+        TurnMetric::<Seconds>::calc(self, from, via, to).map(|c| c.0)
+    }
+}
+
+impl<T> NodeTagMetric<f64> for T
+where
+    T: NodeTagMetric<Seconds>,
+{
+    fn calc(&self, tags: &Tags) -> MetricResult<f64> {
+        NodeTagMetric::<Seconds>::calc(self, tags).map(|c| c.0)
+    }
+}
+
+#[allow(dead_code)]
+pub struct TrafficSignalDelay;
+metric!(TrafficSignalDelay);
+
+impl NodeTagMetric<Seconds> for TrafficSignalDelay {
+    fn calc(&self, tags: &Tags) -> MetricResult<Seconds> {
+        let highway = tags.get("highway").map(smartstring::alias::String::as_ref);
+        let delay = match highway {
+            Some("traffic_signals") => 20.0,
+            Some("stop") => 5.0,
+            Some("give_way") | Some("crossing") => 2.0,
+            _ => 0.0,
+        };
+        Ok(Seconds(delay))
+    }
+}
+
+#[allow(dead_code)]
+pub struct BarrierDelay;
+metric!(BarrierDelay);
+
+impl NodeTagMetric<Seconds> for BarrierDelay {
+    fn calc(&self, tags: &Tags) -> MetricResult<Seconds> {
+        let barrier = tags.get("barrier").map(smartstring::alias::String::as_ref);
+        let delay = match barrier {
+            Some("border_control") => 60.0,
+            Some("gate") | Some("swing_gate") | Some("toll_booth") => 30.0,
+            Some("lift_gate") | Some("kissing_gate") => 15.0,
+            Some("cycle_barrier") => 10.0,
+            Some("cattle_grid") => 5.0,
+            _ => 0.0,
+        };
+        Ok(Seconds(delay))
+    }
+}
+
 #[allow(dead_code)]
 pub struct HeightAscent;
 metric!(HeightAscent);
@@ -233,228 +538,1502 @@ impl NodeMetric<Meters> for HeightAscent {
     }
 }
 
+/// Difficulty of a hiking trail from its `sac_scale`, 1 for ordinary ways
+/// and easy hiking trails up to 6 for difficult alpine routes.
 #[allow(dead_code)]
-pub struct UnsuitDistMetric<U, D> {
-    distance: Rc<D>,
-    unsuitability: Rc<U>,
+pub struct HikingDifficulty;
+metric!(HikingDifficulty);
+
+impl TagMetric<f64> for HikingDifficulty {
+    fn calc(&self, tags: &Tags) -> MetricResult<f64> {
+        Ok(sac_scale(tags).map_or(1.0, f64::from))
+    }
 }
 
-impl<U, D> Metric for UnsuitDistMetric<U, D>
-where
-    D: Metric,
-    U: Metric,
-{
+/// The `sac_scale` of a way as grade from 1 to 6.
+fn sac_scale(tags: &Tags) -> Option<u8> {
+    match tags
+        .get("sac_scale")
+        .map(smartstring::alias::String::as_ref)
+    {
+        Some("hiking") => Some(1),
+        Some("mountain_hiking") => Some(2),
+        Some("demanding_mountain_hiking") => Some(3),
+        Some("alpine_hiking") => Some(4),
+        Some("demanding_alpine_hiking") => Some(5),
+        Some("difficult_alpine_hiking") => Some(6),
+        _ => None,
+    }
+}
+
+/// Walking speed on flat ground, slower on steps and difficult trails.
+/// `WalkingTime` adjusts it to the slope of an edge.
+#[allow(dead_code)]
+pub struct WalkingSpeed;
+metric!(WalkingSpeed);
+
+impl TagMetric<KilometersPerHour> for WalkingSpeed {
+    fn calc(&self, tags: &Tags) -> MetricResult<KilometersPerHour> {
+        const FLAT: f64 = 5.0;
+        if is_ferry_route(tags) {
+            return Ok(KilometersPerHour(FERRY_SPEED));
+        }
+        if tags.contains("highway", "steps") {
+            return Ok(KilometersPerHour(2.0));
+        }
+        let factor = match sac_scale(tags) {
+            None | Some(1) => 1.0,
+            Some(2) => 0.8,
+            Some(3) => 0.6,
+            Some(4) => 0.5,
+            Some(5) => 0.4,
+            Some(_) => 0.3,
+        };
+        Ok(KilometersPerHour(FLAT * factor))
+    }
+}
+
+/// Tobler's hiking function, the walking speed in km/h on a slope `grade`
+/// (rise over run). The fastest speed is reached slightly downhill.
+pub fn tobler_speed(grade: f64) -> KilometersPerHour {
+    KilometersPerHour(6.0 * (-3.5 * (grade + 0.05).abs()).exp())
+}
+
+/// Walking time from the distance, `HeightAscent` and `HeightDescent` of an
+/// edge. The flat speed of the speed metric is scaled with Tobler's hiking
+/// function.
+#[allow(dead_code)]
+pub struct WalkingTime<D: Metric, S: Metric> {
+    distance: Arc<D>,
+    speed: Arc<S>,
+}
+
+impl<D: Metric, S: Metric> Metric for WalkingTime<D, S> {
     fn name(&self) -> String {
         format!(
-            "UnsuitDistMetric: {} / {}",
+            "WalkingTime: {} / {}",
             self.distance.name(),
-            self.unsuitability.name()
+            self.speed.name()
         )
     }
 }
 
-impl<D, U> UnsuitDistMetric<U, D>
-where
-    D: Metric,
-    U: Metric,
-{
+impl<D: Metric, S: Metric> WalkingTime<D, S> {
     #[allow(dead_code)]
-    pub fn new(distance: Rc<D>, unsuitability: Rc<U>) -> Self {
-        UnsuitDistMetric {
-            distance,
-            unsuitability,
-        }
+    pub fn new(distance: Arc<D>, speed: Arc<S>) -> WalkingTime<D, S> {
+        WalkingTime { distance, speed }
     }
 }
 
-impl<D, U> CostMetric<f64> for UnsuitDistMetric<U, D>
-where
-    D: Metric,
-    U: Metric,
-{
-    fn calc(&self, costs: &[f64], map: &MetricIndices) -> MetricResult<f64> {
-        let dist_index = *map
-            .get(&self.distance.name())
-            .ok_or(MetricError::UnknownMetric)?;
-        let unsuitability_index = *map
-            .get(&self.unsuitability.name())
-            .ok_or(MetricError::UnknownMetric)?;
+impl<D: Metric, S: Metric> CostMetric<Seconds> for WalkingTime<D, S> {
+    fn calc(&self, costs: &[f64], map: &MetricIndices) -> MetricResult<Seconds> {
+        let cost = |name: String| {
+            map.get(&name)
+                .map(|i| costs[*i])
+                .ok_or(MetricError::UnknownMetric)
+        };
+        let dist = cost(self.distance.name())?;
+        let flat_speed = cost(self.speed.name())?;
+        let rise = cost(HeightAscent.name())? - cost(HeightDescent.name())?;
+        if dist <= 0.0 {
+            return Ok(Seconds(0.0));
+        }
+        if let Some(time) = ferry_time(costs, map, dist) {
+            return Ok(time);
+        }
 
-        let dist = costs[dist_index];
-        let unsuitability = costs[unsuitability_index];
-        Ok(unsuitability * dist)
+        let slope_factor = tobler_speed(rise / dist).0 / tobler_speed(0.0).0;
+        let speed = KilometersPerHour(flat_speed * slope_factor);
+        let time = Meters(dist) / MetersPerSecond::from(speed);
+        if time.0.is_finite() {
+            Ok(time)
+        } else {
+            Err(MetricError::NonFiniteTime(dist, speed.0))
+        }
+    }
+}
+
+/// Steepness of an `incline` tag in percent, regardless of the direction.
+/// Inclines without value are assumed to be moderately steep.
+fn incline_percent(value: &str) -> Option<f64> {
+    const UNKNOWN_INCLINE: f64 = 8.0;
+    let value = value.trim();
+    match value {
+        "up" | "down" | "yes" => return Some(UNKNOWN_INCLINE),
+        "no" => return Some(0.0),
+        _ => {}
+    }
+    if let Some(percent) = value.strip_suffix('%') {
+        percent.trim().parse::<f64>().ok().map(f64::abs)
+    } else if let Some(degrees) = value.strip_suffix('°') {
+        let degrees: f64 = degrees.trim().parse().ok()?;
+        Some(degrees.to_radians().tan().abs() * 100.0)
+    } else {
+        value.parse::<f64>().ok().map(f64::abs)
     }
 }
 
+/// Unsuitability of a way for wheelchairs. Ways along traffic, steep
+/// inclines, raised kerbs and rough surfaces make a way less suitable, the
+/// penalties multiply.
 #[allow(dead_code)]
-pub struct BicycleUnsuitability;
-metric!(BicycleUnsuitability);
+pub struct WheelchairUnsuitability;
+metric!(WheelchairUnsuitability);
 
-impl TagMetric<f64> for BicycleUnsuitability {
+impl TagMetric<f64> for WheelchairUnsuitability {
     fn calc(&self, tags: &Tags) -> MetricResult<f64> {
-        let bicycle_tag = tags.get("bicycle");
-        if tags.get("cycleway").is_some()
-            || bicycle_tag.is_some() && bicycle_tag != Some(&SmartString::<LazyCompact>::from("no"))
-        {
-            return Ok(0.5);
-        }
-
-        let side_walk: Option<&str> = tags.get("sidewalk").map(smartstring::alias::String::as_ref);
-        if side_walk == Some("yes") {
-            return Ok(1.0);
-        }
-
-        let street_type = tags.get("highway").map(smartstring::alias::String::as_ref);
-        let unsuitability = match street_type {
-            Some("primary") => 5.0,
-            Some("primary_link") => 5.0,
-            Some("secondary") => 4.0,
-            Some("secondary_link") => 4.0,
-            Some("tertiary") => 3.0,
-            Some("tertiary_link") => 3.0,
-            Some("road") => 3.0,
-            Some("bridleway") => 3.0,
-            Some("unclassified") => 2.0,
-            Some("residential") => 2.0,
-            Some("traffic_island") => 2.0,
-            Some("living_street") => 1.0,
-            Some("service") => 1.0,
-            Some("track") => 1.0,
-            Some("platform") => 1.0,
-            Some("pedestrian") => 1.0,
-            Some("path") => 1.0,
-            Some("footway") => 1.0,
-            Some("cycleway") => 0.5,
-            _ => 6.0,
+        let tag = |key| tags.get(key).map(smartstring::alias::String::as_ref);
+        let base = match tag("highway") {
+            Some("footway") | Some("pedestrian") | Some("living_street") => 1.0,
+            Some("path") | Some("service") | Some("residential") => 1.5,
+            Some("track") => 2.5,
+            _ if is_ferry_route(tags) => 1.0,
+            _ if tag("sidewalk").is_some_and(|s| s != "no" && s != "none") => 1.5,
+            _ => 3.0,
+        };
+        let incline = match tag("incline").and_then(incline_percent) {
+            Some(p) if p > 12.0 => 8.0,
+            Some(p) if p > 8.0 => 4.0,
+            Some(p) if p > 6.0 => 2.0,
+            _ => 1.0,
         };
-        Ok(unsuitability)
+        let kerb = match tag("kerb") {
+            Some("raised") | Some("yes") => 4.0,
+            Some("rolled") => 1.5,
+            _ => 1.0,
+        };
+        let limited = if tag("wheelchair") == Some("limited") {
+            2.0
+        } else {
+            1.0
+        };
+        let roughness = surface_roughness(tags);
+        Ok(base * incline * kerb * limited * roughness * roughness)
     }
 }
 
 #[allow(dead_code)]
-pub struct EdgeCount;
-metric!(EdgeCount);
+pub struct HeightDescent;
+metric!(HeightDescent);
 
-impl TagMetric<f64> for EdgeCount {
-    fn calc(&self, _: &Tags) -> MetricResult<f64> {
-        Ok(1.0)
+impl NodeMetric<Meters> for HeightDescent {
+    fn calc(&self, source: &Node, target: &Node) -> MetricResult<Meters> {
+        HeightAscent.calc(target, source)
     }
 }
-#[derive(Debug)]
-pub struct Grid {
-    lat_min: f64,
-    lat_max: f64,
-    lng_min: f64,
-    lng_max: f64,
-    side_length: u32,
-}
 
-pub struct Coord {
-    pub x: u32,
-    pub y: u32,
+/// A cyclist and their bicycle for `BicycleTravelTime`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rider {
+    /// Power the rider keeps up.
+    pub power: Watts,
+    /// Mass of rider and bicycle.
+    pub mass: Kilograms,
+    /// Rolling resistance coefficient on smooth asphalt.
+    pub rolling_resistance: f64,
+    /// Drag coefficient times frontal area in m².
+    pub drag_area: f64,
+    /// Speed the rider brakes to when rolling downhill.
+    pub max_speed: KilometersPerHour,
+    /// Speed of pushing the bicycle where riding would be slower.
+    pub min_speed: KilometersPerHour,
 }
 
-impl Grid {
-    pub fn new_ptr() -> Rc<RefCell<Self>> {
-        Rc::new(RefCell::new(Self {
-            lat_min: 90.0,
-            lat_max: -90.0,
-            lng_min: 180.0,
-            lng_max: -180.0,
-            side_length: 20,
-        }))
-    }
-    pub fn add(&mut self, n: &Node) {
-        self.lat_min = n.lat.min(self.lat_min);
-        self.lat_max = n.lat.max(self.lat_max);
-        self.lng_min = n.long.min(self.lng_min);
-        self.lng_max = n.long.max(self.lng_max);
+impl Default for Rider {
+    fn default() -> Self {
+        Rider {
+            power: Watts(100.0),
+            mass: Kilograms(90.0),
+            rolling_resistance: 0.004,
+            drag_area: 0.6,
+            max_speed: KilometersPerHour(45.0),
+            min_speed: KilometersPerHour(4.0),
+        }
     }
-    pub fn index(&self, n: &Node) -> Coord {
-        let x_len = (self.lng_max - self.lng_min) / Into::<f64>::into(self.side_length);
-        let x = (n.long - self.lng_min) / x_len;
-        let y_len = (self.lat_max - self.lat_min) / Into::<f64>::into(self.side_length);
-        let y = (n.lat - self.lat_min) / y_len;
+}
 
-        Coord {
-            x: (x.ceil() - 1.0) as u32,
-            y: (y.ceil() - 1.0) as u32,
+impl std::str::FromStr for Rider {
+    type Err = String;
+
+    /// Parses `POWER,MASS,ROLLING_RESISTANCE` in W, kg and the coefficient
+    /// on smooth asphalt, the other parameters keep their defaults.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid rider {}: {}", s, e))?;
+        match values[..] {
+            [power, mass, rolling_resistance]
+                if power > 0.0 && mass > 0.0 && rolling_resistance >= 0.0 =>
+            {
+                Ok(Rider {
+                    power: Watts(power),
+                    mass: Kilograms(mass),
+                    rolling_resistance,
+                    ..Rider::default()
+                })
+            }
+            _ => Err(format!(
+                "rider {} needs a positive power and mass and a rolling resistance of at least 0",
+                s
+            )),
         }
     }
 }
 
-pub struct GridX(pub Rc<RefCell<Grid>>);
-metric!(GridX);
-impl NodeMetric<f64> for GridX {
-    fn calc(&self, a: &Node, _: &Node) -> MetricResult<f64> {
-        if self.0.borrow().index(a).x % 2 == 0 {
-            Ok(20.0)
-        } else {
-            Ok(1.0)
+impl Rider {
+    /// Steady speed at the rider's power on a road with the slope `grade`
+    /// (rise over run) and the rolling resistance coefficient `crr`.
+    pub fn speed(&self, grade: f64, crr: f64) -> MetersPerSecond {
+        const GRAVITY: f64 = 9.81;
+        const AIR_DENSITY: f64 = 1.2;
+        let theta = grade.atan();
+        // constant and quadratic part of the resisting force
+        let slope_and_rolling = self.mass.0 * GRAVITY * (theta.sin() + crr * theta.cos());
+        let drag = 0.5 * AIR_DENSITY * self.drag_area;
+        let needed_power = |v: f64| v * (slope_and_rolling + drag * v * v);
+
+        let min = MetersPerSecond::from(self.min_speed).0;
+        let max = MetersPerSecond::from(self.max_speed).0;
+        if needed_power(max) <= self.power.0 {
+            return MetersPerSecond(max);
         }
+        // the needed power is below the power at 0 and grows beyond the
+        // single positive root, even downhill
+        let (mut low, mut high) = (0.0, max);
+        for _ in 0..50 {
+            let mid = (low + high) / 2.0;
+            if needed_power(mid) < self.power.0 {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        MetersPerSecond(low.max(min))
     }
 }
 
-pub struct GridY(pub Rc<RefCell<Grid>>);
-metric!(GridY);
-impl NodeMetric<f64> for GridY {
-    fn calc(&self, a: &Node, _: &Node) -> MetricResult<f64> {
-        if self.0.borrow().index(a).y % 2 == 0 {
-            Ok(20.0)
-        } else {
-            Ok(1.0)
-        }
+/// Travel time of a `Rider` from the distance, `HeightAscent` and
+/// `HeightDescent` of an edge. The rolling resistance grows with the square
+/// of the `SurfaceRoughness`, if that metric is calculated as well.
+#[allow(dead_code)]
+pub struct BicycleTravelTime<D: Metric> {
+    distance: Arc<D>,
+    rider: Rider,
+}
+
+impl<D: Metric> Metric for BicycleTravelTime<D> {
+    fn name(&self) -> String {
+        format!(
+            "BicycleTravelTime: {} W / {} kg",
+            self.rider.power.0, self.rider.mass.0
+        )
     }
 }
 
-pub struct ChessBoard(pub Rc<RefCell<Grid>>);
-metric!(ChessBoard);
-impl NodeMetric<f64> for ChessBoard {
-    fn calc(&self, a: &Node, _: &Node) -> MetricResult<f64> {
-        let c = self.0.borrow().index(a);
-        if c.y % 2 == 0 && c.x % 2 == 0 {
-            Ok(20.0)
-        } else {
-            Ok(1.0)
-        }
+impl<D: Metric> BicycleTravelTime<D> {
+    #[allow(dead_code)]
+    pub fn new(distance: Arc<D>, rider: Rider) -> BicycleTravelTime<D> {
+        BicycleTravelTime { distance, rider }
     }
 }
 
-pub struct RandomWeights;
-metric!(RandomWeights);
-impl TagMetric<f64> for RandomWeights {
-    fn calc(&self, _tags: &Tags) -> MetricResult<f64> {
-        Ok(random::<f64>() * 20.0)
+impl<D: Metric> CostMetric<Seconds> for BicycleTravelTime<D> {
+    fn calc(&self, costs: &[f64], map: &MetricIndices) -> MetricResult<Seconds> {
+        let cost = |name: String| {
+            map.get(&name)
+                .map(|i| costs[*i])
+                .ok_or(MetricError::UnknownMetric)
+        };
+        let dist = cost(self.distance.name())?;
+        let rise = cost(HeightAscent.name())? - cost(HeightDescent.name())?;
+        let roughness = cost(SurfaceRoughness.name()).unwrap_or(1.0);
+        if dist <= 0.0 {
+            return Ok(Seconds(0.0));
+        }
+        if let Some(time) = ferry_time(costs, map, dist) {
+            return Ok(time);
+        }
+
+        let crr = self.rider.rolling_resistance * roughness * roughness;
+        let speed = self.rider.speed(rise / dist, crr);
+        Ok(Meters(dist) / speed)
     }
 }
 
-pub trait EdgeFilter {
-    fn is_invalid(&self, tags: &Tags) -> bool;
+/// Parameters of a battery powered vehicle for `EnergyConsumption`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vehicle {
+    pub mass: Kilograms,
+    pub rolling_resistance: f64,
+    /// Drag coefficient times frontal area in m².
+    pub drag_area: f64,
+    /// Share of the battery energy moving the vehicle.
+    pub drivetrain_efficiency: f64,
+    /// Share of the energy gained downhill that is stored in the battery.
+    pub recuperation_efficiency: f64,
+    /// Power of heating, lights and electronics.
+    pub auxiliary_power: Watts,
+    /// Share of the energy drawn from the battery, below 1 if the rider of
+    /// an e-bike pedals along.
+    pub battery_share: f64,
 }
 
 #[allow(dead_code)]
-pub struct BicycleEdgeFilter;
-
-impl EdgeFilter for BicycleEdgeFilter {
-    fn is_invalid(&self, tags: &Tags) -> bool {
-        let bicycle_tag = tags.get("bicycle");
-        if bicycle_tag == Some(&SmartString::<LazyCompact>::from("no")) {
-            return true;
+impl Vehicle {
+    pub fn electric_car() -> Vehicle {
+        Vehicle {
+            mass: Kilograms(1800.0),
+            rolling_resistance: 0.009,
+            drag_area: 0.6,
+            drivetrain_efficiency: 0.85,
+            recuperation_efficiency: 0.6,
+            auxiliary_power: Watts(1000.0),
+            battery_share: 1.0,
         }
-        if tags.get("cycleway").is_some()
-            || bicycle_tag.is_some() && bicycle_tag != Some(&SmartString::<LazyCompact>::from("no"))
-        {
-            return false;
+    }
+
+    pub fn electric_truck() -> Vehicle {
+        Vehicle {
+            mass: Kilograms(30000.0),
+            rolling_resistance: 0.006,
+            drag_area: 6.0,
+            drivetrain_efficiency: 0.85,
+            recuperation_efficiency: 0.6,
+            auxiliary_power: Watts(3000.0),
+            battery_share: 1.0,
         }
+    }
 
-        let street_type = tags.get("highway").map(smartstring::alias::String::as_ref);
-        let side_walk: Option<&str> = tags.get("sidewalk").map(smartstring::alias::String::as_ref);
-        let has_side_walk: bool = match side_walk {
-            Some(s) => s != "no",
-            None => false,
-        };
-        if has_side_walk {
-            return false;
+    pub fn e_bike() -> Vehicle {
+        Vehicle {
+            mass: Kilograms(100.0),
+            rolling_resistance: 0.006,
+            drag_area: 0.6,
+            drivetrain_efficiency: 0.75,
+            recuperation_efficiency: 0.0,
+            auxiliary_power: Watts(0.0),
+            battery_share: 0.5,
+        }
+    }
+
+    /// Changes the parameters given as comma separated `name=value` pairs,
+    /// e.g. `mass=2100,auxiliary_power=500`. The names are the ones of the
+    /// fields, masses are in kg and powers in W.
+    pub fn with_parameters(mut self, params: &str) -> Result<Vehicle, String> {
+        for param in params.split(',').filter(|p| !p.trim().is_empty()) {
+            let (name, value) = param
+                .split_once('=')
+                .ok_or_else(|| format!("vehicle parameter {} needs NAME=VALUE", param))?;
+            let value: f64 = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid value of vehicle parameter {}", param))?;
+            let share = (0.0..=1.0).contains(&value);
+            match name.trim() {
+                "mass" if value > 0.0 => self.mass = Kilograms(value),
+                "rolling_resistance" if value >= 0.0 => self.rolling_resistance = value,
+                "drag_area" if value >= 0.0 => self.drag_area = value,
+                "drivetrain_efficiency" if share && value > 0.0 => {
+                    self.drivetrain_efficiency = value
+                }
+                "recuperation_efficiency" if share => self.recuperation_efficiency = value,
+                "auxiliary_power" if value >= 0.0 => self.auxiliary_power = Watts(value),
+                "battery_share" if share => self.battery_share = value,
+                _ => return Err(format!("unknown or invalid vehicle parameter {}", param)),
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// Energy in Wh a `Vehicle` draws from its battery on an edge, from the
+/// distance, the speed, `HeightAscent` and `HeightDescent` of the edge.
+/// Energy recuperated downhill reduces the consumption of the edge, but
+/// never below 0, so the costs stay usable for Dijkstra.
+#[allow(dead_code)]
+pub struct EnergyConsumption<D: Metric, S: Metric> {
+    distance: Arc<D>,
+    speed: Arc<S>,
+    vehicle: Vehicle,
+}
+
+impl<D: Metric, S: Metric> Metric for EnergyConsumption<D, S> {
+    fn name(&self) -> String {
+        format!(
+            "EnergyConsumption: {} / {} / {} kg",
+            self.distance.name(),
+            self.speed.name(),
+            self.vehicle.mass.0
+        )
+    }
+}
+
+impl<D: Metric, S: Metric> EnergyConsumption<D, S> {
+    #[allow(dead_code)]
+    pub fn new(distance: Arc<D>, speed: Arc<S>, vehicle: Vehicle) -> EnergyConsumption<D, S> {
+        EnergyConsumption {
+            distance,
+            speed,
+            vehicle,
+        }
+    }
+}
+
+impl<D: Metric, S: Metric> CostMetric<f64> for EnergyConsumption<D, S> {
+    fn calc(&self, costs: &[f64], map: &MetricIndices) -> MetricResult<f64> {
+        const GRAVITY: f64 = 9.81;
+        const AIR_DENSITY: f64 = 1.2;
+        const JOULES_PER_WH: f64 = 3600.0;
+        let cost = |name: String| {
+            map.get(&name)
+                .map(|i| costs[*i])
+                .ok_or(MetricError::UnknownMetric)
+        };
+        let dist = cost(self.distance.name())?;
+        let speed = MetersPerSecond::from(KilometersPerHour(cost(self.speed.name())?));
+        let rise = cost(HeightAscent.name())? - cost(HeightDescent.name())?;
+        // the vehicle doesn't drive on a ferry
+        if dist <= 0.0 || ferry_time(costs, map, dist).is_some() {
+            return Ok(0.0);
+        }
+        if speed.0 <= 0.0 {
+            return Err(MetricError::NonFiniteTime(dist, speed.0));
+        }
+
+        let v = &self.vehicle;
+        let weight = v.mass.0 * GRAVITY;
+        let rolling = weight * v.rolling_resistance * dist;
+        let drag = 0.5 * AIR_DENSITY * v.drag_area * speed.0 * speed.0 * dist;
+        let mechanical = rolling + drag + weight * rise;
+        let battery = if mechanical > 0.0 {
+            mechanical / v.drivetrain_efficiency
+        } else {
+            mechanical * v.recuperation_efficiency
+        };
+        let auxiliary = v.auxiliary_power.0 * (Meters(dist) / speed).0;
+        let joules = battery * v.battery_share + auxiliary;
+        Ok((joules / JOULES_PER_WH).max(0.0))
+    }
+}
+
+/// Width of a lane in meters, to estimate the width of roads.
+const LANE_WIDTH: f64 = 3.0;
+
+/// Lanes of the whole road in both directions, from `lanes` or the sum of
+/// `lanes:forward`, `lanes:backward` and `lanes:both_ways`. Without them
+/// the default of the kind of road is used, halved on one way roads.
+fn lanes(tags: &Tags) -> f64 {
+    let count = |key: &str| {
+        tags.get(key)
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|l| *l > 0.0)
+    };
+    if let Some(lanes) = count("lanes") {
+        return lanes;
+    }
+    let directional: Vec<f64> = ["lanes:forward", "lanes:backward", "lanes:both_ways"]
+        .iter()
+        .filter_map(|k| count(k))
+        .collect();
+    if !directional.is_empty() {
+        return directional.iter().sum();
+    }
+
+    let highway: Option<&str> = tags.get("highway").map(smartstring::alias::String::as_ref);
+    let default = match highway {
+        Some("motorway") | Some("trunk") => 4.0,
+        Some("primary")
+        | Some("secondary")
+        | Some("tertiary")
+        | Some("unclassified")
+        | Some("residential")
+        | Some("road")
+        | Some("motorway_link")
+        | Some("trunk_link")
+        | Some("primary_link")
+        | Some("secondary_link")
+        | Some("tertiary_link") => 2.0,
+        _ => 1.0,
+    };
+    let oneway: Option<&str> = tags.get("oneway").map(smartstring::alias::String::as_ref);
+    let is_one_way = matches!(oneway, Some("yes") | Some("true") | Some("1") | Some("-1"))
+        || highway == Some("motorway")
+        || tags.contains("junction", "roundabout");
+    if is_one_way {
+        (default / 2.0_f64).ceil()
+    } else {
+        default
+    }
+}
+
+#[allow(dead_code)]
+pub struct Lanes;
+metric!(Lanes);
+
+impl TagMetric<f64> for Lanes {
+    fn calc(&self, tags: &Tags) -> MetricResult<f64> {
+        Ok(lanes(tags))
+    }
+}
+
+/// Width of the road in meters from `width` or `est_width`. Without them
+/// paths get a default and roads are as wide as their `Lanes`.
+#[allow(dead_code)]
+pub struct Width;
+metric!(Width);
+
+impl TagMetric<f64> for Width {
+    fn calc(&self, tags: &Tags) -> MetricResult<f64> {
+        let tagged = ["width", "est_width"]
+            .iter()
+            .filter_map(|k| tags.get(*k))
+            .find_map(|v| parse_length(v));
+        if let Some(width) = tagged {
+            return Ok(width.0);
+        }
+        let highway: Option<&str> = tags.get("highway").map(smartstring::alias::String::as_ref);
+        Ok(match highway {
+            Some("path") | Some("footway") | Some("bridleway") | Some("steps") => 1.5,
+            Some("cycleway") => 2.0,
+            Some("track") => 2.5,
+            _ => lanes(tags) * LANE_WIDTH,
+        })
+    }
+}
+
+/// Value of flag metrics, 1 if the way has the property.
+fn flag(set: bool) -> f64 {
+    if set {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Whether a tag is set to anything but `no`, except the given values.
+fn tag_set_except(tags: &Tags, key: &str, except: &[&str]) -> bool {
+    tags.get(key)
+        .map(smartstring::alias::String::as_ref)
+        .is_some_and(|v| v != "no" && !except.contains(&v))
+}
+
+/// Ways with a toll for cars.
+#[allow(dead_code)]
+pub struct Toll;
+metric!(Toll);
+
+impl TagMetric<f64> for Toll {
+    fn calc(&self, tags: &Tags) -> MetricResult<f64> {
+        Ok(flag(
+            tags.contains("toll", "yes") || tags.contains("toll:motorcar", "yes"),
+        ))
+    }
+}
+
+/// Motorways and their links.
+#[allow(dead_code)]
+pub struct Motorway;
+metric!(Motorway);
+
+impl TagMetric<f64> for Motorway {
+    fn calc(&self, tags: &Tags) -> MetricResult<f64> {
+        let highway = tags.get("highway").map(smartstring::alias::String::as_ref);
+        Ok(flag(matches!(
+            highway,
+            Some("motorway") | Some("motorway_link")
+        )))
+    }
+}
+
+/// Ways without paving, by `surface` or, without it, by `tracktype`.
+#[allow(dead_code)]
+pub struct Unpaved;
+metric!(Unpaved);
+
+impl TagMetric<f64> for Unpaved {
+    fn calc(&self, tags: &Tags) -> MetricResult<f64> {
+        let surface = tags.get("surface").map(smartstring::alias::String::as_ref);
+        let unpaved = match surface {
+            Some(s) => matches!(
+                s,
+                "unpaved"
+                    | "compacted"
+                    | "fine_gravel"
+                    | "gravel"
+                    | "pebblestone"
+                    | "ground"
+                    | "dirt"
+                    | "earth"
+                    | "grass"
+                    | "sand"
+                    | "mud"
+                    | "woodchips"
+            ),
+            None => match tags.get("tracktype").map(|t| t.as_str()) {
+                Some(t) => t != "grade1",
+                None => tags.contains("highway", "track"),
+            },
+        };
+        Ok(flag(unpaved))
+    }
+}
+
+/// Tunnels, without short passages through buildings.
+#[allow(dead_code)]
+pub struct Tunnel;
+metric!(Tunnel);
+
+impl TagMetric<f64> for Tunnel {
+    fn calc(&self, tags: &Tags) -> MetricResult<f64> {
+        Ok(flag(tag_set_except(tags, "tunnel", &["building_passage"])))
+    }
+}
+
+#[allow(dead_code)]
+pub struct Bridge;
+metric!(Bridge);
+
+impl TagMetric<f64> for Bridge {
+    fn calc(&self, tags: &Tags) -> MetricResult<f64> {
+        Ok(flag(tag_set_except(tags, "bridge", &[])))
+    }
+}
+
+/// Parses the name of a flag metric: `toll`, `motorway`, `unpaved`, `tunnel`,
+/// `bridge` or `ferry`.
+pub fn parse_flag(name: &str) -> Result<Arc<dyn TagMetric<f64>>, String> {
+    match name {
+        "toll" => Ok(Arc::new(Toll)),
+        "motorway" => Ok(Arc::new(Motorway)),
+        "unpaved" => Ok(Arc::new(Unpaved)),
+        "tunnel" => Ok(Arc::new(Tunnel)),
+        "bridge" => Ok(Arc::new(Bridge)),
+        "ferry" => Ok(Arc::new(FerryFlag)),
+        _ => Err(format!("unknown flag {}", name)),
+    }
+}
+
+/// The distance travelled on ways with a flag, so avoiding them can be one
+/// of the criteria of a route.
+#[allow(dead_code)]
+pub struct FlagMetric<D: Metric, F: Metric + ?Sized> {
+    distance: Arc<D>,
+    flag: Arc<F>,
+}
+
+impl<D: Metric, F: Metric + ?Sized> Metric for FlagMetric<D, F> {
+    fn name(&self) -> String {
+        format!(
+            "FlagMetric: {} / {}",
+            self.distance.name(),
+            self.flag.name()
+        )
+    }
+}
+
+impl<D: Metric, F: Metric + ?Sized> FlagMetric<D, F> {
+    #[allow(dead_code)]
+    pub fn new(distance: Arc<D>, flag: Arc<F>) -> FlagMetric<D, F> {
+        FlagMetric { distance, flag }
+    }
+}
+
+impl<D: Metric, F: Metric + ?Sized> CostMetric<f64> for FlagMetric<D, F> {
+    fn calc(&self, costs: &[f64], map: &MetricIndices) -> MetricResult<f64> {
+        let dist_index = *map
+            .get(&self.distance.name())
+            .ok_or(MetricError::UnknownMetric)?;
+        let flag_index = *map
+            .get(&self.flag.name())
+            .ok_or(MetricError::UnknownMetric)?;
+        Ok(costs[dist_index] * costs[flag_index])
+    }
+}
+
+pub struct UnsuitDistMetric<U, D> {
+    distance: Arc<D>,
+    unsuitability: Arc<U>,
+}
+
+impl<U, D> Metric for UnsuitDistMetric<U, D>
+where
+    D: Metric,
+    U: Metric,
+{
+    fn name(&self) -> String {
+        format!(
+            "UnsuitDistMetric: {} / {}",
+            self.distance.name(),
+            self.unsuitability.name()
+        )
+    }
+}
+
+impl<D, U> UnsuitDistMetric<U, D>
+where
+    D: Metric,
+    U: Metric,
+{
+    pub fn new(distance: Arc<D>, unsuitability: Arc<U>) -> Self {
+        UnsuitDistMetric {
+            distance,
+            unsuitability,
+        }
+    }
+}
+
+impl<D, U> CostMetric<f64> for UnsuitDistMetric<U, D>
+where
+    D: Metric,
+    U: Metric,
+{
+    fn calc(&self, costs: &[f64], map: &MetricIndices) -> MetricResult<f64> {
+        let dist_index = *map
+            .get(&self.distance.name())
+            .ok_or(MetricError::UnknownMetric)?;
+        let unsuitability_index = *map
+            .get(&self.unsuitability.name())
+            .ok_or(MetricError::UnknownMetric)?;
+
+        let dist = costs[dist_index];
+        let unsuitability = costs[unsuitability_index];
+        Ok(unsuitability * dist)
+    }
+}
+
+/// How much the road of a bicycle is exposed to traffic, from 0.5 on
+/// cycleways to 6 on roads without known type.
+fn traffic_unsuitability(tags: &Tags) -> f64 {
+    if is_ferry_route(tags) {
+        return 1.0;
+    }
+    let bicycle_tag = tags.get("bicycle");
+    if tags.get("cycleway").is_some()
+        || bicycle_tag.is_some() && bicycle_tag != Some(&SmartString::<LazyCompact>::from("no"))
+    {
+        return 0.5;
+    }
+
+    let side_walk: Option<&str> = tags.get("sidewalk").map(smartstring::alias::String::as_ref);
+    if side_walk == Some("yes") {
+        return 1.0;
+    }
+
+    let street_type = tags.get("highway").map(smartstring::alias::String::as_ref);
+    match street_type {
+        Some("primary") => 5.0,
+        Some("primary_link") => 5.0,
+        Some("secondary") => 4.0,
+        Some("secondary_link") => 4.0,
+        Some("tertiary") => 3.0,
+        Some("tertiary_link") => 3.0,
+        Some("road") => 3.0,
+        Some("bridleway") => 3.0,
+        Some("unclassified") => 2.0,
+        Some("residential") => 2.0,
+        Some("traffic_island") => 2.0,
+        Some("living_street") => 1.0,
+        Some("service") => 1.0,
+        Some("track") => 1.0,
+        Some("platform") => 1.0,
+        Some("pedestrian") => 1.0,
+        Some("path") => 1.0,
+        Some("footway") => 1.0,
+        Some("cycleway") => 0.5,
+        _ => 6.0,
+    }
+}
+
+/// Unsuitability of a way for bicycles, the exposure to traffic multiplied
+/// by the `SurfaceRoughness`. Ways on signposted cycle routes are lowered by
+/// the factor of their network.
+#[allow(dead_code)]
+#[derive(Default)]
+pub struct BicycleUnsuitability {
+    pub route_factors: CycleRouteFactors,
+}
+metric!(BicycleUnsuitability);
+
+impl TagMetric<f64> for BicycleUnsuitability {
+    fn calc(&self, tags: &Tags) -> MetricResult<f64> {
+        Ok(traffic_unsuitability(tags) * surface_roughness(tags) * self.route_factors.factor(tags))
+    }
+
+    fn uses_cycle_routes(&self) -> bool {
+        true
+    }
+}
+
+/// Roughness of the surface of a way for bicycles, 1 for smooth asphalt and
+/// up to 5 for impassable ways. `smoothness` is the most precise tag and
+/// used first, then `surface` and `tracktype`. A `mtb:scale` above 0 makes a
+/// way at least as rough as its difficulty.
+#[allow(dead_code)]
+pub struct SurfaceRoughness;
+metric!(SurfaceRoughness);
+
+impl TagMetric<f64> for SurfaceRoughness {
+    fn calc(&self, tags: &Tags) -> MetricResult<f64> {
+        Ok(surface_roughness(tags))
+    }
+}
+
+fn surface_roughness(tags: &Tags) -> f64 {
+    let tag = |key| tags.get(key).map(smartstring::alias::String::as_ref);
+    let smoothness = match tag("smoothness") {
+        Some("excellent") | Some("good") => Some(1.0),
+        Some("intermediate") => Some(1.2),
+        Some("bad") => Some(1.6),
+        Some("very_bad") => Some(2.2),
+        Some("horrible") => Some(3.0),
+        Some("very_horrible") => Some(4.0),
+        Some("impassable") => Some(5.0),
+        _ => None,
+    };
+    let surface = || match tag("surface") {
+        Some("asphalt") | Some("concrete") | Some("paved") | Some("chipseal") => Some(1.0),
+        Some("paving_stones") | Some("concrete:plates") | Some("metal") => Some(1.1),
+        Some("concrete:lanes") | Some("wood") => Some(1.3),
+        Some("compacted") | Some("fine_gravel") => Some(1.3),
+        Some("sett") => Some(1.5),
+        Some("cobblestone") | Some("unhewn_cobblestone") => Some(2.0),
+        Some("gravel") | Some("pebblestone") | Some("unpaved") => Some(1.8),
+        Some("ground") | Some("dirt") | Some("earth") => Some(1.8),
+        Some("grass") | Some("grass_paver") => Some(2.5),
+        Some("sand") | Some("mud") => Some(3.0),
+        _ => None,
+    };
+    let tracktype = || match tag("tracktype") {
+        Some("grade1") => Some(1.0),
+        Some("grade2") => Some(1.3),
+        Some("grade3") => Some(1.8),
+        Some("grade4") => Some(2.2),
+        Some("grade5") => Some(2.8),
+        _ => None,
+    };
+    let highway = || match tag("highway") {
+        Some("track") => 1.8,
+        Some("path") | Some("bridleway") => 1.5,
+        _ => 1.0,
+    };
+    let roughness: f64 = smoothness
+        .or_else(surface)
+        .or_else(tracktype)
+        .unwrap_or_else(highway);
+
+    let mtb_scale = match tag("mtb:scale").and_then(|s| s.trim_end_matches(['+', '-']).parse().ok())
+    {
+        Some(0) | None => 1.0,
+        Some(1) => 2.0,
+        Some(2) => 3.0,
+        Some(_) => 4.0,
+    };
+    roughness.max(mtb_scale)
+}
+
+/// Travel speed of an everyday cyclist, slower on rough surfaces and on
+/// ways shared with pedestrians.
+pub struct BicycleSpeed;
+metric!(BicycleSpeed);
+
+impl TagMetric<KilometersPerHour> for BicycleSpeed {
+    fn calc(&self, tags: &Tags) -> MetricResult<KilometersPerHour> {
+        const WALKING: f64 = 5.0;
+        if is_ferry_route(tags) {
+            return Ok(KilometersPerHour(FERRY_SPEED));
+        }
+        if tags.contains("bicycle", "dismount") {
+            return Ok(KilometersPerHour(WALKING));
+        }
+        let base = match tags.get("highway").map(smartstring::alias::String::as_ref) {
+            Some("footway") | Some("pedestrian") | Some("platform") => 10.0,
+            Some("living_street") => 12.0,
+            Some("path") | Some("bridleway") | Some("track") => 16.0,
+            _ => 18.0,
+        };
+        Ok(KilometersPerHour(
+            (base / surface_roughness(tags)).max(WALKING),
+        ))
+    }
+}
+
+#[allow(dead_code)]
+pub struct EdgeCount;
+metric!(EdgeCount);
+
+impl TagMetric<f64> for EdgeCount {
+    fn calc(&self, _: &Tags) -> MetricResult<f64> {
+        Ok(1.0)
+    }
+}
+/// Metric on the cell of an edge in a grid laid over the bounding box of the
+/// graph. The grid is only known once all nodes of the graph are.
+pub trait GridMetric: Metric {
+    fn calc(&self, grid: &Grid, source: &Node, target: &Node) -> MetricResult<f64>;
+}
+
+/// Bounding box of the nodes of a graph.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BoundingBox {
+    pub lat_min: f64,
+    pub lat_max: f64,
+    pub lng_min: f64,
+    pub lng_max: f64,
+}
+
+impl BoundingBox {
+    /// The smallest box containing all nodes, an empty box at 0, 0 without
+    /// nodes.
+    pub fn of(nodes: &[Node]) -> BoundingBox {
+        if nodes.is_empty() {
+            return BoundingBox::default();
+        }
+        nodes.iter().fold(
+            BoundingBox {
+                lat_min: f64::INFINITY,
+                lat_max: f64::NEG_INFINITY,
+                lng_min: f64::INFINITY,
+                lng_max: f64::NEG_INFINITY,
+            },
+            |b, n| BoundingBox {
+                lat_min: b.lat_min.min(n.lat),
+                lat_max: b.lat_max.max(n.lat),
+                lng_min: b.lng_min.min(n.long),
+                lng_max: b.lng_max.max(n.long),
+            },
+        )
+    }
+}
+
+/// `side_length` times `side_length` cells of equal size over a bounding box.
+#[derive(Debug)]
+pub struct Grid {
+    bounds: BoundingBox,
+    side_length: u32,
+}
+
+pub struct Coord {
+    pub x: u32,
+    pub y: u32,
+}
+
+impl Grid {
+    pub fn new(bounds: BoundingBox, side_length: u32) -> Grid {
+        assert!(side_length > 0, "a grid needs at least one cell");
+        Grid {
+            bounds,
+            side_length,
+        }
+    }
+
+    /// Position in cells, `(0, 0)` is the south west corner of the grid and
+    /// `(side_length, side_length)` the north east corner. A grid without
+    /// extent along an axis has all nodes at 0 on it.
+    pub fn position(&self, lat: f64, lng: f64) -> (f64, f64) {
+        let side_length = f64::from(self.side_length);
+        let axis = |value: f64, min: f64, max: f64| {
+            if max > min {
+                (value - min) / (max - min) * side_length
+            } else {
+                0.0
+            }
+        };
+        let b = &self.bounds;
+        (
+            axis(lng, b.lng_min, b.lng_max),
+            axis(lat, b.lat_min, b.lat_max),
+        )
+    }
+
+    /// The cell containing the coordinates. Cells include their south and
+    /// west border, nodes on the north and east border of the grid and
+    /// outside of it belong to the closest cell.
+    pub fn cell(&self, lat: f64, lng: f64) -> Coord {
+        let (x, y) = self.position(lat, lng);
+        let clamp = |v: f64| (v.floor().max(0.0) as u32).min(self.side_length - 1);
+        Coord {
+            x: clamp(x),
+            y: clamp(y),
+        }
+    }
+
+    pub fn index(&self, n: &Node) -> Coord {
+        self.cell(n.lat, n.long)
+    }
+
+    /// Offset of the center of the cell from the center of the grid, in
+    /// cells.
+    fn offset_from_center(&self, c: &Coord) -> (f64, f64) {
+        let half = f64::from(self.side_length) / 2.0;
+        (f64::from(c.x) + 0.5 - half, f64::from(c.y) + 0.5 - half)
+    }
+}
+
+/// Values of the two kinds of cells of `GridX`, `GridY` and `ChessBoard`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellWeights {
+    pub even: f64,
+    pub odd: f64,
+}
+
+impl Default for CellWeights {
+    fn default() -> Self {
+        CellWeights {
+            even: 20.0,
+            odd: 1.0,
+        }
+    }
+}
+
+impl CellWeights {
+    fn get(&self, even: bool) -> f64 {
+        if even {
+            self.even
+        } else {
+            self.odd
+        }
+    }
+}
+
+/// Stripes along the y axis, columns with even x get the even weight.
+pub struct GridX(pub CellWeights);
+metric!(GridX);
+impl GridMetric for GridX {
+    fn calc(&self, grid: &Grid, a: &Node, _: &Node) -> MetricResult<f64> {
+        Ok(self.0.get(grid.index(a).x.is_multiple_of(2)))
+    }
+}
+
+/// Stripes along the x axis, rows with even y get the even weight.
+pub struct GridY(pub CellWeights);
+metric!(GridY);
+impl GridMetric for GridY {
+    fn calc(&self, grid: &Grid, a: &Node, _: &Node) -> MetricResult<f64> {
+        Ok(self.0.get(grid.index(a).y.is_multiple_of(2)))
+    }
+}
+
+/// Cells with even x and even y get the even weight.
+pub struct ChessBoard(pub CellWeights);
+metric!(ChessBoard);
+impl GridMetric for ChessBoard {
+    fn calc(&self, grid: &Grid, a: &Node, _: &Node) -> MetricResult<f64> {
+        let c = grid.index(a);
+        Ok(self.0.get(c.y.is_multiple_of(2) && c.x.is_multiple_of(2)))
+    }
+}
+
+/// Changes linearly with the distance of the cell from the center of the
+/// grid, from `center` in the middle to `corner` in the corners.
+pub struct RadialField {
+    pub center: f64,
+    pub corner: f64,
+}
+metric!(RadialField);
+
+impl GridMetric for RadialField {
+    fn calc(&self, grid: &Grid, a: &Node, _: &Node) -> MetricResult<f64> {
+        let (dx, dy) = grid.offset_from_center(&grid.index(a));
+        let half = f64::from(grid.side_length) / 2.0;
+        let t = dx.hypot(dy) / half.hypot(half);
+        Ok(self.center + (self.corner - self.center) * t.min(1.0))
+    }
+}
+
+/// Changes linearly across the grid from `from` to `to` in the direction
+/// `angle`, in degrees counterclockwise from east.
+pub struct GradientField {
+    pub angle: f64,
+    pub from: f64,
+    pub to: f64,
+}
+metric!(GradientField);
+
+impl GridMetric for GradientField {
+    fn calc(&self, grid: &Grid, a: &Node, _: &Node) -> MetricResult<f64> {
+        let (dx, dy) = grid.offset_from_center(&grid.index(a));
+        let (sin, cos) = self.angle.to_radians().sin_cos();
+        let half = f64::from(grid.side_length) / 2.0;
+        // the projection of the corners is the largest possible
+        let max = half * (cos.abs() + sin.abs());
+        let t = ((dx * cos + dy * sin) / max + 1.0) / 2.0;
+        Ok(self.from + (self.to - self.from) * t.clamp(0.0, 1.0))
+    }
+}
+
+/// Smooth value noise over the grid, similar to Perlin noise. Every octave
+/// adds noise with twice the frequency and half the amplitude of the one
+/// before, the first octave has one random value per cell corner.
+pub struct NoiseField {
+    seed: u64,
+    octaves: u32,
+    min: f64,
+    max: f64,
+}
+metric!(NoiseField);
+
+impl NoiseField {
+    pub fn new(seed: u64, octaves: u32, min: f64, max: f64) -> NoiseField {
+        NoiseField {
+            seed,
+            octaves: octaves.max(1),
+            min,
+            max,
+        }
+    }
+
+    /// Random value between 0 and 1 of a lattice point.
+    fn lattice(seed: u64, x: i64, y: i64) -> f64 {
+        let hash = mix(mix(seed.wrapping_add(x as u64)).wrapping_add(y as u64));
+        (hash >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Bilinear interpolation between the lattice points around `(x, y)`
+    /// with smoothed weights.
+    fn value_noise(seed: u64, x: f64, y: f64) -> f64 {
+        let smooth = |t: f64| t * t * (3.0 - 2.0 * t);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (smooth(x - x0), smooth(y - y0));
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let south = lerp(
+            Self::lattice(seed, x0, y0),
+            Self::lattice(seed, x0 + 1, y0),
+            tx,
+        );
+        let north = lerp(
+            Self::lattice(seed, x0, y0 + 1),
+            Self::lattice(seed, x0 + 1, y0 + 1),
+            tx,
+        );
+        lerp(south, north, ty)
+    }
+}
+
+impl GridMetric for NoiseField {
+    fn calc(&self, grid: &Grid, a: &Node, _: &Node) -> MetricResult<f64> {
+        let (x, y) = grid.position(a.lat, a.long);
+        let (mut sum, mut total) = (0.0, 0.0);
+        let (mut amplitude, mut frequency) = (1.0, 1.0);
+        for octave in 0..self.octaves {
+            let seed = mix(self.seed.wrapping_add(u64::from(octave)));
+            sum += amplitude * Self::value_noise(seed, x * frequency, y * frequency);
+            total += amplitude;
+            amplitude /= 2.0;
+            frequency *= 2.0;
+        }
+        Ok(self.min + (self.max - self.min) * sum / total)
+    }
+}
+
+/// A hotspot of `Hotspots`, its radius is given in cells.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hotspot {
+    pub lat: f64,
+    pub lng: f64,
+    pub radius: f64,
+    pub weight: f64,
+}
+
+/// `base` plus the weights of the hotspots nearby. The weight of a hotspot
+/// falls linearly from the cell containing it to 0 at `radius` cells.
+pub struct Hotspots {
+    pub base: f64,
+    pub spots: Vec<Hotspot>,
+}
+metric!(Hotspots);
+
+impl GridMetric for Hotspots {
+    fn calc(&self, grid: &Grid, a: &Node, _: &Node) -> MetricResult<f64> {
+        let c = grid.index(a);
+        let mut value = self.base;
+        for s in &self.spots {
+            let center = grid.cell(s.lat, s.lng);
+            let dx = f64::from(c.x) - f64::from(center.x);
+            let dy = f64::from(c.y) - f64::from(center.y);
+            let distance = dx.hypot(dy);
+            if distance < s.radius {
+                value += s.weight * (1.0 - distance / s.radius);
+            }
+        }
+        Ok(value)
+    }
+}
+
+/// Parses comma separated numbers.
+fn parse_numbers(params: &str) -> Result<Vec<f64>, String> {
+    params
+        .split(',')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            p.trim()
+                .parse()
+                .map_err(|_| format!("invalid number {}", p))
+        })
+        .collect()
+}
+
+/// A grid metric with a name given by the user, so several metrics of the
+/// same kind can be written.
+pub struct NamedGridMetric {
+    name: String,
+    metric: Arc<dyn GridMetric>,
+}
+
+impl Metric for NamedGridMetric {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+impl GridMetric for NamedGridMetric {
+    fn calc(&self, grid: &Grid, source: &Node, target: &Node) -> MetricResult<f64> {
+        self.metric.calc(grid, source, target)
+    }
+}
+
+/// Parses the description of a grid metric:
+/// `x`, `y` or `chess` with optional `:EVEN,ODD` weights,
+/// `radial:CENTER,CORNER`, `gradient:ANGLE,FROM,TO`,
+/// `noise:SEED,OCTAVES,MIN,MAX` and `hotspots:BASE;LAT,LNG,RADIUS,WEIGHT;...`.
+/// The metric is named after its kind, e.g. `NoiseField`, unless the
+/// description starts with `NAME=`.
+pub fn parse_grid_metric(spec: &str) -> Result<Arc<dyn GridMetric>, String> {
+    if let Some((name, spec)) = spec.split_once('=') {
+        if name.trim().is_empty() {
+            return Err(format!("empty name of grid metric {}", spec));
+        }
+        return Ok(Arc::new(NamedGridMetric {
+            name: name.trim().to_owned(),
+            metric: parse_grid_metric(spec)?,
+        }));
+    }
+    let (kind, params) = spec.split_once(':').unwrap_or((spec, ""));
+    if kind == "hotspots" {
+        let mut parts = params.split(';');
+        let base = match parse_numbers(parts.next().unwrap_or(""))?[..] {
+            [base] => base,
+            _ => return Err("hotspots need BASE first".to_owned()),
+        };
+        let spots = parts
+            .map(|p| match parse_numbers(p)?[..] {
+                [lat, lng, radius, weight] if radius > 0.0 => Ok(Hotspot {
+                    lat,
+                    lng,
+                    radius,
+                    weight,
+                }),
+                _ => Err(format!(
+                    "hotspot {} needs LAT,LNG,RADIUS,WEIGHT with RADIUS > 0",
+                    p
+                )),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        return Ok(Arc::new(Hotspots { base, spots }));
+    }
+
+    let params = parse_numbers(params)?;
+    let weights = || match params[..] {
+        [] => Ok(CellWeights::default()),
+        [even, odd] => Ok(CellWeights { even, odd }),
+        _ => Err(format!("{} takes EVEN,ODD weights", kind)),
+    };
+    match kind {
+        "x" => Ok(Arc::new(GridX(weights()?))),
+        "y" => Ok(Arc::new(GridY(weights()?))),
+        "chess" => Ok(Arc::new(ChessBoard(weights()?))),
+        "radial" => match params[..] {
+            [center, corner] => Ok(Arc::new(RadialField { center, corner })),
+            _ => Err("radial needs CENTER,CORNER".to_owned()),
+        },
+        "gradient" => match params[..] {
+            [angle, from, to] => Ok(Arc::new(GradientField { angle, from, to })),
+            _ => Err("gradient needs ANGLE,FROM,TO".to_owned()),
+        },
+        "noise" => match params[..] {
+            [seed, octaves, min, max] if seed >= 0.0 && octaves >= 1.0 => Ok(Arc::new(
+                NoiseField::new(seed as u64, octaves as u32, min, max),
+            )),
+            _ => Err("noise needs SEED,OCTAVES,MIN,MAX with OCTAVES >= 1".to_owned()),
+        },
+        _ => Err(format!("unknown grid metric {}", kind)),
+    }
+}
+
+/// Distribution of the values of `RandomWeights`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RandomDistribution {
+    /// Uniform between `min` and `max`.
+    Uniform {
+        min: f64,
+        max: f64,
+    },
+    Normal {
+        mean: f64,
+        std_dev: f64,
+    },
+    Exponential {
+        mean: f64,
+    },
+    /// The distance of the edge in meters times a factor uniform between
+    /// `min` and `max`, so the weights are correlated with `Distance`.
+    DistanceFactor {
+        min: f64,
+        max: f64,
+    },
+}
+
+impl Default for RandomDistribution {
+    fn default() -> Self {
+        RandomDistribution::Uniform {
+            min: 0.0,
+            max: 20.0,
+        }
+    }
+}
+
+impl std::str::FromStr for RandomDistribution {
+    type Err = String;
+
+    /// Parses `uniform:MIN,MAX`, `normal:MEAN,STD_DEV`, `exponential:MEAN`
+    /// and `distance:MIN,MAX`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (kind, params) = s.split_once(':').unwrap_or((s, ""));
+        let params = parse_numbers(params)?;
+        let range = |params: &[f64]| match params {
+            [min, max] if 0.0 <= *min && min <= max => Ok((*min, *max)),
+            _ => Err(format!("{} needs MIN,MAX with 0 <= MIN <= MAX", kind)),
+        };
+        match kind {
+            "uniform" => range(&params).map(|(min, max)| RandomDistribution::Uniform { min, max }),
+            "distance" => {
+                range(&params).map(|(min, max)| RandomDistribution::DistanceFactor { min, max })
+            }
+            "normal" => match params[..] {
+                [mean, std_dev] if mean >= 0.0 && std_dev >= 0.0 => {
+                    Ok(RandomDistribution::Normal { mean, std_dev })
+                }
+                _ => Err("normal needs MEAN,STD_DEV with MEAN >= 0 and STD_DEV >= 0".to_owned()),
+            },
+            "exponential" => match params[..] {
+                [mean] if mean > 0.0 => Ok(RandomDistribution::Exponential { mean }),
+                _ => Err("exponential needs MEAN > 0".to_owned()),
+            },
+            _ => Err(format!("unknown distribution {}", kind)),
+        }
+    }
+}
+
+/// Random weights derived from the seed and the osm ids of the nodes of an
+/// edge. The same seed gives the same weights in every run, independent of
+/// the order the edges are processed in. Both directions of an edge get the
+/// same weight. The random numbers come from SplitMix64 seeded with a hash
+/// of the seed and the ids, so they don't depend on the version of any
+/// crate. Weights are never negative, negative values of the normal
+/// distribution are drawn again, which truncates it at 0.
+pub struct RandomWeights {
+    seed: u64,
+    distribution: RandomDistribution,
+}
+metric!(RandomWeights);
+
+impl RandomWeights {
+    pub fn new(seed: u64, distribution: RandomDistribution) -> RandomWeights {
+        RandomWeights { seed, distribution }
+    }
+}
+
+/// Finalizer of SplitMix64, spreads similar inputs over all bits.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+impl NodeMetric<f64> for RandomWeights {
+    fn calc(&self, source: &Node, target: &Node) -> MetricResult<f64> {
+        let (a, b) = if source.osm_id < target.osm_id {
+            (source.osm_id, target.osm_id)
+        } else {
+            (target.osm_id, source.osm_id)
+        };
+        let mut state = mix(mix(self.seed.wrapping_add(a as u64)).wrapping_add(b as u64));
+        // uniform in [0, 1)
+        let mut next = || {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            (mix(state) >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let value = match self.distribution {
+            RandomDistribution::Uniform { min, max } => min + (max - min) * next(),
+            RandomDistribution::Normal { mean, std_dev } => loop {
+                // Box-Muller transform, the mean is not negative, so at
+                // least every second value is accepted
+                let (u, v) = (1.0 - next(), next());
+                let z = (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos();
+                let value = mean + std_dev * z;
+                if value >= 0.0 {
+                    break value;
+                }
+            },
+            RandomDistribution::Exponential { mean } => -mean * (1.0 - next()).ln(),
+            RandomDistribution::DistanceFactor { min, max } => {
+                let Meters(distance) = NodeMetric::<Meters>::calc(&Distance, source, target)?;
+                distance * (min + (max - min) * next())
+            }
+        };
+        Ok(value)
+    }
+}
+
+pub trait EdgeFilter: Send + Sync {
+    fn is_invalid(&self, tags: &Tags) -> bool;
+
+    /// Whether a node with these tags can't be passed. Edges leaving such
+    /// nodes are removed from the graph.
+    fn is_blocking(&self, _node_tags: &Tags) -> bool {
+        false
+    }
+}
+
+/// Looks up the access tags in order, most specific first, and returns
+/// whether the first one found grants access.
+fn access_granted(tags: &Tags, keys: &[&str]) -> Option<bool> {
+    keys.iter()
+        .filter_map(|k| tags.get(*k))
+        .map(|v| !matches!(v.as_ref(), "no" | "private" | "agricultural" | "forestry"))
+        .next()
+}
+
+#[allow(dead_code)]
+pub struct BicycleEdgeFilter;
+
+impl EdgeFilter for BicycleEdgeFilter {
+    fn is_invalid(&self, tags: &Tags) -> bool {
+        let shuttle_train = tags.contains("route", "shuttle_train");
+        if let Some(allowed) = ferry_access(tags, &["bicycle", "vehicle", "access"], !shuttle_train)
+        {
+            return !allowed;
+        }
+        let bicycle_tag = tags.get("bicycle");
+        if bicycle_tag == Some(&SmartString::<LazyCompact>::from("no")) {
+            return true;
+        }
+        if tags.get("cycleway").is_some()
+            || bicycle_tag.is_some() && bicycle_tag != Some(&SmartString::<LazyCompact>::from("no"))
+        {
+            return false;
+        }
+
+        let street_type = tags.get("highway").map(smartstring::alias::String::as_ref);
+        let side_walk: Option<&str> = tags.get("sidewalk").map(smartstring::alias::String::as_ref);
+        let has_side_walk: bool = match side_walk {
+            Some(s) => s != "no",
+            None => false,
+        };
+        if has_side_walk {
+            return false;
         }
         matches!(
             street_type,
@@ -472,12 +2051,34 @@ impl EdgeFilter for BicycleEdgeFilter {
                 | None
         )
     }
+
+    fn is_blocking(&self, node_tags: &Tags) -> bool {
+        let barrier = node_tags
+            .get("barrier")
+            .map(smartstring::alias::String::as_ref);
+        if barrier.is_none() {
+            return false;
+        }
+        if let Some(granted) = access_granted(node_tags, &["bicycle", "vehicle", "access"]) {
+            return !granted;
+        }
+        matches!(
+            barrier,
+            Some("stile") | Some("turnstile") | Some("full-height_turnstile") | Some("fence")
+        )
+    }
 }
 #[allow(dead_code)]
 pub struct CarEdgeFilter;
 
 impl EdgeFilter for CarEdgeFilter {
     fn is_invalid(&self, tags: &Tags) -> bool {
+        // many ferries only carry passengers
+        let shuttle_train = tags.contains("route", "shuttle_train");
+        let keys = ["motorcar", "motor_vehicle", "vehicle", "access"];
+        if let Some(allowed) = ferry_access(tags, &keys, shuttle_train) {
+            return !allowed;
+        }
         let street_type = tags.get("highway").map(smartstring::alias::String::as_ref);
         matches!(
             street_type,
@@ -496,17 +2097,881 @@ impl EdgeFilter for CarEdgeFilter {
                 | None
         )
     }
+
+    fn is_blocking(&self, node_tags: &Tags) -> bool {
+        let barrier = node_tags
+            .get("barrier")
+            .map(smartstring::alias::String::as_ref);
+        if barrier.is_none() {
+            return false;
+        }
+        if let Some(granted) = access_granted(
+            node_tags,
+            &["motorcar", "motor_vehicle", "vehicle", "access"],
+        ) {
+            return !granted;
+        }
+        matches!(
+            barrier,
+            Some("bollard")
+                | Some("block")
+                | Some("jersey_barrier")
+                | Some("cycle_barrier")
+                | Some("kissing_gate")
+                | Some("stile")
+                | Some("turnstile")
+                | Some("full-height_turnstile")
+                | Some("fence")
+                | Some("chain")
+        )
+    }
+}
+
+/// Ways a truck can use: the ways of `CarEdgeFilter` without `hgv=no` and
+/// without ways or nodes whose `maxheight`, `maxwidth` or `maxweight` is
+/// below the size of the truck.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TruckEdgeFilter {
+    pub height: Meters,
+    pub width: Meters,
+    pub weight: Kilograms,
+}
+
+impl Default for TruckEdgeFilter {
+    fn default() -> Self {
+        TruckEdgeFilter {
+            height: Meters(4.0),
+            width: Meters(2.55),
+            weight: Kilograms(40_000.0),
+        }
+    }
+}
+
+impl TruckEdgeFilter {
+    fn exceeds_limits(&self, tags: &Tags) -> bool {
+        let values = |keys: &'static [&'static str]| keys.iter().filter_map(move |k| tags.get(*k));
+        values(&["maxheight", "maxheight:physical"])
+            .filter_map(|v| parse_length(v))
+            .any(|limit| limit.0 < self.height.0)
+            || values(&["maxwidth", "maxwidth:physical"])
+                .filter_map(|v| parse_length(v))
+                .any(|limit| limit.0 < self.width.0)
+            || values(&["maxweight", "maxweight:hgv"])
+                .filter_map(|v| parse_weight(v))
+                .any(|limit| limit.0 < self.weight.0)
+    }
+}
+
+/// Parses `HEIGHT,WIDTH,WEIGHT` in meters and tonnes, e.g. `4,2.55,40`.
+impl std::str::FromStr for TruckEdgeFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid truck size {}: {}", s, e))?;
+        match values[..] {
+            [height, width, weight] if height > 0.0 && width > 0.0 && weight > 0.0 => {
+                Ok(TruckEdgeFilter {
+                    height: Meters(height),
+                    width: Meters(width),
+                    weight: Kilograms::from(Tonnes(weight)),
+                })
+            }
+            _ => Err(format!(
+                "truck size {} needs a positive height, width and weight",
+                s
+            )),
+        }
+    }
+}
+
+impl EdgeFilter for TruckEdgeFilter {
+    fn is_invalid(&self, tags: &Tags) -> bool {
+        let shuttle_train = tags.contains("route", "shuttle_train");
+        let keys = ["hgv", "motor_vehicle", "vehicle", "access"];
+        if let Some(allowed) = ferry_access(tags, &keys, shuttle_train) {
+            return !allowed || self.exceeds_limits(tags);
+        }
+        access_granted(tags, &["hgv"]) == Some(false)
+            || CarEdgeFilter.is_invalid(tags)
+            || self.exceeds_limits(tags)
+    }
+
+    fn is_blocking(&self, node_tags: &Tags) -> bool {
+        CarEdgeFilter.is_blocking(node_tags) || self.exceeds_limits(node_tags)
+    }
+}
+
+/// Lets the filter be chosen at runtime.
+impl<F: EdgeFilter + ?Sized> EdgeFilter for Box<F> {
+    fn is_invalid(&self, tags: &Tags) -> bool {
+        (**self).is_invalid(tags)
+    }
+
+    fn is_blocking(&self, node_tags: &Tags) -> bool {
+        (**self).is_blocking(node_tags)
+    }
+}
+
+#[allow(dead_code)]
+pub struct FootEdgeFilter;
+
+impl EdgeFilter for FootEdgeFilter {
+    fn is_invalid(&self, tags: &Tags) -> bool {
+        let shuttle_train = tags.contains("route", "shuttle_train");
+        if let Some(allowed) = ferry_access(tags, &["foot", "access"], !shuttle_train) {
+            return !allowed;
+        }
+        if let Some(granted) = access_granted(tags, &["foot", "access"]) {
+            if !granted || tags.get("highway").is_none() {
+                return true;
+            }
+            if tags.get("foot").is_some() {
+                return false;
+            }
+        }
+        let side_walk: Option<&str> = tags.get("sidewalk").map(smartstring::alias::String::as_ref);
+        if side_walk.is_some_and(|s| s != "no" && s != "none") {
+            return false;
+        }
+        let street_type = tags.get("highway").map(smartstring::alias::String::as_ref);
+        matches!(
+            street_type,
+            Some("motorway")
+                | Some("motorway_link")
+                | Some("trunk")
+                | Some("trunk_link")
+                | Some("proposed")
+                | Some("construction")
+                | Some("raceway")
+                | Some("bus_guideway")
+                | Some("rest_area")
+                | None
+        )
+    }
+
+    fn is_blocking(&self, node_tags: &Tags) -> bool {
+        let barrier = node_tags
+            .get("barrier")
+            .map(smartstring::alias::String::as_ref);
+        if barrier.is_none() {
+            return false;
+        }
+        if let Some(granted) = access_granted(node_tags, &["foot", "access"]) {
+            return !granted;
+        }
+        matches!(barrier, Some("fence") | Some("wall") | Some("hedge"))
+    }
+}
+
+/// Ways a wheelchair can use: the ways of `FootEdgeFilter` without steps,
+/// hiking trails and impassable surfaces, unless tagged `wheelchair=yes`.
+#[allow(dead_code)]
+pub struct WheelchairEdgeFilter;
+
+impl EdgeFilter for WheelchairEdgeFilter {
+    fn is_invalid(&self, tags: &Tags) -> bool {
+        match tags
+            .get("wheelchair")
+            .map(smartstring::alias::String::as_ref)
+        {
+            Some("no") => return true,
+            Some("yes") | Some("designated") => return FootEdgeFilter.is_invalid(tags),
+            _ => {}
+        }
+        if tags.contains("highway", "steps") && !tags.contains("ramp:wheelchair", "yes") {
+            return true;
+        }
+        if sac_scale(tags).is_some_and(|s| s > 1) {
+            return true;
+        }
+        let smoothness = tags
+            .get("smoothness")
+            .map(smartstring::alias::String::as_ref);
+        if matches!(
+            smoothness,
+            Some("very_horrible") | Some("impassable") | Some("horrible")
+        ) {
+            return true;
+        }
+        FootEdgeFilter.is_invalid(tags)
+    }
+
+    fn is_blocking(&self, node_tags: &Tags) -> bool {
+        let barrier = node_tags
+            .get("barrier")
+            .map(smartstring::alias::String::as_ref);
+        if barrier.is_none() {
+            return false;
+        }
+        if let Some(granted) = access_granted(node_tags, &["wheelchair", "foot", "access"]) {
+            return !granted;
+        }
+        if barrier == Some("kerb") {
+            let kerb = node_tags
+                .get("kerb")
+                .map(smartstring::alias::String::as_ref);
+            return !matches!(kerb, Some("lowered") | Some("flush") | Some("no"));
+        }
+        matches!(
+            barrier,
+            Some("stile")
+                | Some("turnstile")
+                | Some("full-height_turnstile")
+                | Some("kissing_gate")
+                | Some("cycle_barrier")
+                | Some("fence")
+                | Some("wall")
+                | Some("hedge")
+        )
+    }
 }
 
 #[test]
-fn test_index() {
-    let g = Grid {
-        lng_min: 5.0,
-        lng_max: 20.0,
-        lat_min: 7.0,
-        lat_max: 30.0,
-        side_length: 20,
+fn bicycle_surface_metrics() {
+    type Row<'a> = (&'a [(&'a str, &'a str)], f64, f64, f64);
+    // tags, roughness, speed, unsuitability
+    let table: &[Row] = &[
+        (&[("highway", "residential")], 1.0, 18.0, 2.0),
+        (
+            &[("highway", "residential"), ("surface", "asphalt")],
+            1.0,
+            18.0,
+            2.0,
+        ),
+        (
+            &[("highway", "residential"), ("surface", "sett")],
+            1.5,
+            12.0,
+            3.0,
+        ),
+        (
+            &[("highway", "residential"), ("surface", "cobblestone")],
+            2.0,
+            9.0,
+            4.0,
+        ),
+        (
+            &[
+                ("highway", "residential"),
+                ("surface", "cobblestone"),
+                ("smoothness", "intermediate"),
+            ],
+            1.2,
+            15.0,
+            2.4,
+        ),
+        (
+            &[("highway", "cycleway"), ("surface", "asphalt")],
+            1.0,
+            18.0,
+            0.5,
+        ),
+        (
+            &[("highway", "cycleway"), ("surface", "fine_gravel")],
+            1.3,
+            18.0 / 1.3,
+            0.65,
+        ),
+        (&[("highway", "track")], 1.8, 16.0 / 1.8, 1.8),
+        (
+            &[("highway", "track"), ("tracktype", "grade1")],
+            1.0,
+            16.0,
+            1.0,
+        ),
+        (
+            &[
+                ("highway", "track"),
+                ("tracktype", "grade4"),
+                ("surface", "grass"),
+            ],
+            2.5,
+            6.4,
+            2.5,
+        ),
+        (
+            &[
+                ("highway", "path"),
+                ("surface", "ground"),
+                ("mtb:scale", "2"),
+            ],
+            3.0,
+            16.0 / 3.0,
+            3.0,
+        ),
+        (
+            &[
+                ("highway", "path"),
+                ("surface", "asphalt"),
+                ("mtb:scale", "0"),
+            ],
+            1.0,
+            16.0,
+            1.0,
+        ),
+        (
+            &[("highway", "footway"), ("bicycle", "dismount")],
+            1.0,
+            5.0,
+            0.5,
+        ),
+        (
+            &[("highway", "path"), ("smoothness", "impassable")],
+            5.0,
+            5.0,
+            5.0,
+        ),
+        (
+            &[
+                ("highway", "primary"),
+                ("surface", "asphalt"),
+                ("cycleway", "lane"),
+            ],
+            1.0,
+            18.0,
+            0.5,
+        ),
+    ];
+    for (pairs, roughness, speed, unsuitability) in table {
+        let t = tags(pairs);
+        let approx = |expected: f64, actual: f64| {
+            assert!(
+                (expected - actual).abs() < 1e-9,
+                "{:?}: expected {}, got {}",
+                pairs,
+                expected,
+                actual
+            )
+        };
+        approx(
+            *roughness,
+            TagMetric::<f64>::calc(&SurfaceRoughness, &t).unwrap(),
+        );
+        approx(*speed, TagMetric::<f64>::calc(&BicycleSpeed, &t).unwrap());
+        approx(
+            *unsuitability,
+            TagMetric::<f64>::calc(&BicycleUnsuitability::default(), &t).unwrap(),
+        );
+    }
+}
+
+#[test]
+fn bicycle_travel_time() {
+    let rider = Rider::default();
+    let kmh = |grade, crr| rider.speed(grade, crr).0 * 3.6;
+    let flat = kmh(0.0, 0.004);
+    assert!(20.0 < flat && flat < 24.0);
+    assert!(kmh(0.05, 0.004) < flat / 2.0);
+    assert!(kmh(0.02, 0.004) > kmh(0.05, 0.004));
+    assert!(kmh(-0.02, 0.004) > flat);
+    assert!((45.0 - kmh(-0.1, 0.004)).abs() < 1e-9);
+    assert!((4.0 - kmh(0.3, 0.004)).abs() < 1e-9);
+    assert!(kmh(0.0, 0.016) < flat);
+    let stronger = Rider {
+        power: Watts(200.0),
+        ..rider
+    };
+    assert!(stronger.speed(0.05, 0.004).0 * 3.6 > kmh(0.05, 0.004));
+
+    let time = BicycleTravelTime::new(Arc::new(Distance), rider);
+    let mut map = MetricIndices::new();
+    map.insert(Distance.name(), 0);
+    map.insert(HeightAscent.name(), 1);
+    map.insert(HeightDescent.name(), 2);
+    let seconds =
+        |costs: &[f64], map: &MetricIndices| CostMetric::<f64>::calc(&time, costs, map).unwrap();
+    let flat_time = seconds(&[1000.0, 0.0, 0.0], &map);
+    assert!((1000.0 / (flat / 3.6) - flat_time).abs() < 1e-6);
+    assert!(seconds(&[1000.0, 30.0, 0.0], &map) > 2.0 * flat_time);
+    assert!(seconds(&[1000.0, 0.0, 30.0], &map) < flat_time);
+    assert_eq!(0.0, seconds(&[0.0, 0.0, 0.0], &map));
+
+    map.insert(SurfaceRoughness.name(), 3);
+    assert_eq!(flat_time, seconds(&[1000.0, 0.0, 0.0, 1.0], &map));
+    assert!(seconds(&[1000.0, 0.0, 0.0, 2.0], &map) > flat_time);
+
+    map.remove(&HeightDescent.name());
+    assert!(CostMetric::<f64>::calc(&time, &[1000.0, 0.0, 0.0, 1.0], &map).is_err());
+
+    let parsed: Rider = "150, 75,0.005".parse().unwrap();
+    assert_eq!(
+        Rider {
+            power: Watts(150.0),
+            mass: Kilograms(75.0),
+            rolling_resistance: 0.005,
+            ..rider
+        },
+        parsed
+    );
+    assert!("150,75".parse::<Rider>().is_err());
+    assert!("0,75,0.005".parse::<Rider>().is_err());
+    assert!("150,75,x".parse::<Rider>().is_err());
+}
+
+#[test]
+fn energy_consumption() {
+    let mut map = MetricIndices::new();
+    map.insert(Distance.name(), 0);
+    map.insert(CarSpeed.name(), 1);
+    map.insert(HeightAscent.name(), 2);
+    map.insert(HeightDescent.name(), 3);
+    let wh = |vehicle, costs: &[f64]| {
+        let metric = EnergyConsumption::new(Arc::new(Distance), Arc::new(CarSpeed), vehicle);
+        CostMetric::<f64>::calc(&metric, costs, &map).unwrap()
+    };
+    let car = Vehicle::electric_car();
+
+    // around 15 kWh per 100 km on a flat motorway
+    let flat = wh(car, &[1000.0, 100.0, 0.0, 0.0]);
+    assert!(140.0 < flat && flat < 170.0, "{}", flat);
+    assert!(wh(car, &[1000.0, 50.0, 0.0, 0.0]) < flat);
+    let uphill = wh(car, &[1000.0, 100.0, 20.0, 0.0]);
+    assert!(uphill > flat + 100.0);
+    let downhill = wh(car, &[1000.0, 100.0, 0.0, 20.0]);
+    assert!(downhill < flat);
+    assert_eq!(0.0, wh(car, &[1000.0, 100.0, 0.0, 100.0]));
+    // recuperation gets back less than the climb costs
+    let steep = |rise, fall| wh(car, &[1000.0, 100.0, rise, fall]);
+    assert!(steep(50.0, 0.0) + steep(0.0, 50.0) > 2.0 * flat);
+
+    assert!(wh(Vehicle::electric_truck(), &[1000.0, 80.0, 0.0, 0.0]) > flat);
+    assert!(wh(Vehicle::e_bike(), &[1000.0, 25.0, 0.0, 0.0]) < 10.0);
+    assert_eq!(0.0, wh(car, &[0.0, 100.0, 0.0, 0.0]));
+
+    let heavy = car
+        .with_parameters("mass=2100, auxiliary_power=500")
+        .unwrap();
+    assert_eq!(Kilograms(2100.0), heavy.mass);
+    assert_eq!(Watts(500.0), heavy.auxiliary_power);
+    assert_eq!(car.drag_area, heavy.drag_area);
+    assert!(car.with_parameters("mass").is_err());
+    assert!(car.with_parameters("mass=-1").is_err());
+    assert!(car.with_parameters("battery_share=2").is_err());
+    assert!(car.with_parameters("wheels=4").is_err());
+
+    map.remove(&HeightAscent.name());
+    let metric = EnergyConsumption::new(Arc::new(Distance), Arc::new(CarSpeed), car);
+    assert!(CostMetric::<f64>::calc(&metric, &[1000.0, 100.0, 0.0, 0.0], &map).is_err());
+}
+
+#[test]
+fn foot_and_wheelchair_filters() {
+    let foot = FootEdgeFilter;
+    let wheelchair = WheelchairEdgeFilter;
+    let valid = |f: &dyn EdgeFilter, pairs: &[(&str, &str)]| !f.is_invalid(&tags(pairs));
+
+    assert!(valid(&foot, &[("highway", "footway")]));
+    assert!(valid(&foot, &[("highway", "steps")]));
+    assert!(valid(&foot, &[("highway", "primary")]));
+    assert!(!valid(&foot, &[("highway", "motorway")]));
+    assert!(valid(&foot, &[("highway", "trunk"), ("sidewalk", "both")]));
+    assert!(!valid(&foot, &[("highway", "path"), ("foot", "no")]));
+    assert!(!valid(
+        &foot,
+        &[("highway", "service"), ("access", "private")]
+    ));
+    assert!(valid(
+        &foot,
+        &[("highway", "service"), ("access", "no"), ("foot", "yes")]
+    ));
+    assert!(!valid(&foot, &[("building", "yes")]));
+
+    assert!(valid(&wheelchair, &[("highway", "footway")]));
+    assert!(!valid(&wheelchair, &[("highway", "steps")]));
+    assert!(valid(
+        &wheelchair,
+        &[("highway", "steps"), ("ramp:wheelchair", "yes")]
+    ));
+    assert!(!valid(
+        &wheelchair,
+        &[("highway", "path"), ("sac_scale", "mountain_hiking")]
+    ));
+    assert!(!valid(
+        &wheelchair,
+        &[("highway", "footway"), ("wheelchair", "no")]
+    ));
+    assert!(!valid(
+        &wheelchair,
+        &[("highway", "track"), ("smoothness", "horrible")]
+    ));
+
+    assert!(!foot.is_blocking(&tags(&[("barrier", "stile")])));
+    assert!(wheelchair.is_blocking(&tags(&[("barrier", "stile")])));
+    assert!(wheelchair.is_blocking(&tags(&[("barrier", "kerb"), ("kerb", "raised")])));
+    assert!(!wheelchair.is_blocking(&tags(&[("barrier", "kerb"), ("kerb", "lowered")])));
+    assert!(!wheelchair.is_blocking(&tags(&[("barrier", "gate")])));
+    assert!(!wheelchair.is_blocking(&tags(&[("barrier", "stile"), ("wheelchair", "yes")])));
+
+    let boxed: Box<dyn EdgeFilter> = Box::new(WheelchairEdgeFilter);
+    assert!(boxed.is_invalid(&tags(&[("highway", "steps")])));
+}
+
+#[test]
+fn walking_metrics() {
+    let value = |m: &dyn TagMetric<f64>, pairs: &[(&str, &str)]| m.calc(&tags(pairs)).unwrap();
+    assert_eq!(1.0, value(&HikingDifficulty, &[("highway", "path")]));
+    assert_eq!(
+        4.0,
+        value(&HikingDifficulty, &[("sac_scale", "alpine_hiking")])
+    );
+    assert_eq!(5.0, value(&WalkingSpeed, &[("highway", "footway")]));
+    assert_eq!(
+        4.0,
+        value(&WalkingSpeed, &[("sac_scale", "mountain_hiking")])
+    );
+
+    assert!((6.0 - tobler_speed(-0.05).0).abs() < 1e-9);
+    assert!((5.04 - tobler_speed(0.0).0).abs() < 0.01);
+    assert!(tobler_speed(0.2).0 < 3.0);
+
+    let time = WalkingTime::new(Arc::new(Distance), Arc::new(WalkingSpeed));
+    let mut map = MetricIndices::new();
+    map.insert(Distance.name(), 0);
+    map.insert(WalkingSpeed.name(), 1);
+    map.insert(HeightAscent.name(), 2);
+    map.insert(HeightDescent.name(), 3);
+    let seconds = |costs: &[f64]| CostMetric::<f64>::calc(&time, costs, &map).unwrap();
+    // 1 km at 5 km/h
+    assert!((720.0 - seconds(&[1000.0, 5.0, 0.0, 0.0])).abs() < 1e-6);
+    assert!(seconds(&[1000.0, 5.0, 0.0, 50.0]) < 720.0);
+    assert!(seconds(&[1000.0, 5.0, 0.0, 300.0]) > 720.0);
+    assert!(seconds(&[1000.0, 5.0, 100.0, 0.0]) > 1000.0);
+
+    let unsuitability = |pairs: &[(&str, &str)]| value(&WheelchairUnsuitability, pairs);
+    let footway = unsuitability(&[("highway", "footway"), ("surface", "asphalt")]);
+    assert_eq!(1.0, footway);
+    assert_eq!(
+        2.0,
+        unsuitability(&[("highway", "footway"), ("incline", "7%")])
+    );
+    assert_eq!(
+        8.0,
+        unsuitability(&[("highway", "footway"), ("incline", "-15%")])
+    );
+    assert_eq!(
+        4.0,
+        unsuitability(&[("highway", "footway"), ("incline", "5°")])
+    );
+    assert_eq!(
+        4.0,
+        unsuitability(&[("highway", "footway"), ("kerb", "raised")])
+    );
+    assert_eq!(
+        4.0,
+        unsuitability(&[("highway", "footway"), ("surface", "cobblestone")])
+    );
+    assert_eq!(3.0, unsuitability(&[("highway", "primary")]));
+    assert_eq!(
+        1.5,
+        unsuitability(&[("highway", "primary"), ("sidewalk", "both")])
+    );
+}
+
+#[test]
+fn ferries() {
+    let ferry = |pairs: &[(&str, &str)]| {
+        let mut t = tags(pairs);
+        t.insert("route".into(), "ferry".into());
+        t
+    };
+    let plain = ferry(&[]);
+    let cars = ferry(&[("motor_vehicle", "yes"), ("duration", "1:30")]);
+    let no_bicycles = ferry(&[("bicycle", "no")]);
+    let shuttle = tags(&[("route", "shuttle_train")]);
+
+    assert!(CarEdgeFilter.is_invalid(&plain));
+    assert!(!CarEdgeFilter.is_invalid(&cars));
+    assert!(!CarEdgeFilter.is_invalid(&shuttle));
+    assert!(!BicycleEdgeFilter.is_invalid(&plain));
+    assert!(BicycleEdgeFilter.is_invalid(&no_bicycles));
+    assert!(BicycleEdgeFilter.is_invalid(&shuttle));
+    assert!(!FootEdgeFilter.is_invalid(&plain));
+    assert!(!WheelchairEdgeFilter.is_invalid(&plain));
+    assert!(FootEdgeFilter.is_invalid(&ferry(&[("foot", "no")])));
+
+    assert_eq!(1.0, TagMetric::<f64>::calc(&FerryFlag, &plain).unwrap());
+    assert_eq!(
+        0.0,
+        TagMetric::<f64>::calc(&FerryFlag, &tags(&[("highway", "primary")])).unwrap()
+    );
+    assert_eq!(5400.0, FerryDuration.calc(&cars).unwrap());
+    assert_eq!(0.0, FerryDuration.calc(&plain).unwrap());
+    assert!(FerryDuration.is_way_total());
+    assert_eq!(20.0, TagMetric::<f64>::calc(&CarSpeed, &cars).unwrap());
+
+    assert_eq!(Some(Seconds(1800.0)), parse_duration("30"));
+    assert_eq!(Some(Seconds(4530.0)), parse_duration("01:15:30"));
+    assert_eq!(Some(Seconds(5400.0)), parse_duration("PT1H30M"));
+    assert_eq!(None, parse_duration("PT1X"));
+    assert_eq!(None, parse_duration("soon"));
+    assert_eq!(None, parse_duration("0:00"));
+
+    let time = TravelTime::new(Arc::new(Distance), Arc::new(CarSpeed));
+    let mut map = MetricIndices::new();
+    map.insert(Distance.name(), 0);
+    map.insert(CarSpeed.name(), 1);
+    let seconds =
+        |costs: &[f64], map: &MetricIndices| CostMetric::<f64>::calc(&time, costs, map).unwrap();
+    assert_eq!(36.0, seconds(&[1000.0, 100.0, 1.0, 600.0], &map));
+    map.insert(FerryFlag.name(), 2);
+    assert_eq!(36.0, seconds(&[1000.0, 100.0, 0.0, 0.0], &map));
+    assert_eq!(180.0, seconds(&[1000.0, 100.0, 1.0, 0.0], &map));
+    map.insert(FerryDuration.name(), 3);
+    assert_eq!(600.0, seconds(&[1000.0, 100.0, 1.0, 600.0], &map));
+}
+
+#[test]
+fn lanes_and_width() {
+    let both = |pairs: &[(&str, &str)]| {
+        let t = tags(pairs);
+        (
+            TagMetric::<f64>::calc(&Lanes, &t).unwrap(),
+            TagMetric::<f64>::calc(&Width, &t).unwrap(),
+        )
+    };
+    assert_eq!((3.0, 9.0), both(&[("highway", "primary"), ("lanes", "3")]));
+    assert_eq!(
+        (3.0, 9.0),
+        both(&[
+            ("highway", "primary"),
+            ("lanes:forward", "2"),
+            ("lanes:backward", "1"),
+        ])
+    );
+    assert_eq!(
+        (2.0, 5.5),
+        both(&[("highway", "residential"), ("width", "5.5 m")])
+    );
+    assert_eq!(
+        (1.0, 4.0),
+        both(&[("highway", "service"), ("est_width", "4")])
+    );
+    assert_eq!((4.0, 12.0), both(&[("highway", "trunk")]));
+    assert_eq!((2.0, 6.0), both(&[("highway", "motorway")]));
+    assert_eq!(
+        (1.0, 3.0),
+        both(&[("highway", "tertiary"), ("oneway", "yes")])
+    );
+    assert_eq!(
+        (2.0, 6.0),
+        both(&[("highway", "residential"), ("lanes", "many")])
+    );
+    assert_eq!((1.0, 2.5), both(&[("highway", "track")]));
+    assert_eq!(
+        (1.0, 1.2),
+        both(&[("highway", "path"), ("width", "120 cm")])
+    );
+}
+
+#[test]
+fn truck_filter() {
+    let truck = TruckEdgeFilter::default();
+    let valid = |f: &TruckEdgeFilter, pairs: &[(&str, &str)]| !f.is_invalid(&tags(pairs));
+
+    assert!(valid(&truck, &[("highway", "primary")]));
+    assert!(!valid(&truck, &[("highway", "footway")]));
+    assert!(!valid(&truck, &[("highway", "residential"), ("hgv", "no")]));
+    assert!(valid(
+        &truck,
+        &[("highway", "residential"), ("hgv", "destination")]
+    ));
+    assert!(!valid(
+        &truck,
+        &[("highway", "primary"), ("maxheight", "3.8")]
+    ));
+    assert!(valid(
+        &truck,
+        &[("highway", "primary"), ("maxheight", "14'")]
+    ));
+    assert!(valid(
+        &truck,
+        &[("highway", "primary"), ("maxheight", "default")]
+    ));
+    assert!(!valid(
+        &truck,
+        &[("highway", "primary"), ("maxwidth", "2.3")]
+    ));
+    assert!(!valid(
+        &truck,
+        &[("highway", "tertiary"), ("maxweight", "7.5")]
+    ));
+    assert!(!valid(
+        &truck,
+        &[("route", "ferry"), ("motor_vehicle", "yes"), ("hgv", "no")]
+    ));
+    assert!(valid(
+        &truck,
+        &[("route", "ferry"), ("motor_vehicle", "yes")]
+    ));
+    assert!(truck.is_blocking(&tags(&[
+        ("barrier", "height_restrictor"),
+        ("maxheight", "3.5")
+    ])));
+    assert!(!truck.is_blocking(&tags(&[("barrier", "gate")])));
+
+    let van: TruckEdgeFilter = "2.8,2.0,3.5".parse().unwrap();
+    assert_eq!(Kilograms(3500.0), van.weight);
+    assert!(valid(
+        &van,
+        &[
+            ("highway", "primary"),
+            ("maxheight", "3.8"),
+            ("maxweight", "7.5")
+        ]
+    ));
+    assert!("2.8,2.0".parse::<TruckEdgeFilter>().is_err());
+    assert!("2.8,-2,3".parse::<TruckEdgeFilter>().is_err());
+}
+
+#[test]
+fn flag_metrics() {
+    let flags = |pairs: &[(&str, &str)]| {
+        let t = tags(pairs);
+        ["toll", "motorway", "unpaved", "tunnel", "bridge"]
+            .iter()
+            .filter(|f| parse_flag(f).unwrap().calc(&t).unwrap() == 1.0)
+            .copied()
+            .collect::<Vec<_>>()
     };
+    let none: Vec<&str> = vec![];
+    assert_eq!(
+        none,
+        flags(&[("highway", "primary"), ("surface", "asphalt")])
+    );
+    assert_eq!(
+        vec!["toll", "motorway"],
+        flags(&[("highway", "motorway"), ("toll", "yes")])
+    );
+    assert_eq!(
+        none,
+        flags(&[("highway", "primary"), ("toll", "no"), ("toll:hgv", "yes")])
+    );
+    assert_eq!(
+        vec!["motorway", "tunnel"],
+        flags(&[("highway", "motorway_link"), ("tunnel", "yes")])
+    );
+    assert_eq!(
+        none,
+        flags(&[("highway", "service"), ("tunnel", "building_passage")])
+    );
+    assert_eq!(
+        vec!["bridge"],
+        flags(&[("highway", "residential"), ("bridge", "viaduct")])
+    );
+    assert_eq!(none, flags(&[("highway", "residential"), ("bridge", "no")]));
+    assert_eq!(vec!["unpaved"], flags(&[("highway", "track")]));
+    assert_eq!(
+        none,
+        flags(&[("highway", "track"), ("tracktype", "grade1")])
+    );
+    assert_eq!(
+        vec!["unpaved"],
+        flags(&[("highway", "unclassified"), ("surface", "gravel")])
+    );
+    assert_eq!(
+        none,
+        flags(&[("highway", "track"), ("surface", "concrete")])
+    );
+    assert_eq!("FerryFlag", parse_flag("ferry").unwrap().name());
+    assert!(parse_flag("ford").is_err());
+
+    let toll: Arc<dyn TagMetric<f64>> = Arc::new(Toll);
+    let metric = FlagMetric::new(Arc::new(Distance), toll);
+    assert_eq!("FlagMetric: Distance / Toll", metric.name());
+    let mut map = MetricIndices::new();
+    map.insert(Distance.name(), 0);
+    map.insert(Toll.name(), 1);
+    assert_eq!(120.0, metric.calc(&[120.0, 1.0], &map).unwrap());
+    assert_eq!(0.0, metric.calc(&[120.0, 0.0], &map).unwrap());
+}
+
+#[test]
+fn turn_angles() {
+    let via = Node::new(2, 50.0, 10.0, 0.0);
+    let south = Node::new(1, 49.99, 10.0, 0.0);
+    let north = Node::new(3, 50.01, 10.0, 0.0);
+    let east = Node::new(4, 50.0, 10.01, 0.0);
+
+    assert!(turn_angle(&south, &via, &north) < 0.1);
+    assert!((turn_angle(&south, &via, &east) - 90.0).abs() < 0.1);
+    assert!((turn_angle(&south, &via, &south) - 180.0).abs() < 0.1);
+
+    let cost = TurnCost::default();
+    let straight: f64 = TurnMetric::calc(&cost, &south, &via, &north).unwrap();
+    let right: f64 = TurnMetric::calc(&cost, &south, &via, &east).unwrap();
+    let u_turn: f64 = TurnMetric::calc(&cost, &south, &via, &south).unwrap();
+    assert!(straight < right && right < u_turn);
+    assert_eq!(35.0, u_turn);
+}
+
+#[cfg(test)]
+fn tags(pairs: &[(&str, &str)]) -> Tags {
+    pairs
+        .iter()
+        .map(|(k, v)| ((*k).into(), (*v).into()))
+        .collect()
+}
+
+#[test]
+fn node_tag_delays() {
+    let delay = |t: &dyn NodeTagMetric<f64>, pairs| t.calc(&tags(pairs)).unwrap();
+
+    assert_eq!(
+        20.0,
+        delay(&TrafficSignalDelay, &[("highway", "traffic_signals")])
+    );
+    assert_eq!(5.0, delay(&TrafficSignalDelay, &[("highway", "stop")]));
+    assert_eq!(2.0, delay(&TrafficSignalDelay, &[("highway", "give_way")]));
+    assert_eq!(0.0, delay(&TrafficSignalDelay, &[("barrier", "gate")]));
+    assert_eq!(30.0, delay(&BarrierDelay, &[("barrier", "gate")]));
+    assert_eq!(15.0, delay(&BarrierDelay, &[("barrier", "lift_gate")]));
+    assert_eq!(0.0, delay(&BarrierDelay, &[("barrier", "bollard")]));
+}
+
+#[test]
+fn blocking_barriers() {
+    let bollard = tags(&[("barrier", "bollard")]);
+    assert!(CarEdgeFilter.is_blocking(&bollard));
+    assert!(!BicycleEdgeFilter.is_blocking(&bollard));
+
+    let open_bollard = tags(&[("barrier", "bollard"), ("motor_vehicle", "yes")]);
+    assert!(!CarEdgeFilter.is_blocking(&open_bollard));
+
+    let gate = tags(&[("barrier", "gate")]);
+    assert!(!CarEdgeFilter.is_blocking(&gate));
+    assert!(!BicycleEdgeFilter.is_blocking(&gate));
+
+    let private_gate = tags(&[("barrier", "lift_gate"), ("access", "private")]);
+    assert!(CarEdgeFilter.is_blocking(&private_gate));
+    assert!(BicycleEdgeFilter.is_blocking(&private_gate));
+
+    let bike_gate = tags(&[("barrier", "gate"), ("access", "no"), ("bicycle", "yes")]);
+    assert!(CarEdgeFilter.is_blocking(&bike_gate));
+    assert!(!BicycleEdgeFilter.is_blocking(&bike_gate));
+
+    let stile = tags(&[("barrier", "stile")]);
+    assert!(BicycleEdgeFilter.is_blocking(&stile));
+    assert!(!CarEdgeFilter.is_blocking(&tags(&[("highway", "traffic_signals")])));
+}
+
+#[cfg(test)]
+fn bounds(lat_min: f64, lat_max: f64, lng_min: f64, lng_max: f64) -> BoundingBox {
+    BoundingBox {
+        lat_min,
+        lat_max,
+        lng_min,
+        lng_max,
+    }
+}
+
+#[test]
+fn test_index() {
+    let g = Grid::new(bounds(7.0, 30.0, 5.0, 20.0), 20);
 
     let c = g.index(&Node::new(1, 12.7, 7.3, 0.0));
 
@@ -526,16 +2991,273 @@ fn test_index() {
 
 #[test]
 fn index_for_negative_coords() {
-    let g = Grid {
-        lng_min: -10.0,
-        lng_max: 10.0,
-        lat_min: -20.0,
-        lat_max: 20.0,
-        side_length: 20,
-    };
+    let g = Grid::new(bounds(-20.0, 20.0, -10.0, 10.0), 20);
 
     let c = g.index(&Node::new(1, 5.2, -3.3, 0.0));
 
     assert_eq!(6, c.x);
     assert_eq!(12, c.y);
 }
+
+#[test]
+fn index_on_and_outside_of_bounds() {
+    let g = Grid::new(bounds(7.0, 30.0, 5.0, 20.0), 20);
+    let index = |lat, lng| {
+        let c = g.index(&Node::new(1, lat, lng, 0.0));
+        (c.x, c.y)
+    };
+
+    assert_eq!((0, 0), index(7.0, 5.0));
+    assert_eq!((0, 19), index(30.0, 5.0));
+    assert_eq!((19, 0), index(7.0, 20.0));
+    assert_eq!((19, 19), index(30.0, 20.0));
+    // border between two cells belongs to the northern and eastern one
+    assert_eq!((10, 10), index(18.5, 12.5));
+    assert_eq!((0, 0), index(-90.0, -180.0));
+    assert_eq!((19, 19), index(90.0, 180.0));
+}
+
+#[test]
+fn index_for_degenerate_bounds() {
+    let nodes = vec![Node::new(1, 48.0, 9.0, 0.0), Node::new(2, 48.0, 9.5, 0.0)];
+    let b = BoundingBox::of(&nodes);
+    assert_eq!(bounds(48.0, 48.0, 9.0, 9.5), b);
+    let g = Grid::new(b, 10);
+
+    for (lng, x) in &[(9.0, 0), (9.26, 5), (9.5, 9)] {
+        let c = g.index(&Node::new(1, 48.0, *lng, 0.0));
+        assert_eq!((*x, 0), (c.x, c.y));
+    }
+    assert_eq!((0.0, 0.0), g.position(48.0, 9.0));
+
+    let single = Grid::new(BoundingBox::of(&nodes[..1]), 10);
+    let c = single.index(&nodes[0]);
+    assert_eq!((0, 0), (c.x, c.y));
+
+    let empty = BoundingBox::of(&[]);
+    assert_eq!(BoundingBox::default(), empty);
+    let c = Grid::new(empty, 10).index(&nodes[1]);
+    assert_eq!((0, 0), (c.x, c.y));
+}
+
+#[test]
+fn random_weights_are_reproducible() {
+    let nodes: Vec<Node> = (0..100)
+        .map(|i| Node::new(i, 50.0 + i as f64 * 0.001, 10.0, 0.0))
+        .collect();
+    let distributions = [
+        "uniform:2,5",
+        "normal:10,2",
+        "exponential:3",
+        "distance:1,1.5",
+    ];
+    for d in &distributions {
+        let distribution: RandomDistribution = d.parse().unwrap();
+        let weights = |seed| {
+            let metric = RandomWeights::new(seed, distribution);
+            nodes
+                .windows(2)
+                .map(|n| NodeMetric::<f64>::calc(&metric, &n[0], &n[1]).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let first = weights(1);
+        assert_eq!(first, weights(1));
+        assert_ne!(first, weights(2));
+        assert!(first.iter().all(|w| *w >= 0.0));
+
+        let metric = RandomWeights::new(1, distribution);
+        let backwards = NodeMetric::<f64>::calc(&metric, &nodes[1], &nodes[0]).unwrap();
+        assert_eq!(first[0], backwards);
+    }
+
+    let uniform = RandomWeights::new(7, "uniform:2,5".parse().unwrap());
+    let correlated = RandomWeights::new(7, "distance:1,1.5".parse().unwrap());
+    for n in nodes.windows(2) {
+        let w = NodeMetric::<f64>::calc(&uniform, &n[0], &n[1]).unwrap();
+        assert!((2.0..5.0).contains(&w));
+        let distance = NodeMetric::<f64>::calc(&Distance, &n[0], &n[1]).unwrap();
+        let w = NodeMetric::<f64>::calc(&correlated, &n[0], &n[1]).unwrap();
+        assert!(distance <= w && w <= 1.5 * distance);
+    }
+}
+
+#[test]
+fn random_weights_follow_their_distribution() {
+    let nodes: Vec<Node> = (0..10_001).map(|i| Node::new(i, 50.0, 10.0, 0.0)).collect();
+    let stats = |d: &str| {
+        let metric = RandomWeights::new(3, d.parse().unwrap());
+        let weights: Vec<f64> = nodes
+            .windows(2)
+            .map(|n| NodeMetric::<f64>::calc(&metric, &n[0], &n[1]).unwrap())
+            .collect();
+        let mean = weights.iter().sum::<f64>() / weights.len() as f64;
+        let variance =
+            weights.iter().map(|w| (w - mean).powi(2)).sum::<f64>() / weights.len() as f64;
+        (mean, variance.sqrt(), weights)
+    };
+
+    let (mean, std_dev, _) = stats("normal:10,2");
+    assert!((mean - 10.0).abs() < 0.1);
+    assert!((std_dev - 2.0).abs() < 0.1);
+    let (mean, _, _) = stats("exponential:3");
+    assert!((mean - 3.0).abs() < 0.15);
+    let (mean, _, _) = stats("uniform:2,4");
+    assert!((mean - 3.0).abs() < 0.05);
+
+    // half of the normal distribution is drawn again, none is raised to 0
+    let (mean, _, weights) = stats("normal:0,1");
+    assert!(weights.iter().all(|w| *w > 0.0));
+    assert!((mean - (2.0 / std::f64::consts::PI).sqrt()).abs() < 0.05);
+
+    // the weights of a seed must not change between versions
+    let metric = RandomWeights::new(0, "uniform:0,1".parse().unwrap());
+    let weight = NodeMetric::<f64>::calc(&metric, &nodes[0], &nodes[1]).unwrap();
+    assert_eq!(0.749_748_241_358_030_1, weight);
+}
+
+#[test]
+fn parse_random_distributions() {
+    assert_eq!(
+        Ok(RandomDistribution::Normal {
+            mean: 10.0,
+            std_dev: 2.5
+        }),
+        "normal:10,2.5".parse()
+    );
+    assert_eq!(
+        Ok(RandomDistribution::Exponential { mean: 3.0 }),
+        "exponential:3".parse()
+    );
+    assert!("uniform:5,2".parse::<RandomDistribution>().is_err());
+    assert!("uniform:-1,2".parse::<RandomDistribution>().is_err());
+    assert!("normal:-1,2".parse::<RandomDistribution>().is_err());
+    assert!("exponential:0".parse::<RandomDistribution>().is_err());
+    assert!("normal:1".parse::<RandomDistribution>().is_err());
+    assert!("poisson:1".parse::<RandomDistribution>().is_err());
+}
+
+#[cfg(test)]
+fn test_grid() -> Grid {
+    // ten cells of one degree along both axes
+    Grid::new(bounds(0.0, 10.0, 0.0, 10.0), 10)
+}
+
+#[test]
+fn grid_fields() {
+    let grid = test_grid();
+    let at = |x: f64, y: f64| Node::new(1, y, x, 0.0);
+    let value = |m: &dyn GridMetric, x, y| m.calc(&grid, &at(x, y), &at(x, y)).unwrap();
+
+    let stripes = GridX(CellWeights {
+        even: 3.0,
+        odd: 7.0,
+    });
+    assert_eq!(3.0, value(&stripes, 0.5, 4.5));
+    assert_eq!(7.0, value(&stripes, 1.5, 4.5));
+
+    let gradient = GradientField {
+        angle: 0.0,
+        from: 0.0,
+        to: 10.0,
+    };
+    let west_to_east: Vec<f64> = (0..10)
+        .map(|x| value(&gradient, x as f64 + 0.5, 2.5))
+        .collect();
+    assert!(west_to_east.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(west_to_east[3], value(&gradient, 3.5, 8.5));
+    assert!((0.5 - west_to_east[0]).abs() < 1e-9);
+    assert!((9.5 - west_to_east[9]).abs() < 1e-9);
+    let north = GradientField {
+        angle: 90.0,
+        from: 0.0,
+        to: 10.0,
+    };
+    assert!(value(&north, 2.5, 0.5) < value(&north, 2.5, 9.5));
+
+    let radial = RadialField {
+        center: 1.0,
+        corner: 5.0,
+    };
+    let center = value(&radial, 4.5, 4.5);
+    assert!(center < 1.5);
+    assert!(center < value(&radial, 2.5, 4.5));
+    assert!(value(&radial, 2.5, 4.5) < value(&radial, 0.5, 0.5));
+    assert_eq!(value(&radial, 0.5, 0.5), value(&radial, 9.5, 9.5));
+
+    let spot = Hotspot {
+        lat: 2.5,
+        lng: 2.5,
+        radius: 2.0,
+        weight: 10.0,
+    };
+    let hotspots = Hotspots {
+        base: 1.0,
+        spots: vec![spot],
+    };
+    assert_eq!(11.0, value(&hotspots, 2.5, 2.5));
+    assert_eq!(6.0, value(&hotspots, 3.5, 2.5));
+    assert_eq!(1.0, value(&hotspots, 4.5, 2.5));
+    assert_eq!(1.0, value(&hotspots, 9.5, 9.5));
+}
+
+#[test]
+fn noise_field() {
+    let grid = test_grid();
+    let at = |x: f64, y: f64| Node::new(1, y, x, 0.0);
+    let noise = NoiseField::new(5, 3, 10.0, 20.0);
+    let value = |m: &NoiseField, x, y| m.calc(&grid, &at(x, y), &at(x, y)).unwrap();
+
+    let mut values = Vec::new();
+    for i in 0..100 {
+        let (x, y) = (i as f64 * 0.1, 5.0 - i as f64 * 0.03);
+        let v = value(&noise, x, y);
+        assert!((10.0..=20.0).contains(&v));
+        // smooth between close positions
+        assert!((v - value(&noise, x + 0.001, y)).abs() < 0.1);
+        values.push(v);
+    }
+    assert!(values.iter().any(|v| (v - values[0]).abs() > 0.5));
+
+    let same_seed = NoiseField::new(5, 3, 10.0, 20.0);
+    let other_seed = NoiseField::new(6, 3, 10.0, 20.0);
+    assert_eq!(value(&noise, 3.3, 7.1), value(&same_seed, 3.3, 7.1));
+    assert_ne!(value(&noise, 3.3, 7.1), value(&other_seed, 3.3, 7.1));
+}
+
+#[test]
+fn parse_grid_metrics() {
+    let grid = test_grid();
+    let node = Node::new(1, 0.5, 0.5, 0.0);
+    let parse = parse_grid_metric;
+    let value_of = |m: &dyn GridMetric| m.calc(&grid, &node, &node).unwrap();
+
+    let chess = parse("chess").unwrap();
+    assert_eq!("ChessBoard", chess.name());
+    assert_eq!(20.0, chess.calc(&grid, &node, &node).unwrap());
+    assert_eq!(
+        4.0,
+        parse("y:4,2").unwrap().calc(&grid, &node, &node).unwrap()
+    );
+    assert_eq!("NoiseField", parse("noise:1,4,0,1").unwrap().name());
+    assert_eq!("GradientField", parse("gradient:45,1,2").unwrap().name());
+    assert_eq!("RadialField", parse("radial:1,2").unwrap().name());
+    let hotspots = parse("hotspots:2;0.5,0.5,1,3;8,8,2,1").unwrap();
+    assert_eq!(5.0, hotspots.calc(&grid, &node, &node).unwrap());
+
+    assert!(parse("x:1").is_err());
+    assert!(parse("radial:1").is_err());
+    assert!(parse("noise:1,0,0,1").is_err());
+    assert!(parse("hotspots:1;1,2,0,4").is_err());
+    assert!(parse("circle").is_err());
+
+    let hills = parse("hills=noise:1,4,0,1").unwrap();
+    assert_eq!("hills", hills.name());
+    let valleys = parse("valleys=noise:2,4,0,1").unwrap();
+    assert_eq!(
+        value_of(&*valleys),
+        value_of(&*parse("noise:2,4,0,1").unwrap())
+    );
+    assert_ne!(value_of(&*hills), value_of(&*valleys));
+    assert!(parse("=chess").is_err());
+    assert!(parse("hills=circle").is_err());
+}
@@ -0,0 +1,157 @@
+/*
+Pbfextractor creates graph files for the cycle-routing projects from pbf and srtm data
+Copyright (C) 2018  Florian Barth
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use super::pbf::OsmNodeId;
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::spawn;
+
+/// Number of ids sent to the collecting thread at once.
+const BATCH_SIZE: usize = 1 << 16;
+
+/// A set of osm node ids stored as sorted vector, 8 bytes per id instead of
+/// the 16 bytes plus overhead of a `HashSet`.
+pub struct NodeIdSet {
+    ids: Vec<OsmNodeId>,
+    taken: Vec<u64>,
+}
+
+impl NodeIdSet {
+    pub fn from_unsorted(mut ids: Vec<OsmNodeId>) -> NodeIdSet {
+        ids.sort_unstable();
+        ids.dedup();
+        ids.shrink_to_fit();
+        let taken = vec![0; ids.len().div_ceil(64)];
+        NodeIdSet { ids, taken }
+    }
+
+    /// Collects the ids sent in batches on another thread. The returned
+    /// receiver gets the set as soon as all senders are dropped.
+    pub fn collect(batches: Receiver<Vec<OsmNodeId>>) -> Receiver<NodeIdSet> {
+        let (send, recv) = channel();
+
+        spawn(move || {
+            let mut ids = Vec::new();
+            let mut compacted = BATCH_SIZE;
+            for batch in batches {
+                ids.extend(batch);
+                // most nodes are referenced by more than one way, removing
+                // the duplicates from time to time keeps the buffer small
+                if ids.len() >= 2 * compacted {
+                    ids.sort_unstable();
+                    ids.dedup();
+                    compacted = ids.len().max(BATCH_SIZE);
+                }
+            }
+            send.send(NodeIdSet::from_unsorted(ids))
+                .expect("Cannot send node ids back to main thread");
+        });
+        recv
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn contains(&self, id: OsmNodeId) -> bool {
+        self.ids.binary_search(&id).is_ok()
+    }
+
+    /// Returns true the first time it is called with an id of the set.
+    pub fn take(&mut self, id: OsmNodeId) -> bool {
+        match self.ids.binary_search(&id) {
+            Ok(i) => {
+                let (word, bit) = (i / 64, 1 << (i % 64));
+                let first = self.taken[word] & bit == 0;
+                self.taken[word] |= bit;
+                first
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Buffers ids and sends them in batches to `NodeIdSet::collect`, since one
+/// message per id costs more than collecting them.
+pub struct NodeIdBatcher {
+    sender: Sender<Vec<OsmNodeId>>,
+    batch: Vec<OsmNodeId>,
+}
+
+impl NodeIdBatcher {
+    pub fn new(sender: Sender<Vec<OsmNodeId>>) -> NodeIdBatcher {
+        NodeIdBatcher {
+            sender,
+            batch: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    pub fn push(&mut self, id: OsmNodeId) {
+        self.batch.push(id);
+        if self.batch.len() == BATCH_SIZE {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(BATCH_SIZE));
+        self.sender
+            .send(batch)
+            .expect("could not send ids to id set");
+    }
+}
+
+impl Drop for NodeIdBatcher {
+    fn drop(&mut self) {
+        if !self.batch.is_empty() {
+            self.flush();
+        }
+    }
+}
+
+#[test]
+fn node_id_set() {
+    let mut set = NodeIdSet::from_unsorted(vec![7, 3, 100, 3, 64, 7]);
+    assert_eq!(4, set.len());
+    assert!(set.contains(3));
+    assert!(set.contains(100));
+    assert!(!set.contains(4));
+
+    assert!(set.take(64));
+    assert!(!set.take(64));
+    assert!(!set.take(5));
+    assert!(set.contains(64));
+}
+
+#[test]
+fn collect_batches() {
+    let (sender, receiver) = channel();
+    let set = NodeIdSet::collect(receiver);
+    let mut batcher = NodeIdBatcher::new(sender.clone());
+    // more than two batches with every id sent twice
+    for id in (0..BATCH_SIZE * 3 / 2).chain(0..BATCH_SIZE * 3 / 2) {
+        batcher.push(id);
+    }
+    sender.send(vec![BATCH_SIZE * 10]).unwrap();
+    drop(sender);
+    drop(batcher);
+
+    let set = set.recv().unwrap();
+    assert_eq!(BATCH_SIZE * 3 / 2 + 1, set.len());
+    assert!(set.contains(BATCH_SIZE * 10));
+    assert!(!set.contains(BATCH_SIZE * 2));
+}
@@ -15,46 +15,67 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
+use log::{debug, error, info, warn};
 use osmpbfreader::{OsmObj, OsmPbfReader, Way};
+use rayon::prelude::*;
 
+use super::edge_store::{Edge, EdgeStore, Precision};
+use super::external::{EdgeFile, EdgeRuns, WayNodeFile};
+use super::maxspeed::{directional_tags, Direction};
 use super::metrics::*;
-use std::cell::RefCell;
+use super::node_ids::{NodeIdBatcher, NodeIdSet};
+use super::region::Clip;
+use super::report::{Phase, Report};
+use super::restrictions::*;
+use super::routes::{CycleRoute, CycleRoutes, RouteMemberships};
+use std::borrow::Borrow;
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
-use std::rc::Rc;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread::spawn;
+use std::io;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
 
-pub type TagMetrics = Vec<Rc<dyn TagMetric<f64>>>;
-pub type NodeMetrics = Vec<Rc<dyn NodeMetric<f64>>>;
-pub type CostMetrics = Vec<Rc<dyn CostMetric<f64>>>;
+pub type TagMetrics = Vec<Arc<dyn TagMetric<f64>>>;
+pub type NodeMetrics = Vec<Arc<dyn NodeMetric<f64>>>;
+pub type NodeTagMetrics = Vec<Arc<dyn NodeTagMetric<f64>>>;
+pub type CostMetrics = Vec<Arc<dyn CostMetric<f64>>>;
+pub type GridMetrics = Vec<Arc<dyn GridMetric>>;
+pub type WayMetrics = Vec<Arc<dyn WayMetric>>;
 pub type InternalMetrics = HashSet<String>;
 pub type MetricIndices = BTreeMap<String, usize>;
 
 pub struct Loader<'a, Filter: EdgeFilter> {
-    pbf_path: &'a str,
+    pbf_paths: Vec<&'a str>,
     srtm_path: &'a str,
     edge_filter: Filter,
     tag_metrics: TagMetrics,
     node_metrics: NodeMetrics,
+    node_tag_metrics: NodeTagMetrics,
     cost_metrics: CostMetrics,
     pub internal_metrics: InternalMetrics,
     pub metrics_indices: MetricIndices,
-    grid: Rc<RefCell<Grid>>,
+    grid_metrics: GridMetrics,
+    grid_side_length: u32,
+    way_metrics: WayMetrics,
+    clip: Option<Clip>,
+    updatable: bool,
+    memory_limit: Option<usize>,
+    precision: Precision,
+    report: Report,
 }
 
 #[allow(clippy::too_many_arguments)]
 impl<'a, Filter: EdgeFilter> Loader<'a, Filter> {
     pub fn new(
-        pbf_path: &'a str,
+        pbf_paths: Vec<&'a str>,
         srtm_path: &'a str,
         edge_filter: Filter,
         tag_metrics: TagMetrics,
         node_metrics: NodeMetrics,
+        node_tag_metrics: NodeTagMetrics,
         cost_metrics: CostMetrics,
         internal_metrics: InternalMetrics,
-        grid: Rc<RefCell<Grid>>,
     ) -> Loader<'a, Filter> {
         let mut metrics_indices: MetricIndices = BTreeMap::new();
         let mut index = 0;
@@ -66,160 +87,762 @@ impl<'a, Filter: EdgeFilter> Loader<'a, Filter> {
             metrics_indices.insert(n.name(), index);
             index += 1;
         }
+        for n in &node_tag_metrics {
+            metrics_indices.insert(n.name(), index);
+            index += 1;
+        }
         for c in &cost_metrics {
             metrics_indices.insert(c.name(), index);
             index += 1;
         }
         Loader {
-            pbf_path,
+            pbf_paths,
             srtm_path,
             edge_filter,
             tag_metrics,
             node_metrics,
+            node_tag_metrics,
             cost_metrics,
             internal_metrics,
             metrics_indices,
-            grid,
+            grid_metrics: Vec::new(),
+            grid_side_length: 20,
+            way_metrics: Vec::new(),
+            clip: None,
+            updatable: false,
+            memory_limit: None,
+            precision: Precision::Double,
+            report: Report::default(),
+        }
+    }
+
+    /// Keeps the nodes of all ways in the extract, not only of those passing
+    /// the edge filter, so a change file can turn any way into a road.
+    pub fn set_updatable(&mut self) {
+        self.updatable = true;
+    }
+
+    /// Spills edges into temporary files once they need more than
+    /// `memory_limit` bytes. `build_spilled_graph` keeps the finished edges
+    /// in a temporary file as well.
+    pub fn set_memory_limit(&mut self, memory_limit: usize) {
+        self.memory_limit = Some(memory_limit);
+    }
+
+    /// Stores the costs as `f32` with `Precision::Single`, which halves the
+    /// memory needed for them.
+    pub fn set_precision(&mut self, precision: Precision) {
+        self.precision = precision;
+    }
+
+    /// Adds metrics on a grid with `side_length` times `side_length` cells
+    /// over the bounding box of the finished graph.
+    pub fn set_grid_metrics(&mut self, grid_metrics: GridMetrics, side_length: u32) {
+        let first = self.internal_metric_count();
+        for (index, g) in (first..).zip(&grid_metrics) {
+            self.metrics_indices.insert(g.name(), index);
+        }
+        self.grid_metrics = grid_metrics;
+        self.grid_side_length = side_length;
+    }
+
+    /// Adds metrics on the geometry of the ways. The node ids of the ways are
+    /// kept in the extract for them.
+    pub fn set_way_metrics(&mut self, way_metrics: WayMetrics) {
+        let first = self.internal_metric_count();
+        for (index, w) in (first..).zip(&way_metrics) {
+            self.metrics_indices.insert(w.name(), index);
         }
+        self.way_metrics = way_metrics;
     }
 
-    /// Loads the graph from a pbf file.
-    pub fn load_graph(&self) -> (Vec<Node>, Vec<Edge>) {
-        println!("Extracting data out of: {}", self.pbf_path);
-        let fs = File::open(&self.pbf_path).unwrap();
-        let mut reader = OsmPbfReader::new(fs);
+    /// Timing and memory of the phases run so far.
+    pub fn report(&self) -> &Report {
+        &self.report
+    }
+
+    /// An empty edge store with room for the costs of all metrics.
+    pub fn new_edge_store(&self) -> EdgeStore {
+        EdgeStore::new(self.internal_metric_count(), self.precision)
+    }
+
+    /// Restricts the extracted graph to a region.
+    pub fn set_clip(&mut self, clip: Clip) {
+        self.clip = Some(clip);
+    }
+
+    /// Reads the pbf files. Objects contained in more than one file are only
+    /// used once. All files are opened before reading any of them.
+    pub fn read_extract(&self) -> io::Result<Extract> {
+        let mut readers = self
+            .pbf_paths
+            .iter()
+            .map(|path| {
+                let f = File::open(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+                info!("Extracting data out of: {}", path);
+                Ok(OsmPbfReader::new(f))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let merge = readers.len() > 1;
+
+        // relations follow the ways in pbf files, so the routes need a pass of
+        // their own before the ways
+        let cycle_routes = if self.uses_cycle_routes() {
+            self.read_cycle_routes(&mut readers)
+        } else {
+            CycleRoutes::new()
+        };
+        let memberships = RouteMemberships::new(&cycle_routes);
 
         let (id_sender, id_receiver) = channel();
-        let set_receiver = self.collect_node_ids(id_receiver);
+        let set_receiver = NodeIdSet::collect(id_receiver);
+        let mut id_batcher = NodeIdBatcher::new(id_sender);
 
-        let mut edges: Vec<Edge> = reader
-            .par_iter()
-            .flat_map(|obj| {
-                if let Ok(OsmObj::Way(w)) = obj {
-                    self.process_way(&w, &id_sender)
-                } else {
-                    Vec::new()
+        let mut seen_ways = HashSet::new();
+        let mut seen_relations = HashSet::new();
+        let mut restrictions = BTreeMap::new();
+        let mut way_nodes = HashMap::new();
+        let mut spilled_way_nodes = self
+            .memory_limit
+            .filter(|_| !self.way_metrics.is_empty())
+            .map(|_| WayNodeFile::new().unwrap_or_else(|e| temporary_file_failed(e)));
+        let mut route_ways = HashMap::new();
+        let mut edges = self.new_edge_store();
+        let mut way_edges = self.new_edge_store();
+        let mut edge_runs = self
+            .memory_limit
+            .map(|limit| EdgeRuns::new(limit, self.internal_metric_count(), self.precision));
+        let scan = self.report.phase("scanning ways", "objects");
+        for reader in &mut readers {
+            for obj in reader.par_iter() {
+                scan.inc();
+                match obj {
+                    Ok(OsmObj::Way(w)) if !merge || seen_ways.insert(w.id) => {
+                        self.process_way(&w, &memberships, &mut way_edges);
+                        if self.updatable || !way_edges.is_empty() {
+                            for n in &w.nodes {
+                                id_batcher.push(n.0 as OsmNodeId);
+                            }
+                        }
+                        if !way_edges.is_empty() {
+                            match &mut spilled_way_nodes {
+                                Some(file) => {
+                                    let nodes: Vec<_> =
+                                        w.nodes.iter().map(|n| n.0 as OsmNodeId).collect();
+                                    file.push(w.id.0 as OsmWayId, &nodes)
+                                        .unwrap_or_else(|e| temporary_file_failed(e));
+                                }
+                                None => self.add_way_nodes(&w, &mut way_nodes),
+                            }
+                            self.add_route_way(&w, &mut route_ways);
+                        }
+                        match &mut edge_runs {
+                            Some(runs) => runs
+                                .append(&mut way_edges)
+                                .unwrap_or_else(|e| temporary_file_failed(e)),
+                            None => edges.append(&mut way_edges),
+                        }
+                    }
+                    Ok(OsmObj::Relation(r)) if !merge || seen_relations.insert(r.id) => {
+                        let r_restrictions = OsmRestriction::from_relation(&r);
+                        if !r_restrictions.is_empty() {
+                            restrictions.insert(r.id.0 as OsmRelationId, r_restrictions);
+                        }
+                    }
+                    _ => {}
                 }
-            })
-            .collect();
-        println!("Collected {} edges", edges.len());
-        reader.rewind().expect("Can't rewind pbf file!");
-        drop(id_sender);
+            }
+            reader.rewind().expect("Can't rewind pbf file!");
+        }
+        drop(scan);
+        drop(memberships);
+        drop(seen_ways);
+        drop(seen_relations);
+        match &edge_runs {
+            Some(runs) => info!(
+                "Collected {} edges in {} temporary files",
+                runs.len(),
+                runs.run_count()
+            ),
+            None => info!("Collected {} edges", edges.len()),
+        }
+        if let Some(file) = &spilled_way_nodes {
+            info!("Wrote the nodes of {} ways to a temporary file", file.len());
+        }
+        info!("Collected {} turn restrictions", restrictions.len());
+        drop(id_batcher);
 
-        let id_set = set_receiver.recv().expect("Did not get node ids");
-        let srtm = self.metrics_indices.contains_key(&HeightAscent.name());
+        let mut id_set = set_receiver.recv().expect("Did not get node ids");
 
-        let mut nodes: Vec<Node> = reader
-            .par_iter()
-            .filter_map(|obj| {
+        let mut extract = Extract {
+            nodes: Vec::new(),
+            edges,
+            node_tag_costs: HashMap::new(),
+            blocked: HashSet::new(),
+            restrictions,
+            cycle_routes,
+            way_nodes,
+            route_ways,
+            edge_runs,
+            spilled_way_nodes,
+        };
+        let matching = self.report.phase("matching nodes", "nodes");
+        for reader in &mut readers {
+            for obj in reader.par_iter() {
                 if let Ok(OsmObj::Node(n)) = obj {
-                    if id_set.contains(&n.id) {
-                        let lat = f64::from(n.decimicro_lat) / 10_000_000.0;
-                        let lng = f64::from(n.decimicro_lon) / 10_000_000.0;
-                        let height = if srtm { self.srtm(lat, lng) } else { 0.0 };
-                        Some(Node::new(n.id.0 as usize, lat, lng, height))
-                    } else {
-                        None
+                    // removing found ids skips nodes contained in several files
+                    if id_set.take(n.id.0 as OsmNodeId) {
+                        self.add_node(&n, &mut extract);
+                        matching.inc();
                     }
-                } else {
-                    None
                 }
-            })
-            .collect();
-        {
-            let mut grid = (*self.grid).borrow_mut();
-            nodes.iter().for_each(|n| grid.add(&n));
+            }
         }
+        drop(matching);
 
-        println!("Collected {} nodes", nodes.len());
+        info!("Collected {} nodes", extract.nodes.len());
+        Ok(extract)
+    }
+
+    /// Keeps the node ids of a way that added edges, if there are way metrics.
+    pub fn add_way_nodes(&self, w: &Way, way_nodes: &mut HashMap<OsmWayId, Vec<OsmNodeId>>) {
+        if !self.way_metrics.is_empty() {
+            let nodes = w.nodes.iter().map(|n| n.0 as OsmNodeId).collect();
+            way_nodes.insert(w.id.0 as OsmWayId, nodes);
+        }
+    }
 
-        println!("Calculating Metrics");
+    /// Keeps a way that added edges for updates, if a tag metric uses cycle
+    /// routes.
+    pub fn add_route_way(&self, w: &Way, route_ways: &mut HashMap<OsmWayId, Way>) {
+        if self.updatable && self.uses_cycle_routes() {
+            route_ways.insert(w.id.0 as OsmWayId, w.clone());
+        }
+    }
 
-        self.rename_node_ids_and_calculate_node_metrics(&mut nodes, &mut edges);
-        self.calculate_cost_metrics(&mut edges);
+    /// Whether any tag metric uses the cycle routes of ways.
+    pub fn uses_cycle_routes(&self) -> bool {
+        self.tag_metrics.iter().any(|t| t.uses_cycle_routes())
+    }
+
+    /// Reads the cycle route relations and rewinds the readers.
+    fn read_cycle_routes(&self, readers: &mut [OsmPbfReader<File>]) -> CycleRoutes {
+        let mut cycle_routes = CycleRoutes::new();
+        let scan = self.report.phase("scanning cycle routes", "objects");
+        for reader in readers.iter_mut() {
+            for obj in reader.par_iter() {
+                scan.inc();
+                if let Ok(OsmObj::Relation(r)) = obj {
+                    if let Some(route) = CycleRoute::from_relation(&r) {
+                        cycle_routes.insert(r.id.0 as OsmRelationId, route);
+                    }
+                }
+            }
+            reader.rewind().expect("Can't rewind pbf file!");
+        }
+        drop(scan);
+        info!("Collected {} cycle routes", cycle_routes.len());
+        cycle_routes
+    }
+
+    /// Adds the node and the costs of its tags to the extract.
+    pub fn add_node(&self, n: &osmpbfreader::Node, extract: &mut Extract) {
+        let osm_id = n.id.0 as OsmNodeId;
+        if !n.tags.is_empty() {
+            if self.edge_filter.is_blocking(&n.tags) {
+                extract.blocked.insert(osm_id);
+            }
+            let costs = self.node_tag_costs(&n.tags);
+            if costs.iter().any(|(_, c)| *c != 0.0) {
+                extract.node_tag_costs.insert(osm_id, costs);
+            }
+        }
+        let lat = f64::from(n.decimicro_lat) / 10_000_000.0;
+        let lng = f64::from(n.decimicro_lon) / 10_000_000.0;
+        extract.nodes.push(Node::new(osm_id, lat, lng, 0.0));
+    }
+
+    /// Builds the graph out of the extracted osm data. Nodes are ordered by
+    /// their osm id, edges by source and target. With a memory limit the
+    /// edges are read back from the temporary file into memory.
+    pub fn build_graph(&self, extract: Extract) -> Graph {
+        if extract.edge_runs.is_some() {
+            let SpilledGraph {
+                nodes,
+                edges: mut edge_file,
+                restrictions,
+            } = self.build_spilled_graph(extract);
+            let mut edges = EdgeStore::new(edge_file.cost_count(), edge_file.precision());
+            for mut chunk in edge_chunks(&mut edge_file) {
+                edges.append(&mut chunk);
+            }
+            return Graph {
+                nodes,
+                edges,
+                restrictions,
+            };
+        }
+        let Extract {
+            mut nodes,
+            edges,
+            node_tag_costs,
+            blocked,
+            restrictions,
+            cycle_routes: _,
+            way_nodes,
+            route_ways: _,
+            edge_runs: _,
+            spilled_way_nodes: _,
+        } = extract;
+
+        let edges = self.build_edges(&mut nodes, edges, &node_tag_costs, &blocked, way_nodes);
+        let restrictions = self.resolve_restrictions(
+            &nodes,
+            edges.topology().iter().copied(),
+            edges.len(),
+            restrictions,
+        );
+        debug_assert!(restrictions.iter().all(|r| r.is_valid(edges.topology())));
+        Graph {
+            nodes,
+            edges,
+            restrictions,
+        }
+    }
+
+    /// Does the same as `build_graph` for an extract with a memory limit.
+    /// The edges are merged from the edge runs into a temporary file, so
+    /// they can be written without keeping them in memory.
+    pub fn build_spilled_graph(&self, extract: Extract) -> SpilledGraph {
+        let Extract {
+            mut nodes,
+            edges: _,
+            node_tag_costs,
+            blocked,
+            restrictions,
+            cycle_routes: _,
+            way_nodes: _,
+            route_ways: _,
+            edge_runs,
+            spilled_way_nodes,
+        } = extract;
+        let runs = edge_runs.expect("spilled graphs need edge runs");
+
+        let mut edges = self.build_edges_external(
+            &mut nodes,
+            runs,
+            &node_tag_costs,
+            &blocked,
+            spilled_way_nodes,
+        );
+        let edge_count = edges.len();
+        let topology = edge_chunks(&mut edges).flat_map(|c| c.topology().to_vec());
+        let restrictions = self.resolve_restrictions(&nodes, topology, edge_count, restrictions);
+        SpilledGraph {
+            nodes,
+            edges,
+            restrictions,
+        }
+    }
+
+    fn build_edges(
+        &self,
+        nodes: &mut Vec<Node>,
+        mut edges: EdgeStore,
+        node_tag_costs: &HashMap<OsmNodeId, Vec<(usize, f64)>>,
+        blocked: &HashSet<OsmNodeId>,
+        way_nodes: HashMap<OsmWayId, Vec<OsmNodeId>>,
+    ) -> EdgeStore {
+        let used = NodeIdSet::from_unsorted(
+            edges
+                .topology()
+                .iter()
+                .flat_map(|e| [e.source, e.dest])
+                .collect(),
+        );
+        nodes.retain(|n| used.contains(n.osm_id));
+        nodes.sort_unstable_by_key(|n| n.osm_id);
+        if nodes.len() < used.len() {
+            let present = |id| nodes.binary_search_by_key(&id, |n| n.osm_id).is_ok();
+            edges.retain(|e| present(e.source) && present(e.dest));
+            warn!(
+                "Removed edges with {} nodes missing in the pbf files, {} edges left",
+                used.len() - nodes.len(),
+                edges.len()
+            );
+        }
+        drop(used);
+        let way_lengths = self.way_lengths(nodes, std::iter::once(&edges));
+        let bends = self.bends(nodes, way_nodes);
+
+        if let Some(clip) = &self.clip {
+            clip.apply(nodes, &mut edges);
+            info!(
+                "Clipped graph to {} nodes and {} edges",
+                nodes.len(),
+                edges.len()
+            );
+        }
+        self.add_heights(nodes);
+        let grid = self.grid(nodes);
 
-        println!("Deleting duplicate and dominated edges");
+        if !blocked.is_empty() {
+            edges.retain(|e| !blocked.contains(&e.source));
+            info!(
+                "Removed edges leaving {} blocking barriers, {} edges left",
+                blocked.len(),
+                edges.len()
+            );
+        }
 
+        let metrics = self.report.phase("calculating metrics", "edges");
+        self.rename_node_ids_and_calculate_node_metrics(
+            nodes,
+            &mut edges,
+            node_tag_costs,
+            &grid,
+            &way_lengths,
+            &bends,
+            &metrics,
+        );
+        self.calculate_cost_metrics(&mut edges);
+        drop(metrics);
+
+        let dedup = self
+            .report
+            .phase("deleting duplicate and dominated edges", "edges");
+        dedup.add(edges.len());
         self.delete_duplicate_edges(&mut edges);
-        edges = self.delete_dominated_edges(edges);
+        self.delete_dominated_edges(&mut edges);
+        edges
+    }
+
+    /// Does the same as `build_edges` with a few passes over the edge runs.
+    /// Node metrics only depend on the nodes of an edge, so duplicate and
+    /// dominated edges can be removed for each group of parallel edges.
+    fn build_edges_external(
+        &self,
+        nodes: &mut Vec<Node>,
+        mut runs: EdgeRuns,
+        node_tag_costs: &HashMap<OsmNodeId, Vec<(usize, f64)>>,
+        blocked: &HashSet<OsmNodeId>,
+        way_nodes: Option<WayNodeFile>,
+    ) -> EdgeFile {
+        nodes.sort_unstable_by_key(|n| n.osm_id);
+        let way_lengths = self.way_lengths(nodes, edge_groups(&mut runs));
+        let mut bend_runs = way_nodes.map(|way_nodes| self.bend_runs(nodes, way_nodes));
+        let mut bend_groups = bend_runs.as_mut().map(|runs| edge_groups(runs).peekable());
+        let inside = |nodes: &[Node], id| {
+            let region = &self.clip.as_ref().unwrap().region;
+            find_node(nodes, id).is_some_and(|n| region.contains(n.lat, n.long))
+        };
+        let present = |nodes: &[Node], e: &Edge| {
+            find_node(nodes, e.source).is_some() && find_node(nodes, e.dest).is_some()
+        };
 
-        println!("{} edges left", edges.len());
-        (nodes, edges)
+        // nodes only get removed, so whether a node is inside the region
+        // doesn't change in the passes below
+        let ways = match &self.clip {
+            Some(clip) => clip.complete_ways(
+                edges_of_runs(&mut runs).filter(|e| present(nodes, e)),
+                |id| inside(nodes, id),
+            ),
+            None => HashSet::new(),
+        };
+        let keeps = |nodes: &[Node], e: &Edge| {
+            present(nodes, e)
+                && self
+                    .clip
+                    .as_ref()
+                    .is_none_or(|clip| clip.keeps(e, |id| inside(nodes, id), &ways))
+        };
+
+        let collecting = self.report.phase("collecting used nodes", "edges");
+        let (sender, receiver) = channel();
+        let used = NodeIdSet::collect(receiver);
+        let mut batcher = NodeIdBatcher::new(sender);
+        for e in edges_of_runs(&mut runs).filter(|e| keeps(nodes, e)) {
+            collecting.inc();
+            batcher.push(e.source);
+            batcher.push(e.dest);
+        }
+        drop(batcher);
+        let used = used.recv().expect("Did not get node ids");
+        nodes.retain(|n| used.contains(n.osm_id));
+        drop(used);
+        drop(collecting);
+        info!("{} nodes left", nodes.len());
+        self.add_heights(nodes);
+        let grid = self.grid(nodes);
+
+        let metrics = self.report.phase(
+            "calculating metrics and deleting duplicate and dominated edges",
+            "edges",
+        );
+        let mut edges = EdgeFile::new(
+            self.memory_limit.unwrap_or(usize::MAX),
+            self.internal_metric_count(),
+            self.precision,
+        )
+        .unwrap_or_else(|e| temporary_file_failed(e));
+        for mut group in edge_groups(&mut runs) {
+            // the bends of the ways of the group, both are ordered by source
+            // and target
+            let key = (group.get(0).source, group.get(0).dest);
+            let mut bends = HashMap::new();
+            if let Some(bend_groups) = &mut bend_groups {
+                let before = |g: &EdgeStore| (g.get(0).source, g.get(0).dest) <= key;
+                while let Some(way_bends) = bend_groups.next_if(before) {
+                    for e in way_bends.iter().filter(|e| (e.source, e.dest) == key) {
+                        bends.insert((e.osm_way_id, e.source), e.cost(0));
+                        bends.insert((e.osm_way_id, e.dest), e.cost(1));
+                    }
+                }
+            }
+            group.retain(|e| keeps(nodes, &e) && !blocked.contains(&e.source));
+            self.rename_node_ids_and_calculate_node_metrics(
+                nodes,
+                &mut group,
+                node_tag_costs,
+                &grid,
+                &way_lengths,
+                &bends,
+                &metrics,
+            );
+            self.calculate_cost_metrics(&mut group);
+            self.delete_duplicate_edges(&mut group);
+            self.delete_dominated_edges(&mut group);
+            edges
+                .append(&group)
+                .unwrap_or_else(|e| temporary_file_failed(e));
+        }
+        edges
     }
-    fn internal_metric_count(&self) -> usize {
-        self.node_metrics.len() + self.cost_metrics.len() + self.tag_metrics.len()
+
+    /// Tag metrics whose values are totals of the whole way.
+    fn way_total_indices(&self) -> Vec<usize> {
+        self.tag_metrics
+            .iter()
+            .filter(|t| t.is_way_total())
+            .map(|t| self.metrics_indices[&t.name()])
+            .collect()
     }
-    pub fn metric_count(&self) -> usize {
-        self.internal_metric_count() - self.internal_metrics.len()
+
+    /// Lengths of the ways with costs of way total metrics. Segments used in
+    /// both directions count once. Edges have to refer to osm node ids and
+    /// `nodes` have to be sorted by osm id.
+    fn way_lengths<S: Borrow<EdgeStore>>(
+        &self,
+        nodes: &[Node],
+        stores: impl Iterator<Item = S>,
+    ) -> HashMap<OsmWayId, f64> {
+        let indices = self.way_total_indices();
+        let mut lengths = HashMap::new();
+        if indices.is_empty() {
+            return lengths;
+        }
+        let mut segments = HashSet::new();
+        for store in stores {
+            for e in store.borrow().iter() {
+                if indices.iter().all(|i| e.cost(*i) == 0.0) {
+                    continue;
+                }
+                let segment = (e.osm_way_id, e.source.min(e.dest), e.source.max(e.dest));
+                if !segments.insert(segment) {
+                    continue;
+                }
+                if let (Some(s), Some(d)) = (find_node(nodes, e.source), find_node(nodes, e.dest)) {
+                    let length = NodeMetric::<f64>::calc(&Distance, s, d).unwrap();
+                    *lengths.entry(e.osm_way_id).or_insert(0.0) += length;
+                }
+            }
+        }
+        lengths
     }
 
-    fn collect_node_ids(
+    /// Bends of the ways at their nodes in degrees, for the way metrics. The
+    /// ends of ways that aren't closed don't bend. `nodes` have to be sorted
+    /// by osm id.
+    fn bends(
         &self,
-        ids: Receiver<osmpbfreader::NodeId>,
-    ) -> Receiver<HashSet<osmpbfreader::NodeId>> {
-        let (send, recv) = channel();
-
-        spawn(move || {
-            let mut set = HashSet::new();
-            for id in ids {
-                set.insert(id);
+        nodes: &[Node],
+        way_nodes: HashMap<OsmWayId, Vec<OsmNodeId>>,
+    ) -> HashMap<(OsmWayId, OsmNodeId), f64> {
+        let mut bends = HashMap::new();
+        for (way, ids) in way_nodes {
+            for (id, bend) in ids.iter().zip(way_bends(nodes, &ids)) {
+                if let Some(bend) = bend {
+                    bends.insert((way, *id), bend);
+                }
             }
-            send.send(set)
-                .expect("Cannot send node ids back to main thread");
-        });
-        recv
+        }
+        bends
     }
 
-    fn calculate_cost_metrics(&self, edges: &mut [Edge]) {
-        for e in edges {
-            for c in &self.cost_metrics {
-                let index = self.metrics_indices[&c.name()];
-                let value = c.calc(&e.costs, &self.metrics_indices).unwrap();
-                e.costs[index] = value;
+    /// Does the same as `bends` for spilled ways. The bends are stored as
+    /// the costs of both directions of the edges of the ways, the bend at the
+    /// source first, so they can be merged with the edge runs.
+    fn bend_runs(&self, nodes: &[Node], way_nodes: WayNodeFile) -> EdgeRuns {
+        let memory_limit = self.memory_limit.unwrap_or(usize::MAX);
+        let mut runs = EdgeRuns::new(memory_limit, 2, Precision::Double);
+        let mut way_bends_store = EdgeStore::new(2, Precision::Double);
+        for way in way_nodes
+            .read()
+            .unwrap_or_else(|e| temporary_file_failed(e))
+        {
+            let (way, ids) = way.unwrap_or_else(|e| temporary_file_failed(e));
+            let bends: Vec<f64> = way_bends(nodes, &ids)
+                .into_iter()
+                .map(|b| b.unwrap_or(0.0))
+                .collect();
+            for i in 1..ids.len() {
+                let (source, dest) = (ids[i - 1], ids[i]);
+                let (at_source, at_dest) = (bends[i - 1], bends[i]);
+                way_bends_store.push(Edge::new(source, dest, way), &[at_source, at_dest]);
+                way_bends_store.push(Edge::new(dest, source, way), &[at_dest, at_source]);
             }
+            runs.append(&mut way_bends_store)
+                .unwrap_or_else(|e| temporary_file_failed(e));
+        }
+        runs
+    }
+
+    fn add_heights(&self, nodes: &mut [Node]) {
+        if self.metrics_indices.contains_key(&HeightAscent.name())
+            || self.metrics_indices.contains_key(&HeightDescent.name())
+        {
+            let lookups = self.report.phase("srtm lookups", "nodes");
+            nodes.par_iter_mut().for_each(|n| {
+                n.height = self.srtm(n.lat, n.long);
+                lookups.inc();
+            });
+        }
+    }
+
+    /// The grid of the grid metrics over the final nodes.
+    fn grid(&self, nodes: &[Node]) -> Grid {
+        let bounds = BoundingBox::of(nodes);
+        if !self.grid_metrics.is_empty() {
+            debug!(
+                "Grid of {} cells over lat {} to {}, lng {} to {}",
+                self.grid_side_length,
+                bounds.lat_min,
+                bounds.lat_max,
+                bounds.lng_min,
+                bounds.lng_max
+            );
         }
+        Grid::new(bounds, self.grid_side_length)
     }
 
-    fn process_way(&self, w: &Way, id_sender: &Sender<osmpbfreader::NodeId>) -> Vec<Edge> {
-        let mut edges = Vec::new();
-        if self.edge_filter.is_invalid(&w.tags) {
-            return edges;
+    /// Maps the restrictions onto the final graph and reports its size.
+    /// Restrictions whose members did not survive the edge filter are
+    /// dropped.
+    fn resolve_restrictions(
+        &self,
+        nodes: &[Node],
+        edges: impl IntoIterator<Item = Edge>,
+        edge_count: usize,
+        restrictions: BTreeMap<OsmRelationId, Vec<OsmRestriction>>,
+    ) -> Vec<TurnRestriction> {
+        info!("{} edges left", edge_count);
+        let resolving = self.report.phase("resolving restrictions", "restrictions");
+        let osm_restrictions: Vec<OsmRestriction> = restrictions.into_values().flatten().collect();
+        resolving.add(osm_restrictions.len());
+        let resolver = RestrictionResolver::new(nodes, edges, &osm_restrictions);
+        let mut restrictions = Vec::new();
+        let mut dropped = 0;
+        for r in &osm_restrictions {
+            match resolver.resolve(r) {
+                Ok(resolved) => restrictions.extend(resolved),
+                Err(_) => dropped += 1,
+            }
+        }
+        if dropped > 0 {
+            warn!(
+                "Dropped {} turn restrictions with members missing in the graph",
+                dropped
+            );
         }
+        restrictions.sort_by_key(|r| (r.from, r.to));
+        restrictions.dedup();
+        drop(resolving);
+        info!("{} turn restrictions left", restrictions.len());
+        self.report.set_count("nodes", nodes.len());
+        self.report.set_count("edges", edge_count);
+        self.report.set_count("restrictions", restrictions.len());
+        restrictions
+    }
+    fn internal_metric_count(&self) -> usize {
+        self.node_metrics.len()
+            + self.node_tag_metrics.len()
+            + self.cost_metrics.len()
+            + self.tag_metrics.len()
+            + self.grid_metrics.len()
+            + self.way_metrics.len()
+    }
+
+    fn node_tag_costs(&self, tags: &osmpbfreader::Tags) -> Vec<(usize, f64)> {
+        self.node_tag_metrics
+            .iter()
+            .map(|t| (self.metrics_indices[&t.name()], t.calc(tags).unwrap()))
+            .collect()
+    }
 
-        let tag_costs: Vec<(usize, f64)> = self
-            .tag_metrics
+    fn calculate_cost_metrics(&self, edges: &mut EdgeStore) {
+        let cost_metrics: Vec<_> = self
+            .cost_metrics
             .iter()
-            .map(|t| (self.metrics_indices[&t.name()], t.calc(&w.tags).unwrap()))
+            .map(|c| (c, self.metrics_indices[&c.name()]))
             .collect();
-        let is_one_way = self.is_one_way(&w);
-        for (index, node) in w.nodes[0..(w.nodes.len() - 1)].iter().enumerate() {
-            id_sender.send(*node).expect("could not send id to id set");
-            let mut edge = Edge::new(
-                node.0 as NodeId,
-                w.nodes[index + 1].0 as NodeId,
-                self.internal_metric_count(),
-            );
-            for (i, t) in &tag_costs {
-                edge.costs[*i] = *t;
+        let indices = &self.metrics_indices;
+        edges.par_for_each_mut(|_, costs| {
+            // later cost metrics may depend on earlier ones
+            for (c, index) in &cost_metrics {
+                let value = costs.with_f64(|all| c.calc(all, indices).unwrap());
+                costs.set(*index, value);
             }
-            edges.push(edge);
+        });
+    }
+
+    /// Adds the edges of the way to `edges`. Ways filtered out by the edge
+    /// filter don't add any edges. The cycle routes of the way are added to
+    /// the tags the tag metrics see.
+    pub fn process_way(&self, w: &Way, memberships: &RouteMemberships, edges: &mut EdgeStore) {
+        if w.nodes.len() < 2 || self.edge_filter.is_invalid(&w.tags) {
+            return;
+        }
+
+        let route_tags = memberships.route_tags(w.id.0 as OsmWayId, &w.tags);
+        let tags = route_tags.as_ref().unwrap_or(&w.tags);
+        let is_one_way = self.is_one_way(w);
+        let forward = self.tag_costs(tags, Direction::Forward);
+        let backward = if is_one_way {
+            Vec::new()
+        } else {
+            self.tag_costs(tags, Direction::Backward)
+        };
+        for (index, node) in w.nodes[0..(w.nodes.len() - 1)].iter().enumerate() {
+            let next = w.nodes[index + 1].0 as NodeId;
+            let node = node.0 as NodeId;
+            edges.push(Edge::new(node, next, w.id.0 as OsmWayId), &forward);
             if !is_one_way {
-                let mut edge = Edge::new(
-                    w.nodes[index + 1].0 as NodeId,
-                    node.0 as NodeId,
-                    self.internal_metric_count(),
-                );
-                for (i, t) in &tag_costs {
-                    edge.costs[*i] = *t;
-                }
-                edges.push(edge);
+                edges.push(Edge::new(next, node, w.id.0 as OsmWayId), &backward);
             }
         }
+    }
 
-        id_sender
-            .send(*w.nodes.last().unwrap())
-            .expect("could not send id to id set");
-        edges
+    /// Costs of the tag metrics travelling the way in `direction`, the
+    /// other costs are 0.
+    fn tag_costs(&self, tags: &osmpbfreader::Tags, direction: Direction) -> Vec<f64> {
+        let directional = directional_tags(tags, direction);
+        let tags = directional.as_ref().unwrap_or(tags);
+        let mut costs = vec![0.0; self.internal_metric_count()];
+        for t in &self.tag_metrics {
+            costs[self.metrics_indices[&t.name()]] = t.calc(tags).unwrap();
+        }
+        costs
     }
     fn is_one_way(&self, way: &Way) -> bool {
         let one_way = way.tags.get("oneway");
@@ -237,22 +860,78 @@ impl<'a, Filter: EdgeFilter> Loader<'a, Filter> {
         }
     }
 
-    fn rename_node_ids_and_calculate_node_metrics(&self, nodes: &mut [Node], edges: &mut [Edge]) {
-        use std::collections::hash_map::HashMap;
-
-        let map: HashMap<OsmNodeId, (usize, &Node)> =
-            nodes.iter().enumerate().map(|n| (n.1.osm_id, n)).collect();
-        for e in edges.iter_mut() {
-            let (source_id, source) = map[&e.source];
-            let (dest_id, dest) = map[&e.dest];
+    /// Renames the osm ids of the edges to node indices and calculates the
+    /// node, grid and way metrics. Costs of tagged nodes are added to the edges
+    /// leaving them and way totals are split by the share of the edge in the
+    /// length of its way. `nodes` have to be sorted by osm id.
+    fn rename_node_ids_and_calculate_node_metrics(
+        &self,
+        nodes: &[Node],
+        edges: &mut EdgeStore,
+        node_tag_costs: &HashMap<OsmNodeId, Vec<(usize, f64)>>,
+        grid: &Grid,
+        way_lengths: &HashMap<OsmWayId, f64>,
+        bends: &HashMap<(OsmWayId, OsmNodeId), f64>,
+        progress: &Phase,
+    ) {
+        let index = |id| {
+            nodes
+                .binary_search_by_key(&id, |n: &Node| n.osm_id)
+                .expect("edge refers to missing node")
+        };
+        let node_metrics: Vec<_> = self
+            .node_metrics
+            .iter()
+            .map(|n| (n, self.metrics_indices[&n.name()]))
+            .collect();
+        let grid_metrics: Vec<_> = self
+            .grid_metrics
+            .iter()
+            .map(|g| (g, self.metrics_indices[&g.name()]))
+            .collect();
+        let way_metrics: Vec<_> = self
+            .way_metrics
+            .iter()
+            .map(|w| (w, self.metrics_indices[&w.name()]))
+            .collect();
+        let way_totals = self.way_total_indices();
+        edges.par_for_each_mut(|e, costs| {
+            let source_id = index(e.source);
+            let dest_id = index(e.dest);
+            let (source, dest) = (&nodes[source_id], &nodes[dest_id]);
             e.source = source_id;
             e.dest = dest_id;
-            for n in &self.node_metrics {
-                let index = self.metrics_indices[&n.name()];
-                let value = n.calc(source, dest).unwrap();
-                e.costs[index] = value;
+            for (n, index) in &node_metrics {
+                costs.set(*index, n.calc(source, dest).unwrap());
             }
-        }
+            for (g, index) in &grid_metrics {
+                costs.set(*index, g.calc(grid, source, dest).unwrap());
+            }
+            if !way_metrics.is_empty() {
+                let bend = |n: &Node| bends.get(&(e.osm_way_id, n.osm_id)).copied();
+                let bends = (bend(source).unwrap_or(0.0), bend(dest).unwrap_or(0.0));
+                for (w, index) in &way_metrics {
+                    costs.set(*index, w.calc(source, dest, bends).unwrap());
+                }
+            }
+            if let Some(length) = way_lengths.get(&e.osm_way_id) {
+                let share = if *length > 0.0 {
+                    NodeMetric::<f64>::calc(&Distance, source, dest).unwrap() / length
+                } else {
+                    0.0
+                };
+                for index in &way_totals {
+                    let total = costs.get(*index);
+                    costs.set(*index, total * share);
+                }
+            }
+            if let Some(tag_costs) = node_tag_costs.get(&source.osm_id) {
+                for (index, value) in tag_costs {
+                    costs.set(*index, *value);
+                }
+            }
+            progress.inc();
+        });
     }
 
     pub fn srtm(&self, lat: Latitude, lng: Longitude) -> f64 {
@@ -276,9 +955,9 @@ impl<'a, Filter: EdgeFilter> Loader<'a, Filter> {
         let mut f = match File::open(&srtm_file) {
             Ok(f) => f,
             Err(_) => {
-                println!("could not find file: {}", file_name);
-                println!("lat: {}, lng: {}", lat, lng);
-                println!("north: {}, east: {}", north, east);
+                error!("could not find file: {}", file_name);
+                error!("lat: {}, lng: {}", lat, lng);
+                error!("north: {}, east: {}", north, east);
                 std::process::exit(1);
             }
         };
@@ -322,15 +1001,22 @@ impl<'a, Filter: EdgeFilter> Loader<'a, Filter> {
         x.trunc() as i64
     }
 
-    fn delete_duplicate_edges(&self, edges: &mut Vec<Edge>) {
-        edges.sort_by(|e1, e2| {
+    fn delete_duplicate_edges(&self, edges: &mut EdgeStore) {
+        let cost_count = edges.cost_count();
+        let mut order: Vec<EdgeId> = (0..edges.len()).collect();
+        order.par_sort_by(|i1, i2| {
+            let (e1, e2) = (edges.get(*i1), edges.get(*i2));
             let mut result = e1.source.cmp(&e2.source);
             if result == Ordering::Equal {
                 result = e1.dest.cmp(&e2.dest);
             }
             if result == Ordering::Equal {
-                for (c1, c2) in e1.costs.iter().zip(e2.costs.iter()) {
-                    result = c1.partial_cmp(c2).unwrap_or(Ordering::Equal);
+                result = e1.osm_way_id.cmp(&e2.osm_way_id);
+            }
+            if result == Ordering::Equal {
+                for c in 0..cost_count {
+                    let (c1, c2) = (edges.cost(*i1, c), edges.cost(*i2, c));
+                    result = c1.partial_cmp(&c2).unwrap_or(Ordering::Equal);
                     if result != Ordering::Equal {
                         break;
                     }
@@ -338,37 +1024,105 @@ impl<'a, Filter: EdgeFilter> Loader<'a, Filter> {
             }
             result
         });
-        edges.dedup();
+        edges.permute(&order);
+
+        let duplicate: Vec<bool> = (0..edges.len())
+            .into_par_iter()
+            .map(|i| {
+                i > 0
+                    && same_way_edge(edges, i - 1, i)
+                    && (0..cost_count).all(|c| edges.cost(i - 1, c) == edges.cost(i, c))
+            })
+            .collect();
+        edges.retain(|e| !duplicate[e.id]);
     }
 
-    fn delete_dominated_edges(&self, edges: Vec<Edge>) -> Vec<Edge> {
-        let mut indices = ::std::collections::BTreeSet::new();
-        for i in 1..edges.len() {
-            let first = &edges[i - 1];
-            let second = &edges[i];
-            if !(first.source == second.source && first.dest == second.dest) {
-                continue;
-            }
-            if first
-                .costs
-                .iter()
-                .zip(second.costs.iter())
-                .all(|(f, s)| f <= s)
-            {
-                indices.insert(i);
-            }
-        }
-        edges
-            .into_iter()
-            .enumerate()
-            .filter(|(i, _)| !indices.contains(i))
-            .map(|(_, e)| e)
-            .collect()
+    fn delete_dominated_edges(&self, edges: &mut EdgeStore) {
+        let cost_count = edges.cost_count();
+        let dominated: Vec<bool> = (0..edges.len())
+            .into_par_iter()
+            .map(|i| {
+                i > 0
+                    && same_way_edge(edges, i - 1, i)
+                    && (0..cost_count).all(|c| edges.cost(i - 1, c) <= edges.cost(i, c))
+            })
+            .collect();
+        edges.retain(|e| !dominated[e.id]);
     }
 }
 
+/// Parallel edges of different ways are kept, so that turn restrictions can
+/// still find the edges of each of their ways.
+fn same_way_edge(edges: &EdgeStore, first: EdgeId, second: EdgeId) -> bool {
+    let (first, second) = (edges.get(first), edges.get(second));
+    first.source == second.source
+        && first.dest == second.dest
+        && first.osm_way_id == second.osm_way_id
+}
+
+/// Bends of a way at its nodes in degrees. The ends of ways that aren't
+/// closed and nodes missing in `nodes` have no bend.
+fn way_bends(nodes: &[Node], ids: &[OsmNodeId]) -> Vec<Option<f64>> {
+    let closed = ids.len() > 2 && ids.first() == ids.last();
+    let way_nodes: Vec<_> = ids.iter().map(|id| find_node(nodes, *id)).collect();
+    let mut bends = Vec::with_capacity(ids.len());
+    for (i, via) in way_nodes.iter().enumerate() {
+        let from = match i {
+            0 if closed => way_nodes[ids.len() - 2],
+            0 => None,
+            _ => way_nodes[i - 1],
+        };
+        let to = match way_nodes.get(i + 1) {
+            Some(to) => *to,
+            None if closed => way_nodes[1],
+            None => None,
+        };
+        bends.push(match (from, via, to) {
+            (Some(from), Some(via), Some(to)) => Some(bend(from, via, to)),
+            _ => None,
+        });
+    }
+    bends
+}
+
+/// Reports a temporary file of the memory limit that can't be written or
+/// read and exits.
+fn temporary_file_failed(e: io::Error) -> ! {
+    error!("Can't use temporary files for the memory limit: {}", e);
+    std::process::exit(1);
+}
+
+/// The groups of parallel edges of the runs.
+fn edge_groups(runs: &mut EdgeRuns) -> impl Iterator<Item = EdgeStore> + '_ {
+    let groups = runs.groups().unwrap_or_else(|e| temporary_file_failed(e));
+    groups.map(|g| g.unwrap_or_else(|e| temporary_file_failed(e)))
+}
+
+/// The edges of the runs without their costs.
+fn edges_of_runs(runs: &mut EdgeRuns) -> impl Iterator<Item = Edge> + '_ {
+    let edges = runs.edges().unwrap_or_else(|e| temporary_file_failed(e));
+    edges.map(|e| e.unwrap_or_else(|e| temporary_file_failed(e)))
+}
+
+/// The finished edges of the temporary file in chunks.
+fn edge_chunks(edges: &mut EdgeFile) -> impl Iterator<Item = EdgeStore> {
+    let chunks = edges.chunks().unwrap_or_else(|e| temporary_file_failed(e));
+    chunks.map(|c| c.unwrap_or_else(|e| temporary_file_failed(e)))
+}
+
+/// Finds a node in nodes sorted by osm id.
+fn find_node(nodes: &[Node], id: OsmNodeId) -> Option<&Node> {
+    nodes
+        .binary_search_by_key(&id, |n| n.osm_id)
+        .ok()
+        .map(|i| &nodes[i])
+}
+
 pub type NodeId = usize;
+pub type EdgeId = usize;
 pub type OsmNodeId = usize;
+pub type OsmWayId = usize;
+pub type OsmRelationId = usize;
 pub type Latitude = f64;
 pub type Longitude = f64;
 
@@ -390,39 +1144,811 @@ impl Node {
     }
 }
 
-pub struct Edge {
-    pub source: NodeId,
-    pub dest: NodeId,
-    costs: Vec<f64>,
+/// The osm data of a graph before node ids are renamed and metrics depending
+/// on nodes are calculated. Edges refer to osm node ids and only carry the
+/// costs of tag metrics.
+pub struct Extract {
+    pub nodes: Vec<Node>,
+    pub edges: EdgeStore,
+    pub node_tag_costs: HashMap<OsmNodeId, Vec<(usize, f64)>>,
+    pub blocked: HashSet<OsmNodeId>,
+    pub restrictions: BTreeMap<OsmRelationId, Vec<OsmRestriction>>,
+    /// Only collected if a tag metric uses cycle routes.
+    pub cycle_routes: CycleRoutes,
+    /// The node ids of the ways with edges, only collected if there are way
+    /// metrics.
+    pub way_nodes: HashMap<OsmWayId, Vec<OsmNodeId>>,
+    /// The ways with edges, only kept for updates if a tag metric uses cycle
+    /// routes. A changed route changes the costs of its ways, which don't
+    /// have to be part of the change file.
+    pub route_ways: HashMap<OsmWayId, Way>,
+    /// Replaces `edges` if a memory limit is set.
+    pub edge_runs: Option<EdgeRuns>,
+    /// Replaces `way_nodes` if a memory limit is set.
+    pub spilled_way_nodes: Option<WayNodeFile>,
+}
+
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: EdgeStore,
+    pub restrictions: Vec<TurnRestriction>,
+}
+
+/// A graph built with a memory limit, its edges are in a temporary file.
+pub struct SpilledGraph {
+    pub nodes: Vec<Node>,
+    pub edges: EdgeFile,
+    pub restrictions: Vec<TurnRestriction>,
 }
 
-impl Edge {
-    pub fn new(source: NodeId, dest: NodeId, cost_count: usize) -> Edge {
-        let costs = vec![0.0; cost_count];
-        Edge {
-            source,
-            dest,
-            costs,
+#[cfg(test)]
+fn test_loader(pbf_paths: Vec<&str>) -> Loader<'_, CarEdgeFilter> {
+    let tag_metrics: TagMetrics = vec![Arc::new(EdgeCount)];
+    let node_metrics: NodeMetrics = vec![Arc::new(Distance)];
+    Loader::new(
+        pbf_paths,
+        "",
+        CarEdgeFilter,
+        tag_metrics,
+        node_metrics,
+        vec![],
+        vec![],
+        InternalMetrics::new(),
+    )
+}
+
+#[cfg(test)]
+type GraphSummary = (Vec<(OsmNodeId, Latitude, Longitude)>, Vec<String>);
+
+/// Nodes and edges of the graph in a comparable form.
+#[cfg(test)]
+fn graph_summary(g: &Graph) -> GraphSummary {
+    let nodes = g.nodes.iter().map(|n| (n.osm_id, n.lat, n.long)).collect();
+    let edges = g
+        .edges
+        .iter()
+        .map(|e| {
+            format!(
+                "{} {} {} {:?}",
+                e.source,
+                e.dest,
+                e.osm_way_id,
+                e.internal_costs()
+            )
+        })
+        .collect();
+    (nodes, edges)
+}
+
+#[test]
+fn directional_speeds() {
+    use osmpbfreader::{NodeId, WayId};
+
+    let l = Loader::new(
+        vec![],
+        "",
+        CarEdgeFilter,
+        vec![Arc::new(CarSpeed)],
+        vec![],
+        vec![],
+        vec![],
+        InternalMetrics::new(),
+    );
+    let way = |tags: &[(&str, &str)]| Way {
+        id: WayId(1),
+        tags: tags
+            .iter()
+            .map(|(k, v)| ((*k).into(), (*v).into()))
+            .collect(),
+        nodes: vec![NodeId(1), NodeId(2), NodeId(3)],
+    };
+    let speeds = |w: &Way| {
+        let mut edges = l.new_edge_store();
+        l.process_way(w, &RouteMemberships::default(), &mut edges);
+        edges
+            .iter()
+            .map(|e| (e.source, e.dest, e.cost(0)))
+            .collect::<Vec<_>>()
+    };
+
+    let w = way(&[
+        ("highway", "primary"),
+        ("maxspeed:forward", "50 mph"),
+        ("maxspeed:backward", "DE:urban"),
+    ]);
+    let mph = 50.0 * 1.609_344;
+    assert_eq!(
+        vec![(1, 2, mph), (2, 1, 50.0), (2, 3, mph), (3, 2, 50.0)],
+        speeds(&w)
+    );
+
+    let w = way(&[
+        ("highway", "primary"),
+        ("oneway", "yes"),
+        ("maxspeed", "70"),
+        ("maxspeed:backward", "30"),
+    ]);
+    assert_eq!(vec![(1, 2, 70.0), (2, 3, 70.0)], speeds(&w));
+}
+
+#[test]
+fn ferry_durations_are_split_by_length() {
+    use super::test_pbf::{temp_path, PbfWriter};
+
+    let path = temp_path("ferry.pbf");
+    PbfWriter::default()
+        .node(1, 50.0, 10.0, &[])
+        .node(2, 50.0, 10.001, &[])
+        .node(3, 50.0, 10.01, &[])
+        .node(4, 50.0, 10.03, &[])
+        .node(5, 50.0, 10.031, &[])
+        .way(10, &[1, 2], &[("highway", "residential")])
+        .way(
+            11,
+            &[2, 3, 4],
+            &[
+                ("route", "ferry"),
+                ("motor_vehicle", "yes"),
+                ("duration", "00:30"),
+            ],
+        )
+        .way(12, &[4, 5], &[("highway", "residential")])
+        .way(13, &[1, 5], &[("route", "ferry")])
+        .write(&path);
+
+    for memory_limit in &[None, Some(1)] {
+        let tag_metrics: TagMetrics = vec![
+            Arc::new(CarSpeed),
+            Arc::new(FerryFlag),
+            Arc::new(FerryDuration),
+        ];
+        let dist = Arc::new(Distance);
+        let cost_metrics: CostMetrics =
+            vec![Arc::new(TravelTime::new(dist.clone(), Arc::new(CarSpeed)))];
+        let mut l = Loader::new(
+            vec![path.to_str().unwrap()],
+            "",
+            CarEdgeFilter,
+            tag_metrics,
+            vec![dist],
+            vec![],
+            cost_metrics,
+            InternalMetrics::new(),
+        );
+        if let Some(limit) = memory_limit {
+            l.set_memory_limit(*limit);
+        }
+        let g = l.build_graph(l.read_extract().unwrap());
+        let index = |m: &str| l.metrics_indices[m];
+        let time = index("TravelTime: Distance / CarSpeed");
+
+        // the passenger ferry 13 is left out
+        assert_eq!(8, g.edges.len());
+        let ferry: Vec<_> = g.edges.iter().filter(|e| e.osm_way_id == 11).collect();
+        assert_eq!(4, ferry.len());
+        let mut total = 0.0;
+        for e in &ferry {
+            assert_eq!(1.0, e.cost(index("FerryFlag")));
+            assert_eq!(e.cost(time), e.cost(index("FerryDuration")));
+            total += e.cost(time);
         }
+        // both directions take the whole duration
+        assert!((3600.0 - total).abs() < 1e-6);
+        let long = ferry.iter().find(|e| g.nodes[e.dest].osm_id == 4).unwrap();
+        assert!((1800.0 * 20.0 / 29.0 - long.cost(time)).abs() < 0.1);
+
+        let road = g.edges.iter().find(|e| e.osm_way_id == 10).unwrap();
+        assert_eq!(0.0, road.cost(index("FerryFlag")));
+        assert_eq!(0.0, road.cost(index("FerryDuration")));
     }
+}
+
+#[test]
+fn cycle_routes_lower_bicycle_unsuitability() {
+    use super::test_pbf::{temp_path, PbfWriter};
+    use super::update::parse_changes;
+    use osmpbfreader::{OsmId, RelationId, WayId};
 
-    pub fn costs(&self, indices: &MetricIndices, internal_only: &InternalMetrics) -> Vec<f64> {
-        let mut costs = Vec::new();
-        for (metric, index) in indices.iter() {
-            if internal_only.contains(metric) {
-                continue;
+    let road = [("highway", "residential")];
+    let path = temp_path("cycle-routes.pbf");
+    PbfWriter::default()
+        .node(1, 50.0, 10.0, &[])
+        .node(2, 50.0, 10.001, &[])
+        .node(3, 50.0, 10.002, &[])
+        .node(4, 50.0, 10.003, &[])
+        .node(5, 50.0, 10.004, &[])
+        .node(6, 50.0, 10.005, &[])
+        .way(10, &[1, 2], &road)
+        .way(11, &[2, 3], &[("highway", "residential"), ("lcn", "yes")])
+        .way(12, &[3, 4], &road)
+        .way(13, &[4, 5], &road)
+        .way(14, &[5, 6], &road)
+        .relation(
+            20,
+            &[(OsmId::Way(WayId(10)), "forward")],
+            &[("type", "route"), ("route", "bicycle"), ("network", "rcn")],
+        )
+        // a stage of the international super-route below
+        .relation(
+            22,
+            &[(OsmId::Way(WayId(14)), "")],
+            &[("type", "route"), ("route", "bicycle")],
+        )
+        .relation(
+            23,
+            &[(OsmId::Relation(RelationId(22)), "")],
+            &[
+                ("type", "superroute"),
+                ("route", "bicycle"),
+                ("network", "icn"),
+            ],
+        )
+        .write(&path);
+
+    let mut l = Loader::new(
+        vec![path.to_str().unwrap()],
+        "",
+        BicycleEdgeFilter,
+        vec![Arc::new(BicycleUnsuitability::default())],
+        vec![],
+        vec![],
+        vec![],
+        InternalMetrics::new(),
+    );
+    l.set_updatable();
+    let unsuitabilities = |g: &Graph| {
+        let of_way = |id| g.edges.iter().find(|e| e.osm_way_id == id).unwrap().cost(0);
+        let plain = of_way(13);
+        [10, 11, 12, 14]
+            .iter()
+            .map(|id| of_way(*id) / plain)
+            .collect::<Vec<_>>()
+    };
+
+    let extract = l.read_extract().unwrap();
+    assert_eq!(3, extract.cycle_routes.len());
+    let mut sidecar = Vec::new();
+    l.write_sidecar(&extract, &mut sidecar).unwrap();
+    assert_eq!(
+        vec![0.6, 0.7, 1.0, 0.5],
+        unsuitabilities(&l.build_graph(extract))
+    );
+
+    let osc = r#"<osmChange version="0.6">
+  <create>
+    <relation id="21">
+      <member type="way" ref="12" role=""/>
+      <tag k="type" v="route"/>
+      <tag k="route" v="bicycle"/>
+      <tag k="network" v="ncn"/>
+    </relation>
+  </create>
+  <delete>
+    <relation id="23"/>
+  </delete>
+</osmChange>"#;
+    // the ways of the changed routes are not part of the change file
+    let mut extract = l.read_sidecar(sidecar.as_slice()).unwrap();
+    l.apply_changes(&mut extract, parse_changes(osc.as_bytes()).unwrap());
+    assert_eq!(3, extract.cycle_routes.len());
+    assert_eq!(
+        vec![0.6, 0.7, 0.5, 1.0],
+        unsuitabilities(&l.build_graph(extract))
+    );
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn curvature_of_zigzags() {
+    use super::test_pbf::{temp_path, PbfWriter};
+
+    // at the equator the zigzags bend by 90 degrees
+    let road = [("highway", "residential")];
+    let path = temp_path("zigzag.pbf");
+    PbfWriter::default()
+        .node(1, 0.0, 0.0, &[])
+        .node(2, 0.001, 0.001, &[])
+        .node(3, 0.0, 0.002, &[])
+        .node(4, 0.001, 0.003, &[])
+        .node(5, 0.0, 0.004, &[])
+        .node(6, 0.001, 0.005, &[])
+        .node(7, 0.01, 0.0, &[])
+        .node(8, 0.01, 0.001, &[])
+        .node(9, 0.01, 0.002, &[])
+        .node(11, 0.02, 0.0, &[])
+        .node(12, 0.02, 0.001, &[])
+        .node(13, 0.021, 0.001, &[])
+        .node(14, 0.021, 0.0, &[])
+        .way(10, &[1, 2, 3, 4], &road)
+        // continues the zigzag of way 10, the bend at 4 is lost
+        .way(20, &[4, 5, 6], &road)
+        .way(30, &[7, 8, 9], &road)
+        .way(40, &[11, 12, 13, 14, 11], &road)
+        .write(&path);
+
+    let loader = |memory_limit: Option<usize>| {
+        let mut l = Loader::new(
+            vec![path.to_str().unwrap()],
+            "",
+            CarEdgeFilter,
+            vec![],
+            vec![Arc::new(Distance)],
+            vec![],
+            vec![],
+            InternalMetrics::new(),
+        );
+        l.set_way_metrics(vec![Arc::new(Curvature)]);
+        if let Some(limit) = memory_limit {
+            l.set_memory_limit(limit);
+        }
+        l
+    };
+    let check = |l: &Loader<CarEdgeFilter>, g: &Graph| {
+        let (dist, curvature) = (
+            l.metrics_indices["Distance"],
+            l.metrics_indices["Curvature"],
+        );
+        let edge = |from: OsmNodeId, to: OsmNodeId| {
+            let e = g
+                .edges
+                .iter()
+                .find(|e| g.nodes[e.source].osm_id == from && g.nodes[e.dest].osm_id == to)
+                .unwrap();
+            (e.cost(curvature), e.cost(dist))
+        };
+        let bends = |from, to| {
+            let (curvature, length) = edge(from, to);
+            curvature * length / 1000.0
+        };
+        let approx = |expected: f64, actual: f64| {
+            assert!(
+                (expected - actual).abs() < 0.1,
+                "{} != {}",
+                expected,
+                actual
+            )
+        };
+        approx(45.0, bends(1, 2));
+        approx(90.0, bends(2, 3));
+        approx(90.0, bends(3, 2));
+        approx(45.0, bends(3, 4));
+        approx(45.0, bends(4, 5));
+        approx(45.0, bends(5, 6));
+        assert!(edge(7, 8).0.abs() < 1e-9);
+        // the closed way bends at every corner
+        for (from, to) in [(11, 12), (12, 13), (13, 14), (14, 11)] {
+            approx(90.0, bends(from, to));
+        }
+        // 90 degrees on 111.2 m
+        approx(90.0 / 0.1112, edge(11, 12).0);
+    };
+
+    for memory_limit in &[None, Some(1)] {
+        let l = loader(*memory_limit);
+        check(&l, &l.build_graph(l.read_extract().unwrap()));
+    }
+
+    let mut l = loader(None);
+    l.set_updatable();
+    let mut sidecar = Vec::new();
+    l.write_sidecar(&l.read_extract().unwrap(), &mut sidecar)
+        .unwrap();
+    let extract = l.read_sidecar(sidecar.as_slice()).unwrap();
+    assert_eq!(4, extract.way_nodes.len());
+    check(&l, &l.build_graph(extract));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn merge_overlapping_files() {
+    use super::test_pbf::{temp_path, PbfWriter};
+    use osmpbfreader::{NodeId as PbfNodeId, OsmId, WayId};
+
+    let road = [("highway", "residential")];
+    let left_turn = [
+        (OsmId::Way(WayId(10)), "from"),
+        (OsmId::Node(PbfNodeId(3)), "via"),
+        (OsmId::Way(WayId(11)), "to"),
+    ];
+    let restriction = [("type", "restriction"), ("restriction", "no_left_turn")];
+    let west = temp_path("merge-west.pbf");
+    let east = temp_path("merge-east.pbf");
+    PbfWriter::default()
+        .node(1, 50.0, 10.0, &[])
+        .node(2, 50.0, 10.001, &[])
+        .node(3, 50.0, 10.002, &[])
+        .way(10, &[1, 2, 3], &road)
+        .way(11, &[3, 4], &road)
+        .relation(20, &left_turn, &restriction)
+        .write(&west);
+    PbfWriter::default()
+        .node(2, 50.0, 10.001, &[])
+        .node(3, 50.0, 10.002, &[])
+        .node(4, 50.0, 10.003, &[])
+        .way(10, &[1, 2, 3], &road)
+        .way(11, &[3, 4], &road)
+        .relation(20, &left_turn, &restriction)
+        .write(&east);
+
+    let west_only = test_loader(vec![west.to_str().unwrap()]);
+    let west_only = west_only.build_graph(west_only.read_extract().unwrap());
+    // node 4 is missing in the west file, so way 11 is dropped
+    assert_eq!(3, west_only.nodes.len());
+    assert_eq!(4, west_only.edges.len());
+    assert!(west_only.restrictions.is_empty());
+
+    let merged = test_loader(vec![west.to_str().unwrap(), east.to_str().unwrap()]);
+    let merged = merged.build_graph(merged.read_extract().unwrap());
+    let mut osm_ids: Vec<_> = merged.nodes.iter().map(|n| n.osm_id).collect();
+    osm_ids.sort_unstable();
+    assert_eq!(vec![1, 2, 3, 4], osm_ids);
+    assert_eq!(6, merged.edges.len());
+    assert_eq!(1, merged.restrictions.len());
+    for e in merged.edges.iter() {
+        assert!(e.source < merged.nodes.len() && e.dest < merged.nodes.len());
+    }
+    let missing = temp_path("missing.pbf");
+    let missing = test_loader(vec![west.to_str().unwrap(), missing.to_str().unwrap()]);
+    assert!(missing.read_extract().is_err());
+
+    std::fs::remove_file(west).unwrap();
+    std::fs::remove_file(east).unwrap();
+}
+
+#[test]
+fn duplicate_edges_of_other_ways_are_kept() {
+    use super::test_pbf::{temp_path, PbfWriter};
+    use osmpbfreader::{NodeId as PbfNodeId, OsmId, WayId};
+
+    let road = [("highway", "residential")];
+    let straight_on = [
+        (OsmId::Way(WayId(11)), "from"),
+        (OsmId::Node(PbfNodeId(2)), "via"),
+        (OsmId::Way(WayId(12)), "to"),
+    ];
+    let path = temp_path("duplicate-ways.pbf");
+    PbfWriter::default()
+        .node(1, 50.0, 10.0, &[])
+        .node(2, 50.0, 10.001, &[])
+        .node(3, 50.0, 10.002, &[])
+        // way 11 overlaps way 10 with the same costs
+        .way(10, &[1, 2], &road)
+        .way(11, &[1, 2], &road)
+        .way(12, &[2, 3], &road)
+        .relation(
+            20,
+            &straight_on,
+            &[("type", "restriction"), ("restriction", "no_straight_on")],
+        )
+        .write(&path);
+
+    for memory_limit in &[None, Some(1)] {
+        let mut l = test_loader(vec![path.to_str().unwrap()]);
+        if let Some(limit) = memory_limit {
+            l.set_memory_limit(*limit);
+        }
+        let g = l.build_graph(l.read_extract().unwrap());
+        assert_eq!(6, g.edges.len());
+        assert_eq!(1, g.restrictions.len());
+        let from = g.edges.get(g.restrictions[0].from);
+        assert_eq!(11, from.osm_way_id);
+    }
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn external_edges_equal_in_memory() {
+    use super::region::{ClipMode, Region};
+    use super::test_pbf::{temp_path, PbfWriter};
+    use osmpbfreader::{NodeId, OsmId, WayId};
+
+    let road = [("highway", "residential")];
+    let path = temp_path("external.pbf");
+    let mut writer = PbfWriter::default();
+    for id in 1..=20 {
+        let tags: &[(&str, &str)] = if id == 7 {
+            &[("barrier", "bollard")]
+        } else {
+            &[]
+        };
+        writer.node(
+            id,
+            50.0 + (id % 4) as f64 * 0.001,
+            10.0 + id as f64 * 0.001,
+            tags,
+        );
+    }
+    writer
+        .way(1, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10], &road)
+        // duplicates and parallel edges with other costs
+        .way(2, &[3, 4, 5], &road)
+        .way(3, &[4, 5], &[("highway", "primary")])
+        .way(4, &[10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20], &road)
+        // node 21 is missing
+        .way(5, &[20, 21, 1], &road)
+        .way(6, &[12, 2, 15], &road)
+        // completely outside of the clipping region
+        .way(7, &[17, 18], &[("highway", "primary")])
+        .relation(
+            30,
+            &[
+                (OsmId::Way(WayId(6)), "from"),
+                (OsmId::Node(NodeId(12)), "via"),
+                (OsmId::Way(WayId(4)), "to"),
+            ],
+            &[("type", "restriction"), ("restriction", "no_left_turn")],
+        )
+        .write(&path);
+
+    let clips = vec![None, Some(ClipMode::Strict), Some(ClipMode::CompleteWays)];
+    for mode in clips {
+        let build = |memory_limit| {
+            let mut loader = test_loader(vec![path.to_str().unwrap()]);
+            if let Some(mode) = mode {
+                let region = Region::parse_bbox("10.0,49.0,10.0135,51.0").unwrap();
+                loader.set_clip(Clip { region, mode });
             }
-            costs.push(self.costs[*index]);
+            if let Some(limit) = memory_limit {
+                loader.set_memory_limit(limit);
+            }
+            loader.build_graph(loader.read_extract().unwrap())
+        };
+        let in_memory = build(None);
+        // a few edges per run
+        let external = build(Some(500));
+        assert!(!in_memory.edges.is_empty());
+        assert_eq!(graph_summary(&in_memory), graph_summary(&external));
+        assert_eq!(in_memory.restrictions, external.restrictions);
+        if mode.is_none() {
+            assert_eq!(2, in_memory.restrictions.len());
         }
+    }
 
-        costs
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn parallel_metrics_are_deterministic() {
+    use super::test_pbf::{temp_path, PbfWriter};
+
+    // a grid with parallel ways, so duplicate and dominated edges are
+    // removed as well
+    let path = temp_path("parallel.pbf");
+    let mut writer = PbfWriter::default();
+    let id = |row: i64, column: i64| row * 40 + column + 1;
+    for row in 0..40 {
+        for column in 0..40 {
+            writer.node(
+                id(row, column),
+                50.0 + row as f64 * 0.001,
+                10.0 + column as f64 * 0.001,
+                &[],
+            );
+        }
     }
+    for i in 0..40 {
+        let row: Vec<i64> = (0..40).map(|c| id(i, c)).collect();
+        let column: Vec<i64> = (0..40).map(|r| id(r, i)).collect();
+        writer.way(3 * i + 1, &row, &[("highway", "residential")]);
+        writer.way(3 * i + 2, &column, &[("highway", "primary")]);
+        writer.way(3 * i + 3, &row[..10], &[("highway", "primary")]);
+    }
+    writer.write(&path);
+
+    let build = |threads| {
+        let dist = Arc::new(Distance);
+        let cost_metrics: CostMetrics =
+            vec![Arc::new(TravelTime::new(dist.clone(), Arc::new(CarSpeed)))];
+        let loader = Loader::new(
+            vec![path.to_str().unwrap()],
+            "",
+            CarEdgeFilter,
+            vec![Arc::new(CarSpeed), Arc::new(EdgeCount)],
+            vec![
+                dist,
+                Arc::new(RandomWeights::new(3, "normal:10,4".parse().unwrap())),
+            ],
+            vec![],
+            cost_metrics,
+            InternalMetrics::new(),
+        );
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap()
+            .install(|| graph_summary(&loader.build_graph(loader.read_extract().unwrap())))
+    };
+    let sequential = build(1);
+    assert_eq!(40 * 40, sequential.0.len());
+    assert_eq!(sequential, build(4));
+
+    std::fs::remove_file(path).unwrap();
 }
 
-impl PartialEq for Edge {
-    fn eq(&self, rhs: &Self) -> bool {
-        self.source == rhs.source
-            && self.dest == rhs.dest
-            && self.costs.iter().zip(rhs.costs.iter()).all(|(a, b)| a == b)
+/// Time and peak memory of extracting a generated grid network, run with
+/// `cargo test --release -- --ignored --nocapture extract_peak_memory`.
+/// Every variant runs in a process of its own, so its peak memory neither
+/// contains the generation of the pbf file nor the other variants. The
+/// node id variants only collect the node ids of the ways, once in a
+/// `HashSet` with one message per id as before and once in a `NodeIdSet`.
+/// The edge variants collect and sort the edges, once with a `Vec` of costs
+/// per edge as before and once in an `EdgeStore` with `f64` and `f32` costs.
+/// The size can be changed with `PBFEXTRACTOR_BENCH_SIZE`, the side length
+/// of the grid in nodes, `PBFEXTRACTOR_BENCH_MEMORY_LIMIT` sets a memory
+/// limit in MB for the extractions.
+#[test]
+#[ignore]
+fn extract_peak_memory() {
+    use super::test_pbf::{temp_path, PbfWriter};
+    use std::process::Command;
+
+    let size: i64 = std::env::var("PBFEXTRACTOR_BENCH_SIZE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1000);
+
+    // a grid of residential roads, every node is referenced by two ways and
+    // every second node has no way at all
+    let path = temp_path("bench.pbf");
+    let mut writer = PbfWriter::default();
+    let id = |row: i64, column: i64| 2 * (row * size + column) + 1;
+    for row in 0..size {
+        for column in 0..size {
+            let (lat, lng) = (row as f64 * 0.001, column as f64 * 0.001);
+            writer.node(id(row, column), lat, lng, &[]);
+            writer.node(id(row, column) + 1, lat + 0.0005, lng, &[]);
+        }
+    }
+    let road = [("highway", "residential")];
+    for i in 0..size {
+        let row: Vec<i64> = (0..size).map(|c| id(i, c)).collect();
+        let column: Vec<i64> = (0..size).map(|r| id(r, i)).collect();
+        writer.way(2 * i + 1, &row, &road);
+        writer.way(2 * i + 2, &column, &road);
+    }
+    writer.write(&path);
+    drop(writer);
+    println!("Generated {} nodes", 2 * size * size);
+
+    let variants = [
+        "node-ids-hash-set",
+        "node-ids-sorted",
+        "edges-vec-per-edge",
+        "edges-store-f64",
+        "edges-store-f32",
+        "extract-f64",
+        "extract-f32",
+    ];
+    for variant in &variants {
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "pbf::extract_benchmark_variant",
+                "--exact",
+                "--ignored",
+                "--nocapture",
+                "--test-threads=1",
+            ])
+            .env("PBFEXTRACTOR_BENCH_VARIANT", variant)
+            .env("PBFEXTRACTOR_BENCH_PBF", &path)
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let result = stdout
+            .lines()
+            .find_map(|l| l.split("bench-result ").nth(1))
+            .unwrap_or_else(|| panic!("{} failed: {}", variant, stdout));
+        let result: Vec<&str> = result.split(' ').collect();
+        println!(
+            "{:>20}: {} items in {}s, peak memory {} kB",
+            variant, result[0], result[1], result[2]
+        );
     }
+
+    std::fs::remove_file(path).unwrap();
+}
+
+/// One variant of `extract_peak_memory`, does nothing when run on its own.
+#[test]
+#[ignore]
+fn extract_benchmark_variant() {
+    use super::node_ids::NodeIdBatcher;
+    use std::thread::spawn;
+    use std::time::Instant;
+
+    let (variant, path) = match (
+        std::env::var("PBFEXTRACTOR_BENCH_VARIANT"),
+        std::env::var("PBFEXTRACTOR_BENCH_PBF"),
+    ) {
+        (Ok(variant), Ok(path)) => (variant, path),
+        _ => return,
+    };
+    let ways = |f: &mut dyn FnMut(&Way)| {
+        let mut reader = OsmPbfReader::new(File::open(&path).unwrap());
+        for obj in reader.par_iter() {
+            if let Ok(OsmObj::Way(w)) = obj {
+                f(&w);
+            }
+        }
+    };
+
+    let start = Instant::now();
+    let items = match variant.as_ref() {
+        "node-ids-hash-set" => {
+            let (sender, receiver) = channel();
+            let set = spawn(move || receiver.iter().collect::<HashSet<OsmNodeId>>());
+            ways(&mut |w| {
+                for n in &w.nodes {
+                    sender.send(n.0 as OsmNodeId).unwrap();
+                }
+            });
+            drop(sender);
+            set.join().unwrap().len()
+        }
+        "node-ids-sorted" => {
+            let (sender, receiver) = channel();
+            let set = NodeIdSet::collect(receiver);
+            let mut batcher = NodeIdBatcher::new(sender);
+            ways(&mut |w| {
+                for n in &w.nodes {
+                    batcher.push(n.0 as OsmNodeId);
+                }
+            });
+            drop(batcher);
+            set.recv().unwrap().len()
+        }
+        "edges-vec-per-edge" => {
+            let loader = test_loader(vec![]);
+            let mut edges: Vec<(Edge, Vec<f64>)> = Vec::new();
+            ways(&mut |w| {
+                let mut way_edges = loader.new_edge_store();
+                loader.process_way(w, &RouteMemberships::default(), &mut way_edges);
+                edges.extend(way_edges.iter().map(|e| (*e, e.internal_costs())));
+            });
+            edges.par_sort_by_key(|(e, _)| (e.source, e.dest));
+            edges.len()
+        }
+        "edges-store-f64" | "edges-store-f32" => {
+            let mut loader = test_loader(vec![]);
+            if variant.ends_with("f32") {
+                loader.set_precision(Precision::Single);
+            }
+            let mut edges = loader.new_edge_store();
+            ways(&mut |w| loader.process_way(w, &RouteMemberships::default(), &mut edges));
+            let mut order: Vec<EdgeId> = (0..edges.len()).collect();
+            order.par_sort_by_key(|i| {
+                let e = edges.get(*i);
+                (e.source, e.dest)
+            });
+            edges.permute(&order);
+            edges.len()
+        }
+        "extract-f64" | "extract-f32" => {
+            let mut loader = test_loader(vec![&path]);
+            if let Some(limit) = std::env::var("PBFEXTRACTOR_BENCH_MEMORY_LIMIT")
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+            {
+                loader.set_memory_limit(limit * 1024 * 1024);
+            }
+            if variant.ends_with("f32") {
+                loader.set_precision(Precision::Single);
+            }
+            let extract = loader.read_extract().unwrap();
+            if extract.edge_runs.is_some() {
+                loader.build_spilled_graph(extract).edges.len()
+            } else {
+                loader.build_graph(extract).edges.len()
+            }
+        }
+        v => panic!("unknown variant {}", v),
+    };
+    println!(
+        "bench-result {} {:.2} {}",
+        items,
+        start.elapsed().as_secs_f64(),
+        super::report::peak_memory_kb().unwrap_or(0)
+    );
 }
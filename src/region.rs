@@ -0,0 +1,299 @@
+/*
+Pbfextractor creates graph files for the cycle-routing projects from pbf and srtm data
+Copyright (C) 2018  Florian Barth
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use super::edge_store::{Edge, EdgeStore};
+use super::pbf::{Latitude, Longitude, Node, OsmNodeId, OsmWayId};
+
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// A ring of (longitude, latitude) pairs.
+pub type Ring = Vec<(Longitude, Latitude)>;
+
+#[derive(Debug, PartialEq)]
+pub enum Region {
+    BoundingBox {
+        min_lng: Longitude,
+        min_lat: Latitude,
+        max_lng: Longitude,
+        max_lat: Latitude,
+    },
+    Polygon {
+        outer: Vec<Ring>,
+        holes: Vec<Ring>,
+    },
+}
+
+impl Region {
+    /// Parses `minlon,minlat,maxlon,maxlat`.
+    pub fn parse_bbox(s: &str) -> Result<Region, String> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid bounding box {}: {}", s, e))?;
+        if values.len() != 4 {
+            return Err(format!("bounding box {} needs exactly 4 values", s));
+        }
+        if values[0] > values[2] || values[1] > values[3] {
+            return Err(format!("bounding box {} has min > max", s));
+        }
+        Ok(Region::BoundingBox {
+            min_lng: values[0],
+            min_lat: values[1],
+            max_lng: values[2],
+            max_lat: values[3],
+        })
+    }
+
+    pub fn from_poly_file(path: &str) -> Result<Region, String> {
+        let f = File::open(path).map_err(|e| format!("can't open {}: {}", path, e))?;
+        Region::parse_poly(BufReader::new(f))
+    }
+
+    /// Parses the osmosis polygon filter file format. Sections whose name
+    /// starts with `!` are holes.
+    pub fn parse_poly<R: BufRead>(input: R) -> Result<Region, String> {
+        let mut lines = input.lines().map(|l| l.map_err(|e| e.to_string()));
+        // first line is the name of the polygon
+        lines.next().ok_or("empty polygon file")??;
+
+        let mut outer = Vec::new();
+        let mut holes = Vec::new();
+        while let Some(section) = lines.next() {
+            let section = section?;
+            let section = section.trim();
+            if section == "END" {
+                break;
+            }
+            if section.is_empty() {
+                continue;
+            }
+            let mut ring = Ring::new();
+            loop {
+                let line = lines.next().ok_or("unterminated polygon section")??;
+                let line = line.trim();
+                if line == "END" {
+                    break;
+                }
+                let mut coords = line.split_whitespace().map(str::parse::<f64>);
+                match (coords.next(), coords.next()) {
+                    (Some(Ok(lng)), Some(Ok(lat))) => ring.push((lng, lat)),
+                    _ => return Err(format!("invalid coordinate line: {}", line)),
+                }
+            }
+            if section.starts_with('!') {
+                holes.push(ring);
+            } else {
+                outer.push(ring);
+            }
+        }
+        if outer.is_empty() {
+            return Err("polygon file contains no polygon".to_owned());
+        }
+        Ok(Region::Polygon { outer, holes })
+    }
+
+    pub fn contains(&self, lat: Latitude, lng: Longitude) -> bool {
+        match self {
+            Region::BoundingBox {
+                min_lng,
+                min_lat,
+                max_lng,
+                max_lat,
+            } => *min_lng <= lng && lng <= *max_lng && *min_lat <= lat && lat <= *max_lat,
+            Region::Polygon { outer, holes } => {
+                outer.iter().any(|r| ring_contains(r, lat, lng))
+                    && !holes.iter().any(|r| ring_contains(r, lat, lng))
+            }
+        }
+    }
+}
+
+/// Even-odd ray casting test.
+fn ring_contains(ring: &[(Longitude, Latitude)], lat: Latitude, lng: Longitude) -> bool {
+    let mut inside = false;
+    let mut j = ring.len().wrapping_sub(1);
+    for i in 0..ring.len() {
+        let (x_i, y_i) = ring[i];
+        let (x_j, y_j) = ring[j];
+        if (y_i > lat) != (y_j > lat) && lng < (x_j - x_i) * (lat - y_i) / (y_j - y_i) + x_i {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClipMode {
+    /// Drops every edge with an endpoint outside of the region.
+    Strict,
+    /// Keeps all edges of ways with at least one node inside the region.
+    CompleteWays,
+}
+
+pub struct Clip {
+    pub region: Region,
+    pub mode: ClipMode,
+}
+
+impl Clip {
+    /// Removes the edges outside of the region and the nodes no edge
+    /// refers to anymore. Edges have to refer to osm node ids.
+    pub fn apply(&self, nodes: &mut Vec<Node>, edges: &mut EdgeStore) {
+        let inside: HashSet<OsmNodeId> = nodes
+            .iter()
+            .filter(|n| self.region.contains(n.lat, n.long))
+            .map(|n| n.osm_id)
+            .collect();
+        let inside = |id| inside.contains(&id);
+
+        let ways = self.complete_ways(edges.topology().iter(), inside);
+        edges.retain(|e| self.keeps(&e, inside, &ways));
+
+        let used: HashSet<OsmNodeId> = edges
+            .topology()
+            .iter()
+            .flat_map(|e| [e.source, e.dest])
+            .collect();
+        nodes.retain(|n| used.contains(&n.osm_id));
+    }
+
+    /// The ways kept completely in `ClipMode::CompleteWays`, empty for
+    /// `ClipMode::Strict`.
+    pub fn complete_ways<E: Borrow<Edge>>(
+        &self,
+        edges: impl Iterator<Item = E>,
+        inside: impl Fn(OsmNodeId) -> bool,
+    ) -> HashSet<OsmWayId> {
+        match self.mode {
+            ClipMode::Strict => HashSet::new(),
+            ClipMode::CompleteWays => edges
+                .filter(|e| inside(e.borrow().source) || inside(e.borrow().dest))
+                .map(|e| e.borrow().osm_way_id)
+                .collect(),
+        }
+    }
+
+    /// Whether the edge is kept, `ways` have to be the result of
+    /// `complete_ways`.
+    pub fn keeps(
+        &self,
+        e: &Edge,
+        inside: impl Fn(OsmNodeId) -> bool,
+        ways: &HashSet<OsmWayId>,
+    ) -> bool {
+        match self.mode {
+            ClipMode::Strict => inside(e.source) && inside(e.dest),
+            ClipMode::CompleteWays => ways.contains(&e.osm_way_id),
+        }
+    }
+}
+
+#[test]
+fn parse_bbox() {
+    assert_eq!(
+        Ok(Region::BoundingBox {
+            min_lng: 9.0,
+            min_lat: 48.5,
+            max_lng: 9.5,
+            max_lat: 49.0
+        }),
+        Region::parse_bbox("9.0,48.5,9.5,49.0")
+    );
+    assert!(Region::parse_bbox("9.0,48.5,9.5").is_err());
+    assert!(Region::parse_bbox("9.5,48.5,9.0,49.0").is_err());
+    assert!(Region::parse_bbox("a,b,c,d").is_err());
+}
+
+#[test]
+fn poly_with_hole() {
+    let poly = "square
+1
+   0.0 0.0
+   10.0 0.0
+   10.0 10.0
+   0.0 10.0
+   0.0 0.0
+END
+!hole
+   4.0 4.0
+   6.0 4.0
+   6.0 6.0
+   4.0 6.0
+END
+END
+";
+    let region = Region::parse_poly(poly.as_bytes()).unwrap();
+
+    assert!(region.contains(2.0, 2.0));
+    assert!(region.contains(8.0, 5.0));
+    assert!(!region.contains(5.0, 5.0));
+    assert!(!region.contains(11.0, 5.0));
+    assert!(!region.contains(5.0, -1.0));
+
+    assert!(Region::parse_poly("name\n1\n 0.0 0.0\n".as_bytes()).is_err());
+    assert!(Region::parse_poly("name\nEND\n".as_bytes()).is_err());
+}
+
+#[test]
+fn clip_modes() {
+    use super::edge_store::Precision;
+
+    let graph = || {
+        // way 1: 1 - 2 - 3, way 2: 3 - 4, node 3 and 4 are outside
+        let nodes = vec![
+            Node::new(1, 0.5, 0.5, 0.0),
+            Node::new(2, 0.5, 0.9, 0.0),
+            Node::new(3, 0.5, 1.5, 0.0),
+            Node::new(4, 0.5, 2.5, 0.0),
+        ];
+        let mut edges = EdgeStore::new(0, Precision::Double);
+        for (s, d, w) in &[(1, 2, 1), (2, 3, 1), (3, 4, 2)] {
+            edges.push(Edge::new(*s, *d, *w), &[]);
+        }
+        (nodes, edges)
+    };
+    let region = Region::parse_bbox("0,0,1,1").unwrap();
+
+    let (mut nodes, mut edges) = graph();
+    let clip = Clip {
+        region,
+        mode: ClipMode::Strict,
+    };
+    clip.apply(&mut nodes, &mut edges);
+    assert_eq!(1, edges.len());
+    assert_eq!(
+        vec![1, 2],
+        nodes.iter().map(|n| n.osm_id).collect::<Vec<_>>()
+    );
+
+    let (mut nodes, mut edges) = graph();
+    let clip = Clip {
+        mode: ClipMode::CompleteWays,
+        ..clip
+    };
+    clip.apply(&mut nodes, &mut edges);
+    assert_eq!(2, edges.len());
+    assert_eq!(
+        vec![1, 2, 3],
+        nodes.iter().map(|n| n.osm_id).collect::<Vec<_>>()
+    );
+}
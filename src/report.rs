@@ -0,0 +1,320 @@
+/*
+Pbfextractor creates graph files for the cycle-routing projects from pbf and srtm data
+Copyright (C) 2018  Florian Barth
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Logging, progress of the phases of a run and the run report.
+use log::{debug, info, LevelFilter, Log, Metadata, Record};
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The clock is only read once per this many items.
+const PROGRESS_STEP: usize = 1 << 14;
+/// Minimal time between two progress messages of a phase.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Logger {
+    start: Instant,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{:>8.1}s {:<5} {}",
+                self.start.elapsed().as_secs_f64(),
+                record.level(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Logs to stderr. `verbosity` 0 logs infos, every step up adds more
+/// details, -1 only logs warnings and errors.
+pub fn init_logging(verbosity: i64) {
+    let level = match verbosity {
+        v if v < 0 => LevelFilter::Warn,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let logger = Logger {
+        start: Instant::now(),
+    };
+    log::set_boxed_logger(Box::new(logger)).expect("logging is initialized twice");
+    log::set_max_level(level);
+}
+
+/// The highest resident memory of the process so far in kB, only known on
+/// Linux.
+pub fn peak_memory_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    line["VmHWM:".len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn format_memory(kb: Option<u64>) -> String {
+    match kb {
+        Some(kb) => format!("{} MB", kb / 1024),
+        None => "unknown".to_owned(),
+    }
+}
+
+/// Time and number of processed items of a finished phase. The peak memory
+/// is the one of the whole process up to the end of the phase, earlier
+/// phases may have caused it.
+#[derive(Debug, Clone)]
+pub struct PhaseReport {
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub items: usize,
+    pub seconds: f64,
+    pub process_peak_memory_kb: Option<u64>,
+}
+
+/// Collects the phases and counts of a run.
+pub struct Report {
+    start: Instant,
+    phases: Mutex<Vec<PhaseReport>>,
+    counts: Mutex<BTreeMap<&'static str, usize>>,
+}
+
+impl Default for Report {
+    fn default() -> Report {
+        Report {
+            start: Instant::now(),
+            phases: Mutex::new(Vec::new()),
+            counts: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Report {
+    /// Starts a phase counting `unit`s. It is finished when dropped.
+    pub fn phase(&self, name: &'static str, unit: &'static str) -> Phase<'_> {
+        debug!("Started {}", name);
+        Phase {
+            report: self,
+            name,
+            unit,
+            start: Instant::now(),
+            items: AtomicUsize::new(0),
+            last_progress: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a result of the run, like the number of edges of the graph.
+    pub fn set_count(&self, name: &'static str, count: usize) {
+        self.counts.lock().unwrap().insert(name, count);
+    }
+
+    pub fn phases(&self) -> Vec<PhaseReport> {
+        self.phases.lock().unwrap().clone()
+    }
+
+    /// Writes the report as JSON object.
+    pub fn write_json<W: Write>(
+        &self,
+        mut out: W,
+        inputs: &[&str],
+        graph: &str,
+        metrics: &[String],
+    ) -> io::Result<()> {
+        let strings = |values: &[&str]| {
+            let values: Vec<_> = values.iter().map(|v| json_string(v)).collect();
+            format!("[{}]", values.join(", "))
+        };
+        let metrics: Vec<&str> = metrics.iter().map(String::as_str).collect();
+
+        writeln!(out, "{{")?;
+        writeln!(
+            out,
+            "  \"version\": {},",
+            json_string(env!("CARGO_PKG_VERSION"))
+        )?;
+        writeln!(out, "  \"inputs\": {},", strings(inputs))?;
+        writeln!(out, "  \"graph\": {},", json_string(graph))?;
+        writeln!(out, "  \"metrics\": {},", strings(&metrics))?;
+        writeln!(
+            out,
+            "  \"seconds\": {:.3},",
+            self.start.elapsed().as_secs_f64()
+        )?;
+        writeln!(
+            out,
+            "  \"peak_memory_kb\": {},",
+            json_option(peak_memory_kb())
+        )?;
+        writeln!(out, "  \"counts\": {{")?;
+        let counts = self.counts.lock().unwrap();
+        for (i, (name, count)) in counts.iter().enumerate() {
+            let comma = if i + 1 < counts.len() { "," } else { "" };
+            writeln!(out, "    {}: {}{}", json_string(name), count, comma)?;
+        }
+        writeln!(out, "  }},")?;
+        writeln!(out, "  \"phases\": [")?;
+        let phases = self.phases();
+        for (i, p) in phases.iter().enumerate() {
+            let comma = if i + 1 < phases.len() { "," } else { "" };
+            writeln!(
+                out,
+                "    {{\"name\": {}, \"unit\": {}, \"items\": {}, \"seconds\": {:.3}, \"process_peak_memory_kb\": {}}}{}",
+                json_string(p.name),
+                json_string(p.unit),
+                p.items,
+                p.seconds,
+                json_option(p.process_peak_memory_kb),
+                comma
+            )?;
+        }
+        writeln!(out, "  ]")?;
+        writeln!(out, "}}")
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+fn json_option(value: Option<u64>) -> String {
+    value.map_or_else(|| "null".to_owned(), |v| v.to_string())
+}
+
+/// A running phase. Items can be counted from several threads, the count
+/// is logged every few seconds.
+pub struct Phase<'a> {
+    report: &'a Report,
+    name: &'static str,
+    unit: &'static str,
+    start: Instant,
+    items: AtomicUsize,
+    /// Milliseconds since the start of the last progress message.
+    last_progress: AtomicU64,
+}
+
+impl<'a> Phase<'a> {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, items: usize) {
+        let before = self.items.fetch_add(items, Ordering::Relaxed);
+        if before / PROGRESS_STEP != (before + items) / PROGRESS_STEP {
+            self.progress(before + items);
+        }
+    }
+
+    fn progress(&self, items: usize) {
+        let now = self.start.elapsed().as_millis() as u64;
+        let last = self.last_progress.load(Ordering::Relaxed);
+        if now - last < PROGRESS_INTERVAL.as_millis() as u64 {
+            return;
+        }
+        // only one of the threads passing the interval logs
+        if self
+            .last_progress
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            info!("{}: {} {} so far", self.name, items, self.unit);
+        }
+    }
+}
+
+impl<'a> Drop for Phase<'a> {
+    fn drop(&mut self) {
+        let report = PhaseReport {
+            name: self.name,
+            unit: self.unit,
+            items: *self.items.get_mut(),
+            seconds: self.start.elapsed().as_secs_f64(),
+            process_peak_memory_kb: peak_memory_kb(),
+        };
+        info!(
+            "Finished {}: {} {} in {:.1}s, process peak memory so far {}",
+            report.name,
+            report.items,
+            report.unit,
+            report.seconds,
+            format_memory(report.process_peak_memory_kb)
+        );
+        self.report.phases.lock().unwrap().push(report);
+    }
+}
+
+#[test]
+fn phases_and_json() {
+    let report = Report::default();
+    {
+        let phase = report.phase("reading", "ways");
+        phase.add(PROGRESS_STEP * 2);
+        phase.inc();
+    }
+    drop(report.phase("writing", "edges"));
+    report.set_count("edges", 12);
+
+    let phases = report.phases();
+    assert_eq!(2, phases.len());
+    assert_eq!(
+        ("reading", 2 * PROGRESS_STEP + 1),
+        (phases[0].name, phases[0].items)
+    );
+    assert_eq!(0, phases[1].items);
+
+    let mut json = Vec::new();
+    report
+        .write_json(
+            &mut json,
+            &["a \"b\".pbf"],
+            "out.graph",
+            &["Distance".to_owned()],
+        )
+        .unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.contains(r#""inputs": ["a \"b\".pbf"],"#));
+    assert!(json.contains(r#""metrics": ["Distance"],"#));
+    assert!(json.contains(r#""edges": 12"#));
+    assert!(json.contains(r#"{"name": "reading", "unit": "ways", "items": 32769,"#));
+    assert!(json.contains(r#""process_peak_memory_kb": "#));
+    assert!(json.trim_end().ends_with('}'));
+}
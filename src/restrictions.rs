@@ -0,0 +1,457 @@
+/*
+Pbfextractor creates graph files for the cycle-routing projects from pbf and srtm data
+Copyright (C) 2018  Florian Barth
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use super::edge_store::Edge;
+use super::pbf::{EdgeId, Node, NodeId, OsmNodeId, OsmWayId};
+
+use osmpbfreader::{OsmId, Relation};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestrictionKind {
+    NoLeftTurn,
+    NoRightTurn,
+    NoStraightOn,
+    NoUTurn,
+    NoEntry,
+    NoExit,
+    OnlyLeftTurn,
+    OnlyRightTurn,
+    OnlyStraightOn,
+    OnlyUTurn,
+}
+
+impl RestrictionKind {
+    pub fn from_tag(tag: &str) -> Option<RestrictionKind> {
+        use RestrictionKind::*;
+        match tag {
+            "no_left_turn" => Some(NoLeftTurn),
+            "no_right_turn" => Some(NoRightTurn),
+            "no_straight_on" => Some(NoStraightOn),
+            "no_u_turn" => Some(NoUTurn),
+            "no_entry" => Some(NoEntry),
+            "no_exit" => Some(NoExit),
+            "only_left_turn" => Some(OnlyLeftTurn),
+            "only_right_turn" => Some(OnlyRightTurn),
+            "only_straight_on" => Some(OnlyStraightOn),
+            "only_u_turn" => Some(OnlyUTurn),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        use RestrictionKind::*;
+        match self {
+            NoLeftTurn => "no_left_turn",
+            NoRightTurn => "no_right_turn",
+            NoStraightOn => "no_straight_on",
+            NoUTurn => "no_u_turn",
+            NoEntry => "no_entry",
+            NoExit => "no_exit",
+            OnlyLeftTurn => "only_left_turn",
+            OnlyRightTurn => "only_right_turn",
+            OnlyStraightOn => "only_straight_on",
+            OnlyUTurn => "only_u_turn",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OsmVia {
+    Node(OsmNodeId),
+    Ways(Vec<OsmWayId>),
+}
+
+/// A turn restriction as found in the pbf file, referencing OSM ids.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsmRestriction {
+    pub kind: RestrictionKind,
+    pub from: OsmWayId,
+    pub via: OsmVia,
+    pub to: OsmWayId,
+}
+
+impl OsmRestriction {
+    /// Parses a `type=restriction` relation. `no_entry` and `no_exit` may
+    /// have several `from` or `to` members, so one relation can result in
+    /// multiple restrictions.
+    pub fn from_relation(r: &Relation) -> Vec<OsmRestriction> {
+        if r.tags.get("type").map(smartstring::alias::String::as_ref) != Some("restriction") {
+            return Vec::new();
+        }
+        let kind = match r
+            .tags
+            .get("restriction")
+            .and_then(|t| RestrictionKind::from_tag(t))
+        {
+            Some(k) => k,
+            None => return Vec::new(),
+        };
+
+        let mut from = Vec::new();
+        let mut to = Vec::new();
+        let mut via_node = None;
+        let mut via_ways = Vec::new();
+        for member in &r.refs {
+            match (member.role.as_ref(), member.member) {
+                ("from", OsmId::Way(w)) => from.push(w.0 as OsmWayId),
+                ("to", OsmId::Way(w)) => to.push(w.0 as OsmWayId),
+                ("via", OsmId::Node(n)) => via_node = Some(n.0 as OsmNodeId),
+                ("via", OsmId::Way(w)) => via_ways.push(w.0 as OsmWayId),
+                _ => {}
+            }
+        }
+        let via = match (via_node, via_ways.is_empty()) {
+            (Some(n), true) => OsmVia::Node(n),
+            (None, false) => OsmVia::Ways(via_ways),
+            _ => return Vec::new(),
+        };
+
+        let mut restrictions = Vec::new();
+        for f in &from {
+            for t in &to {
+                restrictions.push(OsmRestriction {
+                    kind,
+                    from: *f,
+                    via: via.clone(),
+                    to: *t,
+                });
+            }
+        }
+        restrictions
+    }
+
+    fn via_node(&self) -> Option<OsmNodeId> {
+        match self.via {
+            OsmVia::Node(n) => Some(n),
+            OsmVia::Ways(_) => None,
+        }
+    }
+
+    fn way_ids(&self) -> Vec<OsmWayId> {
+        let mut ways = vec![self.from, self.to];
+        if let OsmVia::Ways(via) = &self.via {
+            ways.extend(via);
+        }
+        ways
+    }
+}
+
+/// A turn restriction in terms of the extracted graph. `via` holds the
+/// edges between `from` and `to` and is empty for via-node restrictions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TurnRestriction {
+    pub kind: RestrictionKind,
+    pub from: EdgeId,
+    pub via: Vec<EdgeId>,
+    pub to: EdgeId,
+}
+
+impl TurnRestriction {
+    /// Checks that all referenced edges exist and form a connected path.
+    pub fn is_valid(&self, edges: &[Edge]) -> bool {
+        let mut path = vec![self.from];
+        path.extend(&self.via);
+        path.push(self.to);
+        path.iter().all(|e| *e < edges.len())
+            && path
+                .windows(2)
+                .all(|w| edges[w[0]].dest == edges[w[1]].source)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RestrictionError {
+    MissingNode(OsmNodeId),
+    MissingWay(OsmWayId),
+    Disconnected,
+}
+
+/// Maps OSM restrictions onto the edges of the extracted graph. Only the
+/// edges of ways with restrictions are kept.
+pub struct RestrictionResolver {
+    edges: HashMap<EdgeId, Edge>,
+    node_ids: HashMap<OsmNodeId, NodeId>,
+    way_edges: HashMap<OsmWayId, Vec<EdgeId>>,
+}
+
+impl RestrictionResolver {
+    /// `edges` are all edges of the graph in the order of their ids.
+    pub fn new(
+        nodes: &[Node],
+        edges: impl IntoIterator<Item = Edge>,
+        restrictions: &[OsmRestriction],
+    ) -> RestrictionResolver {
+        let wanted_nodes: HashSet<OsmNodeId> =
+            restrictions.iter().filter_map(|r| r.via_node()).collect();
+        let wanted_ways: HashSet<OsmWayId> =
+            restrictions.iter().flat_map(|r| r.way_ids()).collect();
+
+        let node_ids = nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| wanted_nodes.contains(&n.osm_id))
+            .map(|(i, n)| (n.osm_id, i))
+            .collect();
+
+        let mut way_edges: HashMap<OsmWayId, Vec<EdgeId>> = HashMap::new();
+        let mut wanted_edges = HashMap::new();
+        for (i, e) in edges.into_iter().enumerate() {
+            if wanted_ways.contains(&e.osm_way_id) {
+                way_edges.entry(e.osm_way_id).or_default().push(i);
+                wanted_edges.insert(i, e);
+            }
+        }
+
+        RestrictionResolver {
+            edges: wanted_edges,
+            node_ids,
+            way_edges,
+        }
+    }
+
+    pub fn resolve(&self, r: &OsmRestriction) -> Result<Vec<TurnRestriction>, RestrictionError> {
+        let from = self.way(r.from)?;
+        let to = self.way(r.to)?;
+
+        let mut result = Vec::new();
+        match &r.via {
+            OsmVia::Node(osm_id) => {
+                let via = *self
+                    .node_ids
+                    .get(osm_id)
+                    .ok_or(RestrictionError::MissingNode(*osm_id))?;
+                for f in from.iter().filter(|f| self.edges[*f].dest == via) {
+                    for t in to.iter().filter(|t| self.edges[*t].source == via) {
+                        result.push(TurnRestriction {
+                            kind: r.kind,
+                            from: *f,
+                            via: Vec::new(),
+                            to: *t,
+                        });
+                    }
+                }
+            }
+            OsmVia::Ways(ways) => {
+                let mut via_edges = Vec::new();
+                for w in ways {
+                    via_edges.extend(self.way(*w)?);
+                }
+                for f in from {
+                    if let Some((path, t)) = self.via_path(*f, &via_edges, to) {
+                        result.push(TurnRestriction {
+                            kind: r.kind,
+                            from: *f,
+                            via: path,
+                            to: t,
+                        });
+                    }
+                }
+            }
+        }
+        if result.is_empty() {
+            Err(RestrictionError::Disconnected)
+        } else {
+            Ok(result)
+        }
+    }
+
+    fn way(&self, id: OsmWayId) -> Result<&[EdgeId], RestrictionError> {
+        self.way_edges
+            .get(&id)
+            .map(Vec::as_slice)
+            .ok_or(RestrictionError::MissingWay(id))
+    }
+
+    /// Breadth first search along the via edges, starting at the head of
+    /// `from` and ending at the tail of any of the `to` edges.
+    fn via_path(
+        &self,
+        from: EdgeId,
+        via_edges: &[EdgeId],
+        to: &[EdgeId],
+    ) -> Option<(Vec<EdgeId>, EdgeId)> {
+        let start = self.edges[&from].dest;
+        let mut parent: HashMap<NodeId, EdgeId> = HashMap::new();
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::new();
+        visited.insert(start);
+        queue.push_back(start);
+
+        while let Some(node) = queue.pop_front() {
+            if node != start {
+                if let Some(t) = to.iter().find(|t| self.edges[*t].source == node) {
+                    let mut path = Vec::new();
+                    let mut current = node;
+                    while current != start {
+                        let e = parent[&current];
+                        path.push(e);
+                        current = self.edges[&e].source;
+                    }
+                    path.reverse();
+                    return Some((path, *t));
+                }
+            }
+            for e in via_edges.iter().filter(|e| self.edges[*e].source == node) {
+                let dest = self.edges[e].dest;
+                if visited.insert(dest) {
+                    parent.insert(dest, *e);
+                    queue.push_back(dest);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+fn test_graph() -> (Vec<Node>, Vec<Edge>) {
+    // 0 - 1 - 2
+    //     |   |
+    //     3 - 4
+    let nodes = (0..5).map(|i| Node::new(100 + i, 0.0, 0.0, 0.0)).collect();
+    let mut edges = Vec::new();
+    for (s, d, w) in &[(0, 1, 10), (1, 2, 14), (1, 3, 11), (2, 4, 12), (3, 4, 13)] {
+        edges.push(Edge::new(*s, *d, *w));
+        edges.push(Edge::new(*d, *s, *w));
+    }
+    edges.sort_by_key(|e| (e.source, e.dest));
+    (nodes, edges)
+}
+
+#[test]
+fn parse_restriction_relation() {
+    use osmpbfreader::{NodeId as PbfNodeId, Ref, RelationId, Tags, WayId};
+
+    let mut tags = Tags::new();
+    tags.insert("type".into(), "restriction".into());
+    tags.insert("restriction".into(), "no_left_turn".into());
+    let r = Relation {
+        id: RelationId(1),
+        tags,
+        refs: vec![
+            Ref {
+                member: OsmId::Way(WayId(10)),
+                role: "from".into(),
+            },
+            Ref {
+                member: OsmId::Node(PbfNodeId(101)),
+                role: "via".into(),
+            },
+            Ref {
+                member: OsmId::Way(WayId(11)),
+                role: "to".into(),
+            },
+        ],
+    };
+
+    let parsed = OsmRestriction::from_relation(&r);
+    assert_eq!(
+        vec![OsmRestriction {
+            kind: RestrictionKind::NoLeftTurn,
+            from: 10,
+            via: OsmVia::Node(101),
+            to: 11,
+        }],
+        parsed
+    );
+}
+
+#[test]
+fn resolve_via_node() {
+    let (nodes, edges) = test_graph();
+    let r = OsmRestriction {
+        kind: RestrictionKind::NoRightTurn,
+        from: 10,
+        via: OsmVia::Node(101),
+        to: 11,
+    };
+    let resolver =
+        RestrictionResolver::new(&nodes, edges.iter().copied(), std::slice::from_ref(&r));
+    let resolved = resolver.resolve(&r).unwrap();
+
+    assert_eq!(1, resolved.len());
+    let t = &resolved[0];
+    assert!(t.is_valid(&edges));
+    assert_eq!((0, 1), (edges[t.from].source, edges[t.from].dest));
+    assert_eq!((1, 3), (edges[t.to].source, edges[t.to].dest));
+}
+
+#[test]
+fn resolve_via_way() {
+    let (nodes, edges) = test_graph();
+    let r = OsmRestriction {
+        kind: RestrictionKind::OnlyStraightOn,
+        from: 11,
+        via: OsmVia::Ways(vec![13]),
+        to: 12,
+    };
+    let resolver =
+        RestrictionResolver::new(&nodes, edges.iter().copied(), std::slice::from_ref(&r));
+    let resolved = resolver.resolve(&r).unwrap();
+
+    assert_eq!(1, resolved.len());
+    let t = &resolved[0];
+    assert!(t.is_valid(&edges));
+    assert_eq!((1, 3), (edges[t.from].source, edges[t.from].dest));
+    assert_eq!(1, t.via.len());
+    assert_eq!((3, 4), (edges[t.via[0]].source, edges[t.via[0]].dest));
+    assert_eq!((4, 2), (edges[t.to].source, edges[t.to].dest));
+}
+
+#[test]
+fn resolve_missing_members() {
+    let (nodes, edges) = test_graph();
+    let missing_way = OsmRestriction {
+        kind: RestrictionKind::NoUTurn,
+        from: 10,
+        via: OsmVia::Node(101),
+        to: 99,
+    };
+    let missing_node = OsmRestriction {
+        kind: RestrictionKind::NoUTurn,
+        from: 10,
+        via: OsmVia::Node(999),
+        to: 10,
+    };
+    let disconnected = OsmRestriction {
+        kind: RestrictionKind::NoUTurn,
+        from: 10,
+        via: OsmVia::Node(104),
+        to: 13,
+    };
+    let all = [
+        missing_way.clone(),
+        missing_node.clone(),
+        disconnected.clone(),
+    ];
+    let resolver = RestrictionResolver::new(&nodes, edges.iter().copied(), &all);
+
+    assert_eq!(
+        Err(RestrictionError::MissingWay(99)),
+        resolver.resolve(&missing_way)
+    );
+    assert_eq!(
+        Err(RestrictionError::MissingNode(999)),
+        resolver.resolve(&missing_node)
+    );
+    assert_eq!(
+        Err(RestrictionError::Disconnected),
+        resolver.resolve(&disconnected)
+    );
+}
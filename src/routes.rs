@@ -0,0 +1,351 @@
+/*
+Pbfextractor creates graph files for the cycle-routing projects from pbf and srtm data
+Copyright (C) 2018  Florian Barth
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Signposted cycle routes from `type=route` relations.
+//!
+//! The routes a way belongs to are added to its tags as the `icn`, `ncn`,
+//! `rcn` and `lcn` tags mappers also use on the ways themselves, so tag
+//! metrics treat both the same.
+use super::pbf::{OsmRelationId, OsmWayId};
+
+use osmpbfreader::{OsmId, Relation, Tags};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+
+/// Level of the network of a cycle route, from the `network` tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CycleNetwork {
+    International,
+    National,
+    Regional,
+    Local,
+}
+
+impl CycleNetwork {
+    pub const ALL: [CycleNetwork; 4] = [
+        CycleNetwork::International,
+        CycleNetwork::National,
+        CycleNetwork::Regional,
+        CycleNetwork::Local,
+    ];
+
+    pub fn from_tag(tag: &str) -> Option<CycleNetwork> {
+        use CycleNetwork::*;
+        match tag {
+            "icn" => Some(International),
+            "ncn" => Some(National),
+            "rcn" => Some(Regional),
+            "lcn" => Some(Local),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        use CycleNetwork::*;
+        match self {
+            International => "icn",
+            National => "ncn",
+            Regional => "rcn",
+            Local => "lcn",
+        }
+    }
+}
+
+/// A cycle route relation with its member ways and routes. Roles are
+/// ignored, a route only signposted in one direction counts for both.
+///
+/// Long routes are often split into a super-route whose members are the
+/// route relations of its stages, the stages then belong to the network of
+/// the super-route as well. Stages don't need a network of their own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CycleRoute {
+    pub network: Option<CycleNetwork>,
+    pub ways: Vec<OsmWayId>,
+    pub routes: Vec<OsmRelationId>,
+}
+
+impl CycleRoute {
+    /// The cycle route of a `route=bicycle` relation of `type=route` or
+    /// `type=superroute`.
+    pub fn from_relation(r: &Relation) -> Option<CycleRoute> {
+        if !(r.tags.contains("type", "route") || r.tags.contains("type", "superroute"))
+            || !r.tags.contains("route", "bicycle")
+        {
+            return None;
+        }
+        let network = r
+            .tags
+            .get("network")
+            .and_then(|n| CycleNetwork::from_tag(n));
+        let mut ways = Vec::new();
+        let mut routes = Vec::new();
+        for m in &r.refs {
+            match m.member {
+                OsmId::Way(w) => ways.push(w.0 as OsmWayId),
+                OsmId::Relation(r) => routes.push(r.0 as OsmRelationId),
+                OsmId::Node(_) => {}
+            }
+        }
+        Some(CycleRoute {
+            network,
+            ways,
+            routes,
+        })
+    }
+}
+
+pub type CycleRoutes = BTreeMap<OsmRelationId, CycleRoute>;
+
+/// The networks of the cycle routes each way belongs to.
+#[derive(Debug, Default)]
+pub struct RouteMemberships {
+    networks: HashMap<OsmWayId, Vec<CycleNetwork>>,
+}
+
+impl RouteMemberships {
+    pub fn new(routes: &CycleRoutes) -> RouteMemberships {
+        let mut networks: HashMap<OsmWayId, Vec<CycleNetwork>> = HashMap::new();
+        for route in routes.values() {
+            let network = match route.network {
+                Some(network) => network,
+                None => continue,
+            };
+            // the ways of the route and of its stages, super-routes may
+            // contain each other
+            let mut pending = vec![route];
+            let mut visited = HashSet::new();
+            while let Some(route) = pending.pop() {
+                for way in &route.ways {
+                    let way_networks = networks.entry(*way).or_default();
+                    if !way_networks.contains(&network) {
+                        way_networks.push(network);
+                    }
+                }
+                for id in &route.routes {
+                    if visited.insert(*id) {
+                        pending.extend(routes.get(id));
+                    }
+                }
+            }
+        }
+        for way_networks in networks.values_mut() {
+            way_networks.sort();
+        }
+        RouteMemberships { networks }
+    }
+
+    /// The ways whose networks differ between both memberships.
+    pub fn changed_ways(&self, other: &RouteMemberships) -> Vec<OsmWayId> {
+        let mut ways: Vec<_> = self
+            .networks
+            .iter()
+            .filter(|(way, networks)| other.networks.get(way) != Some(networks))
+            .map(|(way, _)| *way)
+            .chain(
+                other
+                    .networks
+                    .keys()
+                    .filter(|way| !self.networks.contains_key(way))
+                    .copied(),
+            )
+            .collect();
+        ways.sort_unstable();
+        ways
+    }
+
+    /// The tags of the way with `<network>=yes` added for every cycle route
+    /// it belongs to, `None` if it doesn't belong to any.
+    pub fn route_tags(&self, way: OsmWayId, tags: &Tags) -> Option<Tags> {
+        let networks = self.networks.get(&way)?;
+        let mut tags = tags.clone();
+        for network in networks {
+            tags.insert(network.as_str().into(), "yes".into());
+        }
+        Some(tags)
+    }
+}
+
+/// Factors of the unsuitability of ways on cycle routes, by the level of
+/// the network. A way on several routes gets the lowest factor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CycleRouteFactors([f64; 4]);
+
+impl Default for CycleRouteFactors {
+    fn default() -> Self {
+        CycleRouteFactors([0.5, 0.5, 0.6, 0.7])
+    }
+}
+
+impl CycleRouteFactors {
+    pub fn get(&self, network: CycleNetwork) -> f64 {
+        self.0[network as usize]
+    }
+
+    /// The factor of a way by its `icn`, `ncn`, `rcn` and `lcn` tags, 1 on
+    /// ways without cycle route.
+    pub fn factor(&self, tags: &Tags) -> f64 {
+        CycleNetwork::ALL
+            .iter()
+            .filter(|n| tags.contains(n.as_str(), "yes"))
+            .map(|n| self.get(*n))
+            .fold(1.0, f64::min)
+    }
+}
+
+/// Parses `NETWORK:FACTOR,...`, e.g. `icn:0.5,lcn:0.8`. Networks not given
+/// keep their default factor.
+impl FromStr for CycleRouteFactors {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut factors = CycleRouteFactors::default();
+        for part in s.split(',') {
+            let (network, factor) = part
+                .split_once(':')
+                .ok_or_else(|| format!("expected NETWORK:FACTOR, got {}", part))?;
+            let network = CycleNetwork::from_tag(network.trim())
+                .ok_or_else(|| format!("unknown cycle network {}", network))?;
+            let factor: f64 = factor
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid factor {}", factor))?;
+            if factor <= 0.0 {
+                return Err(format!("factor {} has to be positive", factor));
+            }
+            factors.0[network as usize] = factor;
+        }
+        Ok(factors)
+    }
+}
+
+#[test]
+fn routes_of_relations() {
+    use osmpbfreader::{NodeId, Ref, RelationId, WayId};
+
+    let relation = |tags: &[(&str, &str)]| Relation {
+        id: RelationId(1),
+        tags: tags
+            .iter()
+            .map(|(k, v)| ((*k).into(), (*v).into()))
+            .collect(),
+        refs: vec![
+            Ref {
+                member: OsmId::Way(WayId(10)),
+                role: "forward".into(),
+            },
+            Ref {
+                member: OsmId::Node(NodeId(1)),
+                role: "guidepost".into(),
+            },
+            Ref {
+                member: OsmId::Way(WayId(11)),
+                role: "".into(),
+            },
+        ],
+    };
+    let route = CycleRoute::from_relation(&relation(&[
+        ("type", "route"),
+        ("route", "bicycle"),
+        ("network", "rcn"),
+    ]));
+    assert_eq!(
+        Some(CycleRoute {
+            network: Some(CycleNetwork::Regional),
+            ways: vec![10, 11],
+            routes: vec![],
+        }),
+        route
+    );
+    assert_eq!(
+        None,
+        CycleRoute::from_relation(&relation(&[
+            ("type", "route"),
+            ("route", "hiking"),
+            ("network", "rwn"),
+        ]))
+    );
+    let stage = CycleRoute::from_relation(&relation(&[("type", "route"), ("route", "bicycle")]));
+    assert_eq!(None, stage.as_ref().unwrap().network);
+
+    let mut routes = CycleRoutes::new();
+    routes.insert(1, route.unwrap());
+    routes.insert(
+        2,
+        CycleRoute {
+            network: Some(CycleNetwork::National),
+            ways: vec![11],
+            routes: vec![],
+        },
+    );
+    routes.insert(
+        3,
+        CycleRoute {
+            ways: vec![13],
+            ..stage.unwrap()
+        },
+    );
+    let before = RouteMemberships::new(&routes);
+    // a super-route containing itself, a stage without network and a
+    // missing relation
+    routes.insert(
+        4,
+        CycleRoute {
+            network: Some(CycleNetwork::International),
+            ways: vec![],
+            routes: vec![4, 3, 5],
+        },
+    );
+    let memberships = RouteMemberships::new(&routes);
+    let tags = Tags::new();
+    assert_eq!(None, memberships.route_tags(12, &tags));
+    let way_tags = memberships.route_tags(11, &tags).unwrap();
+    assert!(way_tags.contains("rcn", "yes"));
+    assert!(way_tags.contains("ncn", "yes"));
+    assert!(!way_tags.contains("lcn", "yes"));
+    assert!(memberships
+        .route_tags(13, &tags)
+        .unwrap()
+        .contains("icn", "yes"));
+    assert_eq!(None, before.route_tags(13, &tags));
+    assert_eq!(vec![13], before.changed_ways(&memberships));
+    assert_eq!(vec![13], memberships.changed_ways(&before));
+}
+
+#[test]
+fn route_factors() {
+    let tags = |pairs: &[(&str, &str)]| -> Tags {
+        pairs
+            .iter()
+            .map(|(k, v)| ((*k).into(), (*v).into()))
+            .collect()
+    };
+    let factors: CycleRouteFactors = "icn:0.4, lcn:0.9".parse().unwrap();
+    assert_eq!(0.4, factors.get(CycleNetwork::International));
+    assert_eq!(0.5, factors.get(CycleNetwork::National));
+    assert_eq!(0.9, factors.get(CycleNetwork::Local));
+    assert_eq!(1.0, factors.factor(&tags(&[("highway", "residential")])));
+    assert_eq!(1.0, factors.factor(&tags(&[("lcn", "proposed")])));
+    assert_eq!(0.9, factors.factor(&tags(&[("lcn", "yes")])));
+    assert_eq!(
+        0.6,
+        factors.factor(&tags(&[("lcn", "yes"), ("rcn", "yes")]))
+    );
+    assert!("xcn:0.5".parse::<CycleRouteFactors>().is_err());
+    assert!("icn".parse::<CycleRouteFactors>().is_err());
+    assert!("icn:0".parse::<CycleRouteFactors>().is_err());
+}
@@ -0,0 +1,209 @@
+/*
+Pbfextractor creates graph files for the cycle-routing projects from pbf and srtm data
+Copyright (C) 2018  Florian Barth
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Writes small pbf files for tests.
+use osmpbfreader::fileformat::{Blob, BlobHeader};
+use osmpbfreader::osmformat::{
+    self, HeaderBlock, PrimitiveBlock, PrimitiveGroup, Relation_MemberType,
+};
+use osmpbfreader::OsmId;
+use protobuf::{Message, RepeatedField};
+
+use byteorder::{BigEndian, WriteBytesExt};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+type TestTags = Vec<(String, String)>;
+type TestMembers = Vec<(OsmId, String)>;
+
+const BLOCK_SIZE: usize = 8000;
+
+#[derive(Default)]
+pub struct PbfWriter {
+    nodes: Vec<(i64, f64, f64, TestTags)>,
+    ways: Vec<(i64, Vec<i64>, TestTags)>,
+    relations: Vec<(i64, TestMembers, TestTags)>,
+}
+
+fn to_tags(tags: &[(&str, &str)]) -> TestTags {
+    tags.iter()
+        .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+        .collect()
+}
+
+/// A path in the temp directory unique to this process and `name`.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("pbfextractor-{}-{}", std::process::id(), name))
+}
+
+impl PbfWriter {
+    pub fn node(&mut self, id: i64, lat: f64, lng: f64, tags: &[(&str, &str)]) -> &mut Self {
+        self.nodes.push((id, lat, lng, to_tags(tags)));
+        self
+    }
+
+    pub fn way(&mut self, id: i64, nodes: &[i64], tags: &[(&str, &str)]) -> &mut Self {
+        self.ways.push((id, nodes.to_vec(), to_tags(tags)));
+        self
+    }
+
+    pub fn relation(
+        &mut self,
+        id: i64,
+        members: &[(OsmId, &str)],
+        tags: &[(&str, &str)],
+    ) -> &mut Self {
+        let members = members.iter().map(|(m, r)| (*m, (*r).to_owned())).collect();
+        self.relations.push((id, members, to_tags(tags)));
+        self
+    }
+
+    pub fn write(&self, path: &Path) {
+        let mut out = BufWriter::new(File::create(path).unwrap());
+
+        let mut header = HeaderBlock::new();
+        header.set_required_features(RepeatedField::from_vec(vec!["OsmSchema-V0.6".to_owned()]));
+        write_blob(&mut out, "OSMHeader", header.write_to_bytes().unwrap());
+
+        for chunk in self.nodes.chunks(BLOCK_SIZE) {
+            let mut block = Block::default();
+            let mut group = PrimitiveGroup::new();
+            for (id, lat, lng, tags) in chunk {
+                let mut node = osmformat::Node::new();
+                node.set_id(*id);
+                node.set_lat((lat * 10_000_000.0).round() as i64);
+                node.set_lon((lng * 10_000_000.0).round() as i64);
+                let (keys, vals) = block.tags(tags);
+                node.set_keys(keys);
+                node.set_vals(vals);
+                group.mut_nodes().push(node);
+            }
+            block.write(&mut out, group);
+        }
+        for chunk in self.ways.chunks(BLOCK_SIZE) {
+            let mut block = Block::default();
+            let mut group = PrimitiveGroup::new();
+            for (id, nodes, tags) in chunk {
+                let mut way = osmformat::Way::new();
+                way.set_id(*id);
+                way.set_refs(delta(nodes.iter().cloned()));
+                let (keys, vals) = block.tags(tags);
+                way.set_keys(keys);
+                way.set_vals(vals);
+                group.mut_ways().push(way);
+            }
+            block.write(&mut out, group);
+        }
+        for chunk in self.relations.chunks(BLOCK_SIZE) {
+            let mut block = Block::default();
+            let mut group = PrimitiveGroup::new();
+            for (id, members, tags) in chunk {
+                let mut relation = osmformat::Relation::new();
+                relation.set_id(*id);
+                relation.set_memids(delta(members.iter().map(|(m, _)| m.inner_id())));
+                relation.set_types(
+                    members
+                        .iter()
+                        .map(|(m, _)| match m {
+                            OsmId::Node(_) => Relation_MemberType::NODE,
+                            OsmId::Way(_) => Relation_MemberType::WAY,
+                            OsmId::Relation(_) => Relation_MemberType::RELATION,
+                        })
+                        .collect(),
+                );
+                relation.set_roles_sid(
+                    members
+                        .iter()
+                        .map(|(_, r)| block.string(r) as i32)
+                        .collect(),
+                );
+                let (keys, vals) = block.tags(tags);
+                relation.set_keys(keys);
+                relation.set_vals(vals);
+                group.mut_relations().push(relation);
+            }
+            block.write(&mut out, group);
+        }
+        out.flush().unwrap();
+    }
+}
+
+fn delta<I: Iterator<Item = i64>>(ids: I) -> Vec<i64> {
+    let mut last = 0;
+    ids.map(|id| {
+        let d = id - last;
+        last = id;
+        d
+    })
+    .collect()
+}
+
+#[derive(Default)]
+struct Block {
+    strings: Vec<String>,
+    indices: HashMap<String, u32>,
+}
+
+impl Block {
+    fn string(&mut self, s: &str) -> u32 {
+        if self.strings.is_empty() {
+            // index 0 is reserved as delimiter
+            self.strings.push(String::new());
+        }
+        if let Some(i) = self.indices.get(s) {
+            return *i;
+        }
+        let i = self.strings.len() as u32;
+        self.strings.push(s.to_owned());
+        self.indices.insert(s.to_owned(), i);
+        i
+    }
+
+    fn tags(&mut self, tags: &[(String, String)]) -> (Vec<u32>, Vec<u32>) {
+        tags.iter()
+            .map(|(k, v)| (self.string(k), self.string(v)))
+            .unzip()
+    }
+
+    fn write<W: Write>(mut self, out: &mut W, group: PrimitiveGroup) {
+        self.string("");
+        let mut block = PrimitiveBlock::new();
+        block
+            .mut_stringtable()
+            .set_s(self.strings.into_iter().map(String::into_bytes).collect());
+        block.mut_primitivegroup().push(group);
+        write_blob(out, "OSMData", block.write_to_bytes().unwrap());
+    }
+}
+
+fn write_blob<W: Write>(out: &mut W, blob_type: &str, data: Vec<u8>) {
+    let mut blob = Blob::new();
+    blob.set_raw_size(data.len() as i32);
+    blob.set_raw(data);
+    let blob = blob.write_to_bytes().unwrap();
+
+    let mut header = BlobHeader::new();
+    header.set_field_type(blob_type.to_owned());
+    header.set_datasize(blob.len() as i32);
+    let header = header.write_to_bytes().unwrap();
+
+    out.write_u32::<BigEndian>(header.len() as u32).unwrap();
+    out.write_all(&header).unwrap();
+    out.write_all(&blob).unwrap();
+}
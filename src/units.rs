@@ -22,16 +22,35 @@ use std::ops::{Div, Mul};
 pub struct Meters(pub f64);
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub struct Kilometers(pub f64);
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Feet(pub f64);
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Inches(pub f64);
 
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub struct Seconds(pub f64);
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub struct Hours(pub f64);
 
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Kilograms(pub f64);
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Tonnes(pub f64);
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct ShortTons(pub f64);
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Pounds(pub f64);
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Watts(pub f64);
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub struct MetersPerSecond(pub f64);
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub struct KilometersPerHour(pub f64);
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct MilesPerHour(pub f64);
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Knots(pub f64);
 
 impl MetersPerSecond {
     pub fn new(m: Meters, s: Seconds) -> MetersPerSecond {
@@ -45,6 +64,36 @@ impl From<Kilometers> for Meters {
     }
 }
 
+impl From<Feet> for Meters {
+    fn from(ft: Feet) -> Meters {
+        Meters(ft.0 * 0.3048)
+    }
+}
+
+impl From<Inches> for Meters {
+    fn from(inch: Inches) -> Meters {
+        Meters(inch.0 * 0.0254)
+    }
+}
+
+impl From<Tonnes> for Kilograms {
+    fn from(t: Tonnes) -> Kilograms {
+        Kilograms(t.0 * 1000.0)
+    }
+}
+
+impl From<ShortTons> for Kilograms {
+    fn from(st: ShortTons) -> Kilograms {
+        Kilograms(st.0 * 907.184_74)
+    }
+}
+
+impl From<Pounds> for Kilograms {
+    fn from(lbs: Pounds) -> Kilograms {
+        Kilograms(lbs.0 * 0.453_592_37)
+    }
+}
+
 impl From<Hours> for Seconds {
     fn from(h: Hours) -> Self {
         Seconds(h.0 * 3600.0)
@@ -63,6 +112,18 @@ impl From<KilometersPerHour> for MetersPerSecond {
     }
 }
 
+impl From<MilesPerHour> for KilometersPerHour {
+    fn from(mph: MilesPerHour) -> KilometersPerHour {
+        KilometersPerHour(mph.0 * 1.609_344)
+    }
+}
+
+impl From<Knots> for KilometersPerHour {
+    fn from(kn: Knots) -> KilometersPerHour {
+        KilometersPerHour(kn.0 * 1.852)
+    }
+}
+
 impl Div<MetersPerSecond> for Meters {
     type Output = Seconds;
     fn div(self, mps: MetersPerSecond) -> Self::Output {
@@ -90,6 +151,24 @@ fn test_kmh_to_ms_conversion() {
     assert_eq!(50.0, ms.0);
 }
 
+#[test]
+fn test_mph_and_knots_to_kmh_conversion() {
+    let kmh = KilometersPerHour::from(MilesPerHour(50.0));
+    assert!((80.4672 - kmh.0).abs() < 1e-9);
+
+    let kmh = KilometersPerHour::from(Knots(10.0));
+    assert!((18.52 - kmh.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_imperial_lengths_and_weights() {
+    assert!((3.81 - Meters::from(Feet(12.5)).0).abs() < 1e-9);
+    assert!((0.1524 - Meters::from(Inches(6.0)).0).abs() < 1e-9);
+    assert_eq!(Kilograms(7500.0), Kilograms::from(Tonnes(7.5)));
+    assert!((9071.8474 - Kilograms::from(ShortTons(10.0)).0).abs() < 1e-9);
+    assert!((4535.9237 - Kilograms::from(Pounds(10_000.0)).0).abs() < 1e-9);
+}
+
 #[test]
 fn test_meters_div_ms() {
     let m = Meters(10.0);
@@ -0,0 +1,852 @@
+/*
+Pbfextractor creates graph files for the cycle-routing projects from pbf and srtm data
+Copyright (C) 2018  Florian Barth
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Incremental updates of extracted graphs with OSM change files.
+//!
+//! An extraction run with `--sidecar` stores the `Extract` the graph was
+//! built from. Applying a change file to it and building the graph again
+//! gives the same graph as extracting the updated pbf file, as long as every
+//! node the changed ways refer to was part of a way before or is contained
+//! in the change file. Edges with other nodes are left out with a warning,
+//! the sidecar only stores nodes of ways. If a tag metric uses cycle routes,
+//! the sidecar also stores the tags of the ways with edges, so ways whose
+//! routes changed are processed again even if they are not part of the
+//! change file.
+use super::edge_store::Edge;
+use super::metrics::EdgeFilter;
+use super::pbf::{Extract, Loader, MetricIndices, Node, OsmNodeId, OsmRelationId, OsmWayId};
+use super::restrictions::{OsmRestriction, OsmVia, RestrictionKind};
+use super::routes::{CycleNetwork, CycleRoute, RouteMemberships};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::{info, warn};
+use osmpbfreader::{NodeId, OsmId, OsmObj, Ref, Relation, RelationId, Tags, Way, WayId};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+const MAGIC: &[u8; 8] = b"PBFXSIDE";
+const VERSION: u32 = 4;
+
+#[derive(Debug)]
+pub enum UpdateError {
+    Io(io::Error),
+    Xml(String),
+    InvalidSidecar(String),
+}
+
+impl From<io::Error> for UpdateError {
+    fn from(e: io::Error) -> Self {
+        UpdateError::Io(e)
+    }
+}
+
+impl std::fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            UpdateError::Io(e) => write!(f, "io error: {}", e),
+            UpdateError::Xml(e) => write!(f, "invalid change file: {}", e),
+            UpdateError::InvalidSidecar(e) => write!(f, "invalid sidecar: {}", e),
+        }
+    }
+}
+
+pub type UpdateResult<T> = Result<T, UpdateError>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Create,
+    Modify,
+    Delete,
+}
+
+/// Reads an `.osc` file, gzipped if the path ends with `.gz`.
+pub fn read_change_file(path: &str) -> UpdateResult<Vec<(Action, OsmObj)>> {
+    let f = File::open(path)?;
+    if path.ends_with(".gz") {
+        parse_changes(BufReader::new(flate2::read::GzDecoder::new(f)))
+    } else {
+        parse_changes(BufReader::new(f))
+    }
+}
+
+/// Parses the osmChange xml format into the objects of each action in file
+/// order.
+pub fn parse_changes<R: BufRead>(input: R) -> UpdateResult<Vec<(Action, OsmObj)>> {
+    let mut reader = Reader::from_reader(input);
+    let mut buf = Vec::new();
+    let mut changes = Vec::new();
+    let mut action = None;
+    let mut current: Option<OsmObj> = None;
+
+    loop {
+        let event = reader
+            .read_event_into(&mut buf)
+            .map_err(|e| UpdateError::Xml(e.to_string()))?;
+        let start = match &event {
+            Event::Start(e) | Event::Empty(e) => Some(e),
+            _ => None,
+        };
+        if let Some(e) = start {
+            match e.name().as_ref() {
+                "create" => action = Some(Action::Create),
+                "modify" => action = Some(Action::Modify),
+                "delete" => action = Some(Action::Delete),
+                "node" => current = Some(OsmObj::Node(parse_node(e)?)),
+                "way" => {
+                    current = Some(OsmObj::Way(Way {
+                        id: WayId(attribute(e, "id")?),
+                        tags: Tags::new(),
+                        nodes: Vec::new(),
+                    }))
+                }
+                "relation" => {
+                    current = Some(OsmObj::Relation(Relation {
+                        id: RelationId(attribute(e, "id")?),
+                        tags: Tags::new(),
+                        refs: Vec::new(),
+                    }))
+                }
+                "tag" => {
+                    let k: String = attribute(e, "k")?;
+                    let v: String = attribute(e, "v")?;
+                    match current.as_mut() {
+                        Some(OsmObj::Node(n)) => n.tags.insert(k.into(), v.into()),
+                        Some(OsmObj::Way(w)) => w.tags.insert(k.into(), v.into()),
+                        Some(OsmObj::Relation(r)) => r.tags.insert(k.into(), v.into()),
+                        None => None,
+                    };
+                }
+                "nd" => {
+                    if let Some(OsmObj::Way(w)) = current.as_mut() {
+                        w.nodes.push(NodeId(attribute(e, "ref")?));
+                    }
+                }
+                "member" => {
+                    if let Some(OsmObj::Relation(r)) = current.as_mut() {
+                        r.refs.push(parse_member(e)?);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let finished = match &event {
+            Event::End(e) => matches!(e.name().as_ref(), "node" | "way" | "relation"),
+            Event::Empty(e) => matches!(e.name().as_ref(), "node" | "way" | "relation"),
+            Event::Eof => break,
+            _ => false,
+        };
+        if finished {
+            let obj = current
+                .take()
+                .ok_or_else(|| UpdateError::Xml("unexpected end tag".to_owned()))?;
+            let action =
+                action.ok_or_else(|| UpdateError::Xml("object outside of an action".to_owned()))?;
+            changes.push((action, obj));
+        }
+        buf.clear();
+    }
+    Ok(changes)
+}
+
+fn attribute<T: std::str::FromStr>(e: &BytesStart, name: &str) -> UpdateResult<T> {
+    for a in e.attributes() {
+        let a = a.map_err(|e| UpdateError::Xml(e.to_string()))?;
+        if a.key.as_ref() == name {
+            let value = a
+                .normalized_value(quick_xml::XmlVersion::Implicit1_0)
+                .map_err(|e| UpdateError::Xml(e.to_string()))?;
+            return value
+                .parse()
+                .map_err(|_| UpdateError::Xml(format!("invalid value for {}: {}", name, value)));
+        }
+    }
+    Err(UpdateError::Xml(format!("missing attribute {}", name)))
+}
+
+fn parse_node(e: &BytesStart) -> UpdateResult<osmpbfreader::Node> {
+    // deleted nodes may come without coordinates
+    let lat: f64 = attribute(e, "lat").unwrap_or(0.0);
+    let lon: f64 = attribute(e, "lon").unwrap_or(0.0);
+    Ok(osmpbfreader::Node {
+        id: NodeId(attribute(e, "id")?),
+        tags: Tags::new(),
+        decimicro_lat: (lat * 10_000_000.0).round() as i32,
+        decimicro_lon: (lon * 10_000_000.0).round() as i32,
+    })
+}
+
+fn parse_member(e: &BytesStart) -> UpdateResult<Ref> {
+    let id: i64 = attribute(e, "ref")?;
+    let member_type: String = attribute(e, "type")?;
+    let member = match member_type.as_ref() {
+        "node" => OsmId::Node(NodeId(id)),
+        "way" => OsmId::Way(WayId(id)),
+        "relation" => OsmId::Relation(RelationId(id)),
+        t => return Err(UpdateError::Xml(format!("unknown member type {}", t))),
+    };
+    let role: String = attribute(e, "role")?;
+    Ok(Ref {
+        member,
+        role: role.into(),
+    })
+}
+
+impl<'a, Filter: EdgeFilter> Loader<'a, Filter> {
+    /// Applies the changes to the extract. Changed ways run through the edge
+    /// filter and tag metrics again, changed nodes through the node tag
+    /// metrics.
+    pub fn apply_changes(&self, extract: &mut Extract, changes: Vec<(Action, OsmObj)>) {
+        let mut nodes: HashMap<OsmNodeId, Option<osmpbfreader::Node>> = HashMap::new();
+        let mut ways: BTreeMap<OsmWayId, Option<Way>> = BTreeMap::new();
+        let old_memberships = if self.uses_cycle_routes() {
+            Some(RouteMemberships::new(&extract.cycle_routes))
+        } else {
+            None
+        };
+        let applying = self.report().phase("applying changes", "objects");
+        for (action, obj) in changes {
+            applying.inc();
+            match obj {
+                OsmObj::Node(n) => {
+                    let node = if action == Action::Delete {
+                        None
+                    } else {
+                        Some(n.clone())
+                    };
+                    nodes.insert(n.id.0 as OsmNodeId, node);
+                }
+                OsmObj::Way(w) => {
+                    let id = w.id.0 as OsmWayId;
+                    ways.insert(
+                        id,
+                        if action == Action::Delete {
+                            None
+                        } else {
+                            Some(w)
+                        },
+                    );
+                }
+                OsmObj::Relation(r) => {
+                    let id = r.id.0 as OsmRelationId;
+                    extract.restrictions.remove(&id);
+                    extract.cycle_routes.remove(&id);
+                    if action != Action::Delete {
+                        let restrictions = OsmRestriction::from_relation(&r);
+                        if !restrictions.is_empty() {
+                            extract.restrictions.insert(id, restrictions);
+                        }
+                        if let Some(route) = CycleRoute::from_relation(&r) {
+                            if self.uses_cycle_routes() {
+                                extract.cycle_routes.insert(id, route);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let memberships = RouteMemberships::new(&extract.cycle_routes);
+        if let Some(old_memberships) = old_memberships {
+            // ways whose routes changed are processed again with their
+            // stored tags, ways without edges are not affected
+            for id in old_memberships.changed_ways(&memberships) {
+                if let Some(w) = extract.route_ways.get(&id) {
+                    ways.entry(id).or_insert_with(|| Some(w.clone()));
+                }
+            }
+        }
+        info!(
+            "Applying changes to {} nodes and {} ways",
+            nodes.len(),
+            ways.len()
+        );
+
+        // like a full extraction only nodes of ways are kept, so nodes that
+        // are not part of a way, e.g. points of interest, don't pile up
+        let referenced: HashSet<OsmNodeId> = ways
+            .values()
+            .flatten()
+            .flat_map(|w| &w.nodes)
+            .map(|n| n.0 as OsmNodeId)
+            .collect();
+        let mut missing = referenced.clone();
+        let mut known = HashSet::new();
+        extract.nodes.retain(|n| {
+            missing.remove(&n.osm_id);
+            if nodes.contains_key(&n.osm_id) {
+                known.insert(n.osm_id);
+                false
+            } else {
+                true
+            }
+        });
+        for (id, node) in nodes {
+            extract.node_tag_costs.remove(&id);
+            extract.blocked.remove(&id);
+            match node {
+                Some(node) if known.contains(&id) || referenced.contains(&id) => {
+                    missing.remove(&id);
+                    self.add_node(&node, extract);
+                }
+                _ => {}
+            }
+        }
+
+        extract.edges.retain(|e| !ways.contains_key(&e.osm_way_id));
+        let mut edges = self.new_edge_store();
+        for (id, way) in &ways {
+            extract.way_nodes.remove(id);
+            extract.route_ways.remove(id);
+            if let Some(w) = way {
+                let before = edges.len();
+                self.process_way(w, &memberships, &mut edges);
+                if edges.len() > before {
+                    self.add_way_nodes(w, &mut extract.way_nodes);
+                    self.add_route_way(w, &mut extract.route_ways);
+                }
+            }
+        }
+        let unknown = edges
+            .iter()
+            .filter(|e| missing.contains(&e.source) || missing.contains(&e.dest))
+            .count();
+        if unknown > 0 {
+            warn!(
+                "{} edges of changed ways use nodes that are neither in the sidecar nor in the change file \
+                 and are left out, a full extraction of the updated data would contain them",
+                unknown
+            );
+        }
+        extract.edges.append(&mut edges);
+        drop(applying);
+    }
+
+    /// Writes the extract together with the metric indices it was built
+    /// with.
+    pub fn write_sidecar<W: Write>(&self, extract: &Extract, out: W) -> io::Result<()> {
+        let mut out = BufWriter::new(out);
+        out.write_all(MAGIC)?;
+        out.write_u32::<LittleEndian>(VERSION)?;
+
+        write_len(&mut out, self.metrics_indices.len())?;
+        for (name, index) in &self.metrics_indices {
+            write_str(&mut out, name)?;
+            write_len(&mut out, *index)?;
+        }
+
+        write_len(&mut out, extract.nodes.len())?;
+        for n in &extract.nodes {
+            write_len(&mut out, n.osm_id)?;
+            out.write_f64::<LittleEndian>(n.lat)?;
+            out.write_f64::<LittleEndian>(n.long)?;
+        }
+
+        write_len(&mut out, extract.edges.len())?;
+        for e in extract.edges.iter() {
+            write_len(&mut out, e.source)?;
+            write_len(&mut out, e.dest)?;
+            write_len(&mut out, e.osm_way_id)?;
+            for c in 0..extract.edges.cost_count() {
+                out.write_f64::<LittleEndian>(e.cost(c))?;
+            }
+        }
+
+        write_len(&mut out, extract.node_tag_costs.len())?;
+        for (id, costs) in &extract.node_tag_costs {
+            write_len(&mut out, *id)?;
+            write_len(&mut out, costs.len())?;
+            for (index, value) in costs {
+                write_len(&mut out, *index)?;
+                out.write_f64::<LittleEndian>(*value)?;
+            }
+        }
+
+        write_len(&mut out, extract.blocked.len())?;
+        for id in &extract.blocked {
+            write_len(&mut out, *id)?;
+        }
+
+        write_len(&mut out, extract.restrictions.len())?;
+        for (id, restrictions) in &extract.restrictions {
+            write_len(&mut out, *id)?;
+            write_len(&mut out, restrictions.len())?;
+            for r in restrictions {
+                write_str(&mut out, r.kind.as_str())?;
+                write_len(&mut out, r.from)?;
+                write_len(&mut out, r.to)?;
+                match &r.via {
+                    OsmVia::Node(n) => {
+                        out.write_u8(0)?;
+                        write_len(&mut out, *n)?;
+                    }
+                    OsmVia::Ways(ways) => {
+                        out.write_u8(1)?;
+                        write_len(&mut out, ways.len())?;
+                        for w in ways {
+                            write_len(&mut out, *w)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        write_len(&mut out, extract.cycle_routes.len())?;
+        for (id, route) in &extract.cycle_routes {
+            write_len(&mut out, *id)?;
+            write_str(&mut out, route.network.map_or("", CycleNetwork::as_str))?;
+            write_len(&mut out, route.ways.len())?;
+            for w in &route.ways {
+                write_len(&mut out, *w)?;
+            }
+            write_len(&mut out, route.routes.len())?;
+            for r in &route.routes {
+                write_len(&mut out, *r)?;
+            }
+        }
+
+        write_len(&mut out, extract.way_nodes.len())?;
+        for (id, nodes) in &extract.way_nodes {
+            write_len(&mut out, *id)?;
+            write_len(&mut out, nodes.len())?;
+            for n in nodes {
+                write_len(&mut out, *n)?;
+            }
+        }
+
+        write_len(&mut out, extract.route_ways.len())?;
+        for (id, w) in &extract.route_ways {
+            write_len(&mut out, *id)?;
+            write_len(&mut out, w.tags.len())?;
+            for (key, value) in w.tags.iter() {
+                write_str(&mut out, key)?;
+                write_str(&mut out, value)?;
+            }
+            write_len(&mut out, w.nodes.len())?;
+            for n in &w.nodes {
+                write_len(&mut out, n.0 as OsmNodeId)?;
+            }
+        }
+        out.flush()
+    }
+
+    /// Reads an extract written by `write_sidecar`. The metrics of the loader
+    /// have to be the same as when the sidecar was written.
+    pub fn read_sidecar<R: Read>(&self, input: R) -> UpdateResult<Extract> {
+        let mut input = BufReader::new(input);
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC || input.read_u32::<LittleEndian>()? != VERSION {
+            return Err(UpdateError::InvalidSidecar(
+                "unknown file format".to_owned(),
+            ));
+        }
+
+        let mut indices = MetricIndices::new();
+        for _ in 0..read_len(&mut input)? {
+            let name = read_str(&mut input)?;
+            indices.insert(name, read_len(&mut input)?);
+        }
+        if indices != self.metrics_indices {
+            return Err(UpdateError::InvalidSidecar(format!(
+                "sidecar was written with the metrics {:?}",
+                indices.keys().collect::<Vec<_>>()
+            )));
+        }
+
+        let mut nodes = Vec::new();
+        for _ in 0..read_len(&mut input)? {
+            let osm_id = read_len(&mut input)?;
+            let lat = input.read_f64::<LittleEndian>()?;
+            let long = input.read_f64::<LittleEndian>()?;
+            nodes.push(Node::new(osm_id, lat, long, 0.0));
+        }
+
+        let mut edges = self.new_edge_store();
+        let mut costs = vec![0.0; indices.len()];
+        for _ in 0..read_len(&mut input)? {
+            let source = read_len(&mut input)?;
+            let dest = read_len(&mut input)?;
+            let way = read_len(&mut input)?;
+            for c in costs.iter_mut() {
+                *c = input.read_f64::<LittleEndian>()?;
+            }
+            edges.push(Edge::new(source, dest, way), &costs);
+        }
+
+        let mut node_tag_costs = HashMap::new();
+        for _ in 0..read_len(&mut input)? {
+            let id = read_len(&mut input)?;
+            let mut costs = Vec::new();
+            for _ in 0..read_len(&mut input)? {
+                let index = read_len(&mut input)?;
+                costs.push((index, input.read_f64::<LittleEndian>()?));
+            }
+            node_tag_costs.insert(id, costs);
+        }
+
+        let mut blocked = HashSet::new();
+        for _ in 0..read_len(&mut input)? {
+            blocked.insert(read_len(&mut input)?);
+        }
+
+        let mut restrictions = BTreeMap::new();
+        for _ in 0..read_len(&mut input)? {
+            let id = read_len(&mut input)?;
+            let mut relation = Vec::new();
+            for _ in 0..read_len(&mut input)? {
+                let kind = read_str(&mut input)?;
+                let kind = RestrictionKind::from_tag(&kind).ok_or_else(|| {
+                    UpdateError::InvalidSidecar(format!("unknown restriction {}", kind))
+                })?;
+                let from = read_len(&mut input)?;
+                let to = read_len(&mut input)?;
+                let via = match input.read_u8()? {
+                    0 => OsmVia::Node(read_len(&mut input)?),
+                    _ => {
+                        let mut ways = Vec::new();
+                        for _ in 0..read_len(&mut input)? {
+                            ways.push(read_len(&mut input)?);
+                        }
+                        OsmVia::Ways(ways)
+                    }
+                };
+                relation.push(OsmRestriction {
+                    kind,
+                    from,
+                    via,
+                    to,
+                });
+            }
+            restrictions.insert(id, relation);
+        }
+
+        let mut cycle_routes = BTreeMap::new();
+        for _ in 0..read_len(&mut input)? {
+            let id = read_len(&mut input)?;
+            let network = match read_str(&mut input)?.as_ref() {
+                "" => None,
+                network => Some(CycleNetwork::from_tag(network).ok_or_else(|| {
+                    UpdateError::InvalidSidecar(format!("unknown cycle network {}", network))
+                })?),
+            };
+            let mut ways = Vec::new();
+            for _ in 0..read_len(&mut input)? {
+                ways.push(read_len(&mut input)?);
+            }
+            let mut routes = Vec::new();
+            for _ in 0..read_len(&mut input)? {
+                routes.push(read_len(&mut input)?);
+            }
+            cycle_routes.insert(
+                id,
+                CycleRoute {
+                    network,
+                    ways,
+                    routes,
+                },
+            );
+        }
+
+        let mut way_nodes = HashMap::new();
+        for _ in 0..read_len(&mut input)? {
+            let id = read_len(&mut input)?;
+            let mut nodes = Vec::new();
+            for _ in 0..read_len(&mut input)? {
+                nodes.push(read_len(&mut input)?);
+            }
+            way_nodes.insert(id, nodes);
+        }
+
+        let mut route_ways = HashMap::new();
+        for _ in 0..read_len(&mut input)? {
+            let id = read_len(&mut input)?;
+            let mut tags = Tags::new();
+            for _ in 0..read_len(&mut input)? {
+                let key = read_str(&mut input)?;
+                tags.insert(key.into(), read_str(&mut input)?.into());
+            }
+            let mut nodes = Vec::new();
+            for _ in 0..read_len(&mut input)? {
+                nodes.push(NodeId(read_len(&mut input)? as i64));
+            }
+            route_ways.insert(
+                id,
+                Way {
+                    id: WayId(id as i64),
+                    tags,
+                    nodes,
+                },
+            );
+        }
+
+        Ok(Extract {
+            nodes,
+            edges,
+            node_tag_costs,
+            blocked,
+            restrictions,
+            cycle_routes,
+            way_nodes,
+            route_ways,
+            edge_runs: None,
+            spilled_way_nodes: None,
+        })
+    }
+}
+
+fn write_len<W: Write>(out: &mut W, v: usize) -> io::Result<()> {
+    out.write_u64::<LittleEndian>(v as u64)
+}
+
+fn read_len<R: Read>(input: &mut R) -> io::Result<usize> {
+    input.read_u64::<LittleEndian>().map(|v| v as usize)
+}
+
+fn write_str<W: Write>(out: &mut W, s: &str) -> io::Result<()> {
+    write_len(out, s.len())?;
+    out.write_all(s.as_bytes())
+}
+
+fn read_str<R: Read>(input: &mut R) -> UpdateResult<String> {
+    let mut bytes = vec![0; read_len(input)?];
+    input.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| UpdateError::InvalidSidecar(e.to_string()))
+}
+
+#[test]
+fn parse_osc() {
+    let osc = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osmChange version="0.6" generator="test">
+  <create>
+    <node id="5" version="1" lat="50.0040000" lon="10.0000000"/>
+    <way id="12" version="1">
+      <nd ref="4"/>
+      <nd ref="5"/>
+      <tag k="highway" v="primary"/>
+    </way>
+  </create>
+  <modify>
+    <node id="2" version="2" lat="50.0010000" lon="10.0005000">
+      <tag k="highway" v="traffic_signals"/>
+    </node>
+    <relation id="20" version="2">
+      <member type="way" ref="10" role="from"/>
+      <member type="node" ref="2" role="via"/>
+      <member type="way" ref="12" role="to"/>
+      <tag k="type" v="restriction"/>
+      <tag k="restriction" v="no_left_turn"/>
+    </relation>
+  </modify>
+  <delete>
+    <way id="11" version="3"/>
+  </delete>
+</osmChange>
+"#;
+    let changes = parse_changes(osc.as_bytes()).unwrap();
+    assert_eq!(5, changes.len());
+
+    match &changes[0] {
+        (Action::Create, OsmObj::Node(n)) => {
+            assert_eq!(5, n.id.0);
+            assert_eq!(500_040_000, n.decimicro_lat);
+        }
+        c => panic!("unexpected change {:?}", c),
+    }
+    match &changes[1] {
+        (Action::Create, OsmObj::Way(w)) => {
+            assert_eq!(vec![NodeId(4), NodeId(5)], w.nodes);
+            assert!(w.tags.contains("highway", "primary"));
+        }
+        c => panic!("unexpected change {:?}", c),
+    }
+    match &changes[2] {
+        (Action::Modify, OsmObj::Node(n)) => {
+            assert!(n.tags.contains("highway", "traffic_signals"));
+            assert_eq!(100_005_000, n.decimicro_lon);
+        }
+        c => panic!("unexpected change {:?}", c),
+    }
+    match &changes[3] {
+        (Action::Modify, OsmObj::Relation(r)) => {
+            assert_eq!(1, OsmRestriction::from_relation(r).len());
+        }
+        c => panic!("unexpected change {:?}", c),
+    }
+    match &changes[4] {
+        (Action::Delete, OsmObj::Way(w)) => assert_eq!(11, w.id.0),
+        c => panic!("unexpected change {:?}", c),
+    }
+}
+
+#[test]
+fn update_equals_full_extraction() {
+    use super::metrics::{CarEdgeFilter, Distance, EdgeCount, TrafficSignalDelay};
+    use super::pbf::{Graph, InternalMetrics, NodeMetrics, NodeTagMetrics, TagMetrics};
+    use super::test_pbf::{temp_path, PbfWriter};
+    use std::sync::Arc;
+
+    let loader = |path| {
+        let tag_metrics: TagMetrics = vec![Arc::new(EdgeCount)];
+        let node_metrics: NodeMetrics = vec![Arc::new(Distance)];
+        let node_tag_metrics: NodeTagMetrics = vec![Arc::new(TrafficSignalDelay)];
+        Loader::new(
+            vec![path],
+            "",
+            CarEdgeFilter,
+            tag_metrics,
+            node_metrics,
+            node_tag_metrics,
+            vec![],
+            InternalMetrics::new(),
+        )
+    };
+    let road = [("highway", "residential")];
+    let footway = [("highway", "footway")];
+    let restriction = [("type", "restriction"), ("restriction", "no_left_turn")];
+    let members = [
+        (OsmId::Way(WayId(10)), "from"),
+        (OsmId::Node(NodeId(2)), "via"),
+        (OsmId::Way(WayId(12)), "to"),
+    ];
+
+    let old = temp_path("update-old.pbf");
+    PbfWriter::default()
+        .node(1, 50.0, 10.0, &[])
+        .node(2, 50.001, 10.0, &[])
+        .node(3, 50.002, 10.0, &[])
+        .node(4, 50.003, 10.0, &[])
+        .way(10, &[1, 2], &road)
+        .way(11, &[2, 3], &road)
+        .way(12, &[2, 4], &footway)
+        .write(&old);
+
+    // the same data with the changes of the osc below applied
+    let new = temp_path("update-new.pbf");
+    PbfWriter::default()
+        .node(1, 50.0, 10.0, &[])
+        .node(2, 50.001, 10.0005, &[("highway", "traffic_signals")])
+        .node(3, 50.002, 10.0, &[])
+        .node(4, 50.003, 10.0, &[])
+        .node(5, 50.004, 10.0, &[])
+        .way(10, &[1, 2], &road)
+        .way(12, &[2, 4], &[("highway", "primary")])
+        .way(13, &[4, 5], &road)
+        .relation(20, &members, &restriction)
+        .write(&new);
+    let osc = r#"<osmChange version="0.6">
+  <create>
+    <node id="5" lat="50.004" lon="10.0"/>
+    <node id="6" lat="50.005" lon="10.0"><tag k="amenity" v="cafe"/></node>
+    <way id="13"><nd ref="4"/><nd ref="5"/><tag k="highway" v="residential"/></way>
+    <relation id="20">
+      <member type="way" ref="10" role="from"/>
+      <member type="node" ref="2" role="via"/>
+      <member type="way" ref="12" role="to"/>
+      <tag k="type" v="restriction"/>
+      <tag k="restriction" v="no_left_turn"/>
+    </relation>
+  </create>
+  <modify>
+    <node id="2" lat="50.001" lon="10.0005"><tag k="highway" v="traffic_signals"/></node>
+    <way id="12"><nd ref="2"/><nd ref="4"/><tag k="highway" v="primary"/></way>
+  </modify>
+  <delete>
+    <way id="11"/>
+  </delete>
+</osmChange>"#;
+
+    let full_loader = loader(new.to_str().unwrap());
+    let full = full_loader.build_graph(full_loader.read_extract().unwrap());
+
+    let mut old_loader = loader(old.to_str().unwrap());
+    old_loader.set_updatable();
+    let mut sidecar = Vec::new();
+    old_loader
+        .write_sidecar(&old_loader.read_extract().unwrap(), &mut sidecar)
+        .unwrap();
+    let mut extract = old_loader.read_sidecar(sidecar.as_slice()).unwrap();
+    old_loader.apply_changes(&mut extract, parse_changes(osc.as_bytes()).unwrap());
+    // the cafe is not part of a way
+    assert!(extract.nodes.iter().all(|n| n.osm_id != 6));
+    let updated = old_loader.build_graph(extract);
+
+    let summary = |g: &Graph| {
+        let nodes: Vec<_> = g
+            .nodes
+            .iter()
+            .map(|n| format!("{} {} {} {}", n.osm_id, n.lat, n.long, n.height))
+            .collect();
+        let edges: Vec<_> = g
+            .edges
+            .iter()
+            .map(|e| format!("{} {} {:?}", e.source, e.dest, e.internal_costs()))
+            .collect();
+        (nodes, edges, g.restrictions.clone())
+    };
+    assert_eq!(4, full.nodes.len());
+    assert_eq!(1, full.restrictions.len());
+    assert_eq!(summary(&full), summary(&updated));
+
+    std::fs::remove_file(old).unwrap();
+    std::fs::remove_file(new).unwrap();
+}
+
+#[test]
+fn sidecar_needs_same_metrics() {
+    use super::edge_store::{EdgeStore, Precision};
+    use super::metrics::{CarEdgeFilter, Distance};
+    use super::pbf::{InternalMetrics, NodeMetrics};
+    use std::sync::Arc;
+
+    let with_metrics = |node_metrics: NodeMetrics| {
+        Loader::new(
+            vec![],
+            "",
+            CarEdgeFilter,
+            vec![],
+            node_metrics,
+            vec![],
+            vec![],
+            InternalMetrics::new(),
+        )
+    };
+    let extract = Extract {
+        nodes: vec![Node::new(1, 50.0, 10.0, 0.0)],
+        edges: EdgeStore::new(1, Precision::Double),
+        node_tag_costs: HashMap::new(),
+        blocked: HashSet::new(),
+        restrictions: BTreeMap::new(),
+        cycle_routes: BTreeMap::new(),
+        way_nodes: HashMap::new(),
+        route_ways: HashMap::new(),
+        edge_runs: None,
+        spilled_way_nodes: None,
+    };
+    let mut sidecar = Vec::new();
+    with_metrics(vec![Arc::new(Distance)])
+        .write_sidecar(&extract, &mut sidecar)
+        .unwrap();
+
+    let read = with_metrics(vec![Arc::new(Distance)]).read_sidecar(sidecar.as_slice());
+    assert_eq!(1, read.unwrap().nodes.len());
+    assert!(with_metrics(vec![])
+        .read_sidecar(sidecar.as_slice())
+        .is_err());
+}